@@ -4,12 +4,23 @@ use std::io::Write;
 use std::path::PathBuf;
 
 fn main() {
+    // Pick the memory layout: standalone (default), OTA application or OTA bootloader,
+    // see src/ota.rs for the partition table
+    let memory: &[u8] = if env::var_os("CARGO_FEATURE_BOOTLOADER").is_some() {
+        include_bytes!("memory-bootloader.x")
+    } else if env::var_os("CARGO_FEATURE_OTA").is_some() {
+        include_bytes!("memory-app.x")
+    } else {
+        include_bytes!("memory.x")
+    };
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=memory-app.x");
+    println!("cargo:rerun-if-changed=memory-bootloader.x");
 }
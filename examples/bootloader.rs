@@ -0,0 +1,77 @@
+//! OTA的bootloader：完成升级交换或回滚，再在USART1(PA9/PA10)上等1秒tools/flasher的
+//! 刷机命令(应用区是空的时一直等)，最后跳转到0x08002000的应用
+//!
+//! cargo build --release --example bootloader --features bootloader
+//! 应用用--features ota编译，分区见src/ota.rs
+
+#![no_main]
+#![no_std]
+#![feature(alloc_error_handler)]
+
+use alloc_cortex_m::CortexMHeap;
+use bluepill::flasher::{self, FlashTarget};
+use bluepill::hal::prelude::*;
+use bluepill::hal::timer::Timer;
+use bluepill::ota;
+use bluepill::storage::stm32::Stm32Flash;
+use cortex_m_rt::entry;
+use panic_halt as _;
+
+#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+/// 堆内存 1K
+const HEAP_SIZE: usize = 1024;
+
+#[entry]
+fn main() -> ! {
+    unsafe {
+        ALLOCATOR.init(cortex_m_rt::heap_start() as usize, HEAP_SIZE);
+    }
+    let p = bluepill::Peripherals::take().unwrap();
+    let mut flash = p.device.FLASH.constrain();
+    let mut rcc = p.device.RCC.constrain();
+    let mut afio = p.device.AFIO.constrain(&mut rcc.apb2);
+    let clocks = rcc.cfgr.freeze(&mut flash.acr);
+    let mut gpioa = p.device.GPIOA.split(&mut rcc.apb2);
+    let mut serial = bluepill::serial::Serial::with_usart(p.device.USART1)
+        .pins(gpioa.pa9, gpioa.pa10)
+        .cr(&mut gpioa.crh)
+        .clocks(clocks)
+        .afio_mapr(&mut afio.mapr)
+        .bus(&mut rcc.apb2)
+        .build();
+    let mut timer = Timer::syst(p.core.SYST, &clocks).start_count_down(1.hz());
+
+    let app = 0x0800_0000 + ota::APP as u32;
+    let mut chip = Stm32Flash::range(&mut flash, 0, 64);
+    //先完成掉电打断的交换，出错时照样往下走，下次上电再继续
+    ota::boot(&mut chip).ok();
+    //应用区的栈指针是0xFFFFFFFF说明没有固件
+    let wait = if unsafe { core::ptr::read_volatile(app as *const u32) } == 0xffff_ffff {
+        None
+    } else {
+        Some(1000)
+    };
+    let mut target = FlashTarget::new(chip);
+    flasher::serve(&mut serial, &mut timer, &mut target, wait).ok();
+    unsafe { jump(app) }
+}
+
+//停掉SysTick，切换向量表和栈指针，跳到应用的复位向量
+unsafe fn jump(address: u32) -> ! {
+    (*cortex_m::peripheral::SYST::PTR).csr.write(0);
+    let scb = &*cortex_m::peripheral::SCB::PTR;
+    scb.vtor.write(address);
+    let sp = core::ptr::read_volatile(address as *const u32);
+    let reset = core::ptr::read_volatile((address + 4) as *const u32);
+    cortex_m::register::msp::write(sp);
+    let reset: extern "C" fn() -> ! = core::mem::transmute(reset as usize);
+    reset()
+}
+
+// 内存不足执行此处代码(调试用)
+#[alloc_error_handler]
+fn alloc_error(_layout: core::alloc::Layout) -> ! {
+    cortex_m::asm::bkpt();
+    loop {}
+}
@@ -18,7 +18,13 @@ use bluepill::hal::prelude::*;
 use bluepill::hal::timer::Timer;
 use bluepill::io::*;
 use bluepill::net::esp826601s;
+use bluepill::net::provision::Provisioner;
+use bluepill::net::{Net, TcpStream};
+#[cfg(feature = "ota")]
+use bluepill::ota::Updater;
 use bluepill::stdio;
+use bluepill::storage::stm32::Stm32Flash;
+use bluepill::storage::KvStore;
 use bluepill::timer::TimerBuilder;
 use bluepill::*;
 use bluepill::*;
@@ -95,9 +101,23 @@ fn main() -> ! {
     sprintln!("new esp826601s ok");
     //wifi.hello().ok();
     // wifi.hangup().ok();
-    wifi.dial("Wosai-Guest", "Shouqianba$520", false).ok();
+    //WIFI账号保存在flash最后4页，没有保存过时开热点配网
+    let mut store = KvStore::mount(Stm32Flash::last_pages(&mut flash, 4)).unwrap();
+    let provisioner = Provisioner::new("bluepill", "");
+    match provisioner.connect(&mut wifi, &mut store, false) {
+        Ok(credentials) => {
+            sprintln!("joined {}", credentials.ssid);
+            //用--features ota编译、由bootloader启动时，能联网才确认新固件，否则下次复位回滚
+            #[cfg(feature = "ota")]
+            {
+                let mut updater = Updater::new(Stm32Flash::range(&mut flash, 0, 64));
+                updater.confirm().ok();
+            }
+        }
+        Err(err) => sprintln!("{:?}", err),
+    }
     match wifi.device_info() {
-        Ok(inf) => sprint!("{}", inf),
+        Ok(inf) => sprintln!("{:?}", inf),
         Err(bluepill::io::Error::Other(err)) => sprint!("{:?}", err),
         Err(err) => {
             sprintln!("{:?}", err)
@@ -112,27 +132,38 @@ fn main() -> ! {
     }
 
     match wifi.net_state() {
-        Ok(inf) => sprint!("{}", inf),
+        Ok(inf) => sprintln!("{:?}", inf),
         Err(bluepill::io::Error::Other(err)) => sprint!("{:?}", err),
         Err(err) => {
             sprintln!("{:?}", err)
         }
     }
 
+    match wifi.ifinfo() {
+        Ok(inf) => sprintln!(
+            "inet {} netmask {} gateway {} ether {}",
+            inf.inet4,
+            inf.netmask,
+            inf.gateway,
+            inf.ether
+        ),
+        Err(err) => sprintln!("{:?}", err),
+    }
+
     loop {
-        match wifi.connect(("172.18.61.78", "443")) {
-            Ok(reply) => {
-                sprint!("{}", reply);
+        match wifi.open_tcp(("172.18.61.78", 443)) {
+            Ok(mut stream) => {
+                if let Some(size) = stream.write(b"1111111111").ok() {
+                    sprintln!("sent {}", size);
+                }
+                let mut buf = [0u8; 10];
+                if let Some(size) = stream.read(&mut buf).ok() {
+                    sprintln!("read {:?}", &buf[..size]);
+                }
+                stream.close().ok();
             }
             Err(err) => sprintln!("{:?}", err),
         }
-        if let Some(size) = wifi.send_data(b"1111111111", 5000).ok() {
-            sprintln!("sent {}", size);
-        }
-        if let Some(reply) = wifi.read_data(10, 5000).ok() {
-            sprint!("{}", reply);
-        }
-        wifi.disconnect().ok();
         delay.delay_ms(5000u32);
     }
 }
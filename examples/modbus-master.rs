@@ -0,0 +1,93 @@
+#![no_main]
+#![no_std]
+#![feature(alloc_error_handler)]
+
+use bluepill::clocks::ClockExt;
+use bluepill::hal::delay::Delay;
+use bluepill::hal::prelude::*;
+use bluepill::modbus::Master;
+use bluepill::sprintln;
+use bluepill::timer::TimerBuilder;
+use cortex_m_rt::entry;
+use embedded_hal::blocking::delay::DelayMs;
+use panic_halt as _;
+
+use alloc_cortex_m::CortexMHeap;
+
+#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+/// 堆内存 8K
+const HEAP_SIZE: usize = 8192;
+
+//从机地址和波特率，土壤湿度探头出厂默认是1和9600
+const SLAVE: u8 = 1;
+const BAUDRATE: u32 = 9600;
+
+fn init() {
+    unsafe {
+        ALLOCATOR.init(cortex_m_rt::heap_start() as usize, HEAP_SIZE);
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    init();
+    let p = bluepill::Peripherals::take().unwrap(); //核心设备、外围设备
+    let mut flash = p.device.FLASH.constrain(); //Flash
+    let mut rcc = p.device.RCC.constrain(); //RCC
+    let mut afio = p.device.AFIO.constrain(&mut rcc.apb2);
+    let clocks = rcc.cfgr.clocks_72mhz(&mut flash.acr);
+    let mut delay = Delay::new(p.core.SYST, clocks); //配置延时器
+    let mut gpioa = p.device.GPIOA.split(&mut rcc.apb2);
+
+    let (stdout, _) = bluepill::serial::Serial::with_usart(p.device.USART1)
+        .pins(gpioa.pa9, gpioa.pa10) //映射到引脚
+        .cr(&mut gpioa.crh) //配置GPIO控制寄存器
+        .clocks(clocks) //时钟
+        .afio_mapr(&mut afio.mapr) //复用重映射即寄存器
+        .bus(&mut rcc.apb2) //配置内核总线
+        .build()
+        .split();
+    bluepill::stdio::use_tx1(stdout);
+
+    //MAX485的DE和RE接在一起
+    let de = gpioa.pa1.into_push_pull_output(&mut gpioa.crl);
+    let port = bluepill::serial::Serial::with_usart(p.device.USART2)
+        .pins(gpioa.pa2, gpioa.pa3) //映射到引脚
+        .cr(&mut gpioa.crl) //配置GPIO控制寄存器
+        .clocks(clocks) //时钟
+        .afio_mapr(&mut afio.mapr) //复用重映射
+        .bus(&mut rcc.apb1) //配置内核总线
+        .baudrate(BAUDRATE)
+        .build_rw()
+        .half_duplex(de);
+    let timer = TimerBuilder::with_tim(p.device.TIM2)
+        .clocks(clocks)
+        .bus(&mut rcc.apb1)
+        .build()
+        .start_count_down(10.khz());
+    let mut master = Master::new(port, timer, BAUDRATE).timeout(200);
+
+    loop {
+        //输入寄存器0是湿度，1是温度，都放大了10倍
+        let mut values = [0u16; 2];
+        match master.read_input_registers(SLAVE, 0, &mut values) {
+            Ok(()) => sprintln!(
+                "humidity {}.{}% temperature {}.{}C",
+                values[0] / 10,
+                values[0] % 10,
+                values[1] as i16 / 10,
+                (values[1] as i16 % 10).abs()
+            ),
+            Err(err) => sprintln!("modbus error {:?}", err),
+        }
+        delay.delay_ms(1_000u32);
+    }
+}
+
+// 内存不足执行此处代码(调试用)
+#[alloc_error_handler]
+fn alloc_error(_layout: core::alloc::Layout) -> ! {
+    cortex_m::asm::bkpt();
+    loop {}
+}
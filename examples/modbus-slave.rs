@@ -0,0 +1,99 @@
+#![no_main]
+#![no_std]
+#![feature(alloc_error_handler)]
+
+use bluepill::clocks::ClockExt;
+use bluepill::hal::adc;
+use bluepill::hal::prelude::*;
+use bluepill::led::Led;
+use bluepill::modbus::{Exception, Slave};
+use bluepill::timer::TimerBuilder;
+use core::cell::RefCell;
+use cortex_m_rt::entry;
+use panic_halt as _;
+
+use alloc_cortex_m::CortexMHeap;
+
+#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+/// 堆内存 8K
+const HEAP_SIZE: usize = 8192;
+
+const ADDRESS: u8 = 1;
+const BAUDRATE: u32 = 9600;
+
+fn init() {
+    unsafe {
+        ALLOCATOR.init(cortex_m_rt::heap_start() as usize, HEAP_SIZE);
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    init();
+    let p = bluepill::Peripherals::take().unwrap(); //核心设备、外围设备
+    let mut flash = p.device.FLASH.constrain(); //Flash
+    let mut rcc = p.device.RCC.constrain(); //RCC
+    let mut afio = p.device.AFIO.constrain(&mut rcc.apb2);
+    let clocks = rcc.cfgr.clocks_72mhz(&mut flash.acr);
+    let mut gpioa = p.device.GPIOA.split(&mut rcc.apb2);
+    let mut gpioc = p.device.GPIOC.split(&mut rcc.apb2);
+    let led = RefCell::new(Led(gpioc.pc13).ppo(&mut gpioc.crh)); //配置LED
+    bluepill::init_adc(adc::Adc::adc1(p.device.ADC1, &mut rcc.apb2, clocks));
+
+    //MAX485的DE和RE接在一起
+    let de = gpioa.pa1.into_push_pull_output(&mut gpioa.crl);
+    let port = bluepill::serial::Serial::with_usart(p.device.USART2)
+        .pins(gpioa.pa2, gpioa.pa3) //映射到引脚
+        .cr(&mut gpioa.crl) //配置GPIO控制寄存器
+        .clocks(clocks) //时钟
+        .afio_mapr(&mut afio.mapr) //复用重映射
+        .bus(&mut rcc.apb1) //配置内核总线
+        .baudrate(BAUDRATE)
+        .build_rw()
+        .half_duplex(de);
+    let timer = TimerBuilder::with_tim(p.device.TIM2)
+        .clocks(clocks)
+        .bus(&mut rcc.apb1)
+        .build()
+        .start_count_down(10.khz());
+
+    //保持寄存器0~3给主机存放参数，断电丢失
+    let mut holding = [0u16; 4];
+    let mut slave = Slave::new(port, timer, ADDRESS, BAUDRATE)
+        //线圈0是板子上的LED
+        .coils(0, 1, |_, value| {
+            let mut led = led.borrow_mut();
+            match value {
+                Some(true) => led.on(),
+                Some(false) => led.off(),
+                None => {}
+            }
+            Ok(led.is_on())
+        })
+        //离散输入0是LED的状态
+        .discrete_inputs(0, 1, |_| Ok(led.borrow_mut().is_on()))
+        .holding_registers(0, 4, |address, value| {
+            if let Some(value) = value {
+                holding[address as usize] = value;
+            }
+            Ok(holding[address as usize])
+        })
+        //输入寄存器0是芯片温度
+        .input_registers(0, 1, |_| {
+            bluepill::chip_temp()
+                .map(|temp| temp as u16)
+                .ok_or(Exception::ServerDeviceFailure)
+        });
+
+    loop {
+        slave.poll().ok();
+    }
+}
+
+// 内存不足执行此处代码(调试用)
+#[alloc_error_handler]
+fn alloc_error(_layout: core::alloc::Layout) -> ! {
+    cortex_m::asm::bkpt();
+    loop {}
+}
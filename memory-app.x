@@ -0,0 +1,6 @@
+/* 由bootloader启动的应用(--features ota)，分区见src/ota.rs */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08002000, LENGTH = 25K
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+}
@@ -0,0 +1,6 @@
+/* OTA的bootloader(--features bootloader)，分区见src/ota.rs */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 8K
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+}
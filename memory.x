@@ -1,5 +1,6 @@
+/* 用ST-LINK直接烧录时的布局；OTA的布局见memory-app.x、memory-bootloader.x和src/ota.rs */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 60K /* 最后4页(0x0800F000)留给storage::KvStore */
   RAM : ORIGIN = 0x20000000, LENGTH = 20K
 }
\ No newline at end of file
@@ -0,0 +1,249 @@
+//! 串口刷机
+//!
+//! 应用跑飞、OTA也救不回来时，bootloader上电后在USART1(PA9/PA10，README里FT232的接法)上
+//! 等待主机的刷机命令，主机端是tools/flasher，协议见protocol.rs
+
+pub mod protocol;
+
+use crate::hal::time::{Hertz, U32Ext};
+use crate::io::{Error, Result};
+use crate::ota;
+use crate::storage::Flash;
+use protocol::{Info, Server, Status, Target, MAX_FRAME, VERSION};
+
+const FLASH_BASE: u32 = 0x0800_0000;
+
+/// 只允许写应用区，bootloader不会被覆盖
+pub struct FlashTarget<F> {
+    flash: F,     //整片64K，和ota一样
+    erased: bool, //擦过应用区
+}
+
+impl<F: Flash> FlashTarget<F> {
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            erased: false,
+        }
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    //绝对地址换成flash里的偏移，超出应用区时返回BadAddress
+    fn offset(&self, address: u32, len: u32) -> core::result::Result<usize, Status> {
+        let start = FLASH_BASE + ota::APP as u32;
+        let end = start + ota::SLOT_SIZE as u32;
+        match address.checked_add(len) {
+            Some(last) if address >= start && last <= end => Ok((address - FLASH_BASE) as usize),
+            _ => Err(Status::BadAddress),
+        }
+    }
+}
+
+impl<F: Flash> Target for FlashTarget<F> {
+    fn info(&self) -> Info {
+        Info {
+            version: VERSION,
+            page_size: self.flash.page_size() as u16,
+            start: FLASH_BASE + ota::APP as u32,
+            len: ota::SLOT_SIZE as u32,
+        }
+    }
+
+    //从address所在的页开始擦除，覆盖len字节
+    fn erase(&mut self, address: u32, len: u32) -> core::result::Result<(), Status> {
+        let offset = self.offset(address, len)?;
+        let page_size = self.flash.page_size();
+        //应用区换了固件，OTA试运行或交换到一半的状态不能再用，否则bootloader会把它换走
+        if !self.erased {
+            self.flash
+                .erase(ota::STATE / page_size)
+                .map_err(|_| Status::FlashError)?;
+            self.erased = true;
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let last = offset + len as usize - 1;
+        for page in offset / page_size..=last / page_size {
+            self.flash.erase(page).map_err(|_| Status::FlashError)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> core::result::Result<(), Status> {
+        let offset = self.offset(address, data.len() as u32)?;
+        if offset & 1 != 0 || data.len() & 1 != 0 {
+            return Err(Status::BadAddress);
+        }
+        self.flash
+            .write(offset, data)
+            .map_err(|_| Status::FlashError)
+    }
+
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> core::result::Result<(), Status> {
+        let offset = self.offset(address, buf.len() as u32)?;
+        self.flash.read(offset, buf).map_err(|_| Status::FlashError)
+    }
+}
+
+//在串口上处理刷机命令，应答了Boot后返回true；wait毫秒内没收到有效的请求时返回false，
+//wait为None时一直等
+pub fn serve<S, TIM, T>(
+    serial: &mut S,
+    timer: &mut TIM,
+    target: &mut T,
+    wait: Option<u32>,
+) -> Result<bool>
+where
+    S: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+    T: Target,
+{
+    let mut server = Server::new();
+    let mut out = [0u8; MAX_FRAME];
+    let mut elapsed = 0;
+    timer.start(1.khz());
+    loop {
+        if let Some(wait) = wait {
+            if !server.connected() && timer.wait().is_ok() {
+                elapsed += 1;
+                if elapsed >= wait {
+                    return Ok(false);
+                }
+            }
+        }
+        //溢出、帧错误的字节直接丢掉，坏帧靠CRC发现
+        let byte = match serial.read() {
+            Ok(byte) => byte,
+            Err(_) => continue,
+        };
+        if let Some(len) = server.feed(target, byte, &mut out) {
+            for byte in &out[..len] {
+                nb::block!(serial.write(*byte)).map_err(|_| Error::WriteError)?;
+            }
+            nb::block!(serial.flush()).map_err(|_| Error::WriteError)?;
+            if server.boot_requested() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SimFlash;
+    use alloc::collections::VecDeque;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use protocol::{Client, Port};
+
+    //主机端的串口直接接到进程内的设备端，lossy时按固定规律损坏请求、丢掉应答
+    struct Pipe {
+        server: Server,
+        target: FlashTarget<SimFlash>,
+        rx: VecDeque<u8>,
+        sent: u32,
+        lossy: bool,
+    }
+
+    impl Port for Pipe {
+        fn send(&mut self, frame: &[u8]) -> core::result::Result<(), protocol::Error> {
+            self.sent += 1;
+            let mut out = [0u8; MAX_FRAME];
+            for (i, &byte) in frame.iter().enumerate() {
+                let corrupt = self.lossy && self.sent % 7 == 3 && i == 5;
+                let byte = if corrupt { byte ^ 0x40 } else { byte };
+                if let Some(len) = self.server.feed(&mut self.target, byte, &mut out) {
+                    if self.lossy && self.sent % 5 == 1 {
+                        continue;
+                    }
+                    self.rx.extend(&out[..len]);
+                }
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> core::result::Result<Option<u8>, protocol::Error> {
+            Ok(self.rx.pop_front())
+        }
+    }
+
+    fn pipe(lossy: bool) -> Pipe {
+        let mut flash = SimFlash::new(1024, 64);
+        //应用区外有一次没完成的OTA
+        flash.write(ota::STATE, &[0x54, 0x4f]).unwrap();
+        Pipe {
+            server: Server::new(),
+            target: FlashTarget::new(flash),
+            rx: VecDeque::new(),
+            sent: 0,
+            lossy,
+        }
+    }
+
+    fn flash_image(lossy: bool) {
+        let image: Vec<u8> = (0..5001u32).map(|i| (i * 7 + 3) as u8).collect();
+        let mut client = Client::new(pipe(lossy)).retries(5);
+        let info = client.hello().unwrap();
+        assert_eq!(
+            info,
+            Info {
+                version: VERSION,
+                page_size: 1024,
+                start: 0x0800_2000,
+                len: 25 * 1024,
+            }
+        );
+        client.flash(info.start, &image, |_| {}).unwrap();
+        client.verify(info.start, &image[..100]).unwrap();
+        let mut bad = image.clone();
+        bad[10] ^= 1;
+        assert_eq!(
+            client.verify(info.start, &bad),
+            Err(protocol::Error::Device(Status::CrcMismatch))
+        );
+        //bootloader和应用区之外都不能写
+        assert_eq!(
+            client.erase(FLASH_BASE, 10),
+            Err(protocol::Error::Device(Status::BadAddress))
+        );
+        assert_eq!(
+            client.write(info.start + info.len - 2, &[1, 2, 3, 4]),
+            Err(protocol::Error::Device(Status::BadAddress))
+        );
+        assert_eq!(
+            client.write(info.start + 1, &[1, 2]),
+            Err(protocol::Error::Device(Status::BadAddress))
+        );
+        //超过MAX_DATA的数据不截断
+        assert_eq!(
+            client.write(info.start, &[0; protocol::MAX_DATA + 2]),
+            Err(protocol::Error::TooLong)
+        );
+        assert!(!client.port().server.boot_requested());
+        client.boot().unwrap();
+
+        let pipe = client.release();
+        assert!(pipe.server.boot_requested());
+        let mut flash = pipe.target.release();
+        let mut buf = vec![0u8; image.len()];
+        flash.read(ota::APP, &mut buf).unwrap();
+        assert_eq!(buf, image);
+        //刷机清掉了OTA状态
+        assert_eq!(ota::status(&mut flash).unwrap(), ota::Status::Idle);
+    }
+
+    #[test]
+    fn client_server() {
+        flash_image(false);
+    }
+
+    #[test]
+    fn client_server_retries() {
+        flash_image(true);
+    }
+}
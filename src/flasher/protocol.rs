@@ -0,0 +1,605 @@
+//! 串口刷机协议，设备端(src/flasher.rs)和主机端(tools/flasher)共用这个文件，
+//! 所以这里只依赖core、cobs和crc16
+//!
+//! 帧: COBS(负载 CRC16) 0x00，CRC16是XMODEM，小端
+//!
+//! 请求负载: 命令(1) 序号(1) 参数；应答负载: 状态(1) 序号(1) 数据。
+//! 序号和上一个请求相同时设备不再执行，直接重发上一个应答，主机超时重发是安全的。
+//! 主机每次连接先发Hello
+//!
+//! | 命令        | 参数                      | 应答数据                                  |
+//! |-------------|---------------------------|-------------------------------------------|
+//! | Hello 0x01  |                           | 版本(1) 页大小(2) 可写区起始(4) 长度(4)   |
+//! | Erase 0x02  | 地址(4) 长度(4)           |                                           |
+//! | Write 0x03  | 地址(4) 数据(最多256字节) |                                           |
+//! | Verify 0x04 | 地址(4) 长度(4) CRC16(2)  |                                           |
+//! | Boot 0x05   |                           |                                           |
+
+use crc16::{State, XMODEM};
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+pub const VERSION: u8 = 1;
+//Write一次最多带的数据
+pub const MAX_DATA: usize = 256;
+const MAX_PAYLOAD: usize = 2 + 4 + MAX_DATA + 2;
+//COBS每254字节多1字节，再加开头1字节和结尾的0
+pub const MAX_FRAME: usize = MAX_PAYLOAD + MAX_PAYLOAD / 254 + 2;
+
+const HELLO: u8 = 0x01;
+const ERASE: u8 = 0x02;
+const WRITE: u8 = 0x03;
+const VERIFY: u8 = 0x04;
+const BOOT: u8 = 0x05;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Command<'a> {
+    Hello,
+    Erase { address: u32, len: u32 },
+    Write { address: u32, data: &'a [u8] },
+    Verify { address: u32, len: u32, crc: u16 },
+    Boot,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Ok = 0,
+    BadFrame = 1,   //CRC错误或帧太长
+    BadCommand = 2, //未知命令或参数长度不对
+    BadAddress = 3, //超出可写区或没有按半字对齐
+    FlashError = 4,
+    CrcMismatch = 5,
+}
+
+impl Status {
+    fn from_u8(code: u8) -> Option<Self> {
+        Some(match code {
+            0 => Status::Ok,
+            1 => Status::BadFrame,
+            2 => Status::BadCommand,
+            3 => Status::BadAddress,
+            4 => Status::FlashError,
+            5 => Status::CrcMismatch,
+            _ => return None,
+        })
+    }
+}
+
+/// 设备信息，Hello的应答
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Info {
+    pub version: u8,
+    pub page_size: u16,
+    pub start: u32, //可写区的绝对地址
+    pub len: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Response {
+    Done,
+    Info(Info),
+    Failed(Status),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Error {
+    Timeout,
+    Io,
+    Protocol, //应答格式不对
+    TooLong,  //Write的数据超过MAX_DATA
+    Device(Status),
+}
+
+pub fn crc(data: &[u8]) -> u16 {
+    State::<XMODEM>::calculate(data)
+}
+
+//把负载加上CRC编码成一帧，返回帧长度(含结尾的0)
+fn frame(payload: &[u8], out: &mut [u8; MAX_FRAME]) -> usize {
+    let mut raw = [0u8; MAX_PAYLOAD];
+    let len = payload.len();
+    raw[..len].copy_from_slice(payload);
+    raw[len..len + 2].copy_from_slice(&crc(payload).to_le_bytes());
+    let encoded = cobs::encode(&raw[..len + 2], &mut out[..]);
+    out[encoded] = 0;
+    encoded + 1
+}
+
+//Write的数据超过MAX_DATA时返回Error::TooLong，不会截断
+pub fn encode_command(
+    seq: u8,
+    command: &Command,
+    out: &mut [u8; MAX_FRAME],
+) -> Result<usize, Error> {
+    let mut payload = [0u8; MAX_PAYLOAD];
+    payload[1] = seq;
+    let len = match *command {
+        Command::Hello => {
+            payload[0] = HELLO;
+            2
+        }
+        Command::Erase { address, len } => {
+            payload[0] = ERASE;
+            payload[2..6].copy_from_slice(&address.to_le_bytes());
+            payload[6..10].copy_from_slice(&len.to_le_bytes());
+            10
+        }
+        Command::Write { address, data } => {
+            if data.len() > MAX_DATA {
+                return Err(Error::TooLong);
+            }
+            payload[0] = WRITE;
+            payload[2..6].copy_from_slice(&address.to_le_bytes());
+            payload[6..6 + data.len()].copy_from_slice(data);
+            6 + data.len()
+        }
+        Command::Verify { address, len, crc } => {
+            payload[0] = VERIFY;
+            payload[2..6].copy_from_slice(&address.to_le_bytes());
+            payload[6..10].copy_from_slice(&len.to_le_bytes());
+            payload[10..12].copy_from_slice(&crc.to_le_bytes());
+            12
+        }
+        Command::Boot => {
+            payload[0] = BOOT;
+            2
+        }
+    };
+    Ok(frame(&payload[..len], out))
+}
+
+pub fn decode_command(payload: &[u8]) -> Result<(u8, Command<'_>), Status> {
+    if payload.len() < 2 {
+        return Err(Status::BadCommand);
+    }
+    let seq = payload[1];
+    let args = &payload[2..];
+    let command = match (payload[0], args.len()) {
+        (HELLO, 0) => Command::Hello,
+        (ERASE, 8) => Command::Erase {
+            address: u32_at(args, 0),
+            len: u32_at(args, 4),
+        },
+        (WRITE, len) if len >= 4 => Command::Write {
+            address: u32_at(args, 0),
+            data: &args[4..],
+        },
+        (VERIFY, 10) => Command::Verify {
+            address: u32_at(args, 0),
+            len: u32_at(args, 4),
+            crc: u16::from_le_bytes([args[8], args[9]]),
+        },
+        (BOOT, 0) => Command::Boot,
+        _ => return Err(Status::BadCommand),
+    };
+    Ok((seq, command))
+}
+
+pub fn encode_response(seq: u8, response: &Response, out: &mut [u8; MAX_FRAME]) -> usize {
+    let mut payload = [0u8; 13];
+    payload[1] = seq;
+    let len = match *response {
+        Response::Done => 2,
+        Response::Info(info) => {
+            payload[2] = info.version;
+            payload[3..5].copy_from_slice(&info.page_size.to_le_bytes());
+            payload[5..9].copy_from_slice(&info.start.to_le_bytes());
+            payload[9..13].copy_from_slice(&info.len.to_le_bytes());
+            13
+        }
+        Response::Failed(status) => {
+            payload[0] = status as u8;
+            2
+        }
+    };
+    frame(&payload[..len], out)
+}
+
+pub fn decode_response(payload: &[u8]) -> Result<(u8, Response), Error> {
+    if payload.len() < 2 {
+        return Err(Error::Protocol);
+    }
+    let seq = payload[1];
+    let response = match Status::from_u8(payload[0]).ok_or(Error::Protocol)? {
+        Status::Ok if payload.len() == 13 => Response::Info(Info {
+            version: payload[2],
+            page_size: u16::from_le_bytes([payload[3], payload[4]]),
+            start: u32_at(payload, 5),
+            len: u32_at(payload, 9),
+        }),
+        Status::Ok if payload.len() == 2 => Response::Done,
+        Status::Ok => return Err(Error::Protocol),
+        status => Response::Failed(status),
+    };
+    Ok((seq, response))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// 按0分帧，解码COBS并检查CRC
+pub struct Decoder {
+    frame: [u8; MAX_FRAME],
+    len: usize,
+    overflow: bool, //帧太长，丢弃到下一个0
+    payload: [u8; MAX_FRAME],
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            frame: [0; MAX_FRAME],
+            len: 0,
+            overflow: false,
+            payload: [0; MAX_FRAME],
+        }
+    }
+
+    //收到一帧时返回不含CRC的负载，CRC错误或帧太长时返回Status::BadFrame
+    pub fn feed(&mut self, byte: u8) -> Option<Result<&[u8], Status>> {
+        if byte != 0 {
+            if self.len == self.frame.len() {
+                self.overflow = true;
+            } else {
+                self.frame[self.len] = byte;
+                self.len += 1;
+            }
+            return None;
+        }
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overflow, false) {
+            return Some(Err(Status::BadFrame));
+        }
+        //连续的0之间没有数据，忽略
+        if len == 0 {
+            return None;
+        }
+        let decoded = match cobs::decode(&self.frame[..len], &mut self.payload) {
+            Ok(decoded) if decoded >= 2 => decoded,
+            _ => return Some(Err(Status::BadFrame)),
+        };
+        let (payload, check) = self.payload[..decoded].split_at(decoded - 2);
+        if crc(payload).to_le_bytes() != check {
+            return Some(Err(Status::BadFrame));
+        }
+        Some(Ok(payload))
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 设备端的flash，地址都是绝对地址
+pub trait Target {
+    fn info(&self) -> Info;
+    fn erase(&mut self, address: u32, len: u32) -> Result<(), Status>;
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Status>;
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Status>;
+}
+
+/// 设备端：逐字节喂进串口收到的数据，得到要发回的应答帧
+pub struct Server {
+    decoder: Decoder,
+    last: Option<(u8, Response)>, //上一个请求的序号和应答
+    boot: bool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+            last: None,
+            boot: false,
+        }
+    }
+
+    //已经应答了Boot，发完应答后应该启动应用
+    pub fn boot_requested(&self) -> bool {
+        self.boot
+    }
+
+    //收到过有效的请求
+    pub fn connected(&self) -> bool {
+        self.last.is_some()
+    }
+
+    //需要应答时把应答帧写进out并返回长度
+    pub fn feed<T: Target>(
+        &mut self,
+        target: &mut T,
+        byte: u8,
+        out: &mut [u8; MAX_FRAME],
+    ) -> Option<usize> {
+        let (seq, response) = match self.decoder.feed(byte)? {
+            Err(status) => (0, Response::Failed(status)),
+            Ok(payload) => match decode_command(payload) {
+                Err(status) => {
+                    let seq = payload.get(1).copied().unwrap_or(0);
+                    (seq, Response::Failed(status))
+                }
+                Ok((seq, command)) => {
+                    let response = match self.last {
+                        //重发的请求；Hello总是执行，主机重新连接时序号从头开始
+                        Some((last, response)) if last == seq && command != Command::Hello => {
+                            response
+                        }
+                        _ => execute(target, &command).unwrap_or_else(Response::Failed),
+                    };
+                    if command == Command::Boot {
+                        self.boot = true;
+                    }
+                    self.last = Some((seq, response));
+                    (seq, response)
+                }
+            },
+        };
+        Some(encode_response(seq, &response, out))
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn execute<T: Target>(target: &mut T, command: &Command) -> Result<Response, Status> {
+    match *command {
+        Command::Hello => return Ok(Response::Info(target.info())),
+        Command::Erase { address, len } => target.erase(address, len)?,
+        Command::Write { address, data } => target.write(address, data)?,
+        Command::Verify { address, len, crc } => {
+            let mut state = State::<XMODEM>::new();
+            let mut buf = [0u8; 64];
+            let mut offset = 0;
+            while offset < len {
+                let n = buf.len().min((len - offset) as usize);
+                target.read(address + offset, &mut buf[..n])?;
+                state.update(&buf[..n]);
+                offset += n as u32;
+            }
+            if state.get() != crc {
+                return Err(Status::CrcMismatch);
+            }
+        }
+        Command::Boot => {}
+    }
+    Ok(Response::Done)
+}
+
+/// 主机端的串口
+pub trait Port {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error>;
+    //读一个字节，超时返回None
+    fn recv(&mut self) -> Result<Option<u8>, Error>;
+}
+
+/// 主机端：发请求等应答，超时或帧损坏时重发
+pub struct Client<P> {
+    port: P,
+    seq: u8,
+    retries: u8,
+    decoder: Decoder,
+}
+
+impl<P: Port> Client<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            seq: 0,
+            retries: 3,
+            decoder: Decoder::new(),
+        }
+    }
+
+    pub fn retries(mut self, retries: u8) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn release(self) -> P {
+        self.port
+    }
+
+    //调整串口超时等，序号不受影响
+    pub fn port(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn request(&mut self, command: &Command) -> Result<Response, Error> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut frame = [0u8; MAX_FRAME];
+        let len = encode_command(self.seq, command, &mut frame)?;
+        let mut result = Err(Error::Timeout);
+        for _ in 0..=self.retries {
+            self.port.send(&frame[..len])?;
+            result = self.response();
+            match result {
+                Err(Error::Timeout) | Ok(Response::Failed(Status::BadFrame)) => continue,
+                _ => break,
+            }
+        }
+        match result? {
+            Response::Failed(status) => Err(Error::Device(status)),
+            response => Ok(response),
+        }
+    }
+
+    //等待当前序号的应答，忽略之前请求迟到的应答
+    fn response(&mut self) -> Result<Response, Error> {
+        loop {
+            let byte = self.port.recv()?.ok_or(Error::Timeout)?;
+            match self.decoder.feed(byte) {
+                None => continue,
+                Some(Err(_)) => return Ok(Response::Failed(Status::BadFrame)),
+                Some(Ok(payload)) => {
+                    let (seq, response) = decode_response(payload)?;
+                    if seq == self.seq || response == Response::Failed(Status::BadFrame) {
+                        return Ok(response);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn hello(&mut self) -> Result<Info, Error> {
+        match self.request(&Command::Hello)? {
+            Response::Info(info) => Ok(info),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    pub fn erase(&mut self, address: u32, len: u32) -> Result<(), Error> {
+        self.request(&Command::Erase { address, len }).map(|_| ())
+    }
+
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        self.request(&Command::Write { address, data }).map(|_| ())
+    }
+
+    pub fn verify(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        let command = Command::Verify {
+            address,
+            len: data.len() as u32,
+            crc: crc(data),
+        };
+        self.request(&command).map(|_| ())
+    }
+
+    pub fn boot(&mut self) -> Result<(), Error> {
+        self.request(&Command::Boot).map(|_| ())
+    }
+
+    //擦除、分块写入并校验整个镜像，progress收到已写入的字节数
+    pub fn flash<F: FnMut(usize)>(
+        &mut self,
+        address: u32,
+        image: &[u8],
+        mut progress: F,
+    ) -> Result<(), Error> {
+        self.erase(address, image.len() as u32)?;
+        let mut written = 0;
+        for chunk in image.chunks(MAX_DATA) {
+            //flash按半字编程，奇数长度补一个0xFF
+            let mut data = [0xffu8; MAX_DATA];
+            data[..chunk.len()].copy_from_slice(chunk);
+            let len = chunk.len() + (chunk.len() & 1);
+            self.write(address + written as u32, &data[..len])?;
+            written += chunk.len();
+            progress(written);
+        }
+        self.verify(address, image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //逐字节喂给decoder，返回最后一帧的负载
+    fn decode<'a>(decoder: &'a mut Decoder, frame: &[u8]) -> Option<Result<&'a [u8], Status>> {
+        let (last, rest) = frame.split_last()?;
+        for &byte in rest {
+            assert!(decoder.feed(byte).is_none());
+        }
+        decoder.feed(*last)
+    }
+
+    #[test]
+    fn command_frames() {
+        let data = [0u8; MAX_DATA];
+        let commands = [
+            Command::Hello,
+            Command::Erase {
+                address: 0x0800_2000,
+                len: 5001,
+            },
+            Command::Write {
+                address: 0x0800_2000,
+                data: &data,
+            },
+            Command::Write {
+                address: 0x0800_2100,
+                data: &[1, 0, 2, 0],
+            },
+            Command::Verify {
+                address: 0x0800_2000,
+                len: 5001,
+                crc: 0x1234,
+            },
+            Command::Boot,
+        ];
+        let mut out = [0u8; MAX_FRAME];
+        let mut decoder = Decoder::new();
+        for (seq, command) in commands.iter().enumerate() {
+            let len = encode_command(seq as u8, command, &mut out).unwrap();
+            assert!(!out[..len - 1].contains(&0));
+            let payload = decode(&mut decoder, &out[..len]).unwrap().unwrap();
+            assert_eq!(decode_command(payload), Ok((seq as u8, *command)));
+        }
+    }
+
+    #[test]
+    fn oversize_write_rejected() {
+        let data = [0u8; MAX_DATA + 1];
+        let command = Command::Write {
+            address: 0x0800_2000,
+            data: &data,
+        };
+        let mut out = [0u8; MAX_FRAME];
+        assert_eq!(encode_command(1, &command, &mut out), Err(Error::TooLong));
+    }
+
+    #[test]
+    fn response_frames() {
+        let info = Info {
+            version: VERSION,
+            page_size: 1024,
+            start: 0x0800_2000,
+            len: 25 * 1024,
+        };
+        let responses = [
+            Response::Done,
+            Response::Info(info),
+            Response::Failed(Status::CrcMismatch),
+        ];
+        let mut out = [0u8; MAX_FRAME];
+        let mut decoder = Decoder::new();
+        for (seq, response) in responses.iter().enumerate() {
+            let len = encode_response(seq as u8, response, &mut out);
+            let payload = decode(&mut decoder, &out[..len]).unwrap().unwrap();
+            assert_eq!(decode_response(payload), Ok((seq as u8, *response)));
+        }
+        assert_eq!(decode_response(&[9, 1]), Err(Error::Protocol));
+        assert_eq!(decode_response(&[0, 1, 2]), Err(Error::Protocol));
+    }
+
+    #[test]
+    fn bad_frames() {
+        let mut out = [0u8; MAX_FRAME];
+        let mut decoder = Decoder::new();
+        //太长的帧丢弃到下一个0，之后的帧不受影响
+        for _ in 0..MAX_FRAME + 5 {
+            assert!(decoder.feed(1).is_none());
+        }
+        assert_eq!(decoder.feed(0), Some(Err(Status::BadFrame)));
+        assert!(decoder.feed(0).is_none());
+        //CRC错误
+        let len = encode_command(7, &Command::Boot, &mut out).unwrap();
+        out[1] ^= 0x40;
+        assert_eq!(
+            decode(&mut decoder, &out[..len]),
+            Some(Err(Status::BadFrame))
+        );
+        let len = encode_command(8, &Command::Boot, &mut out).unwrap();
+        let payload = decode(&mut decoder, &out[..len]).unwrap().unwrap();
+        assert_eq!(decode_command(payload), Ok((8, Command::Boot)));
+        //参数长度不对
+        assert_eq!(decode_command(&[ERASE, 1, 0, 0]), Err(Status::BadCommand));
+        assert_eq!(decode_command(&[0x7f, 1]), Err(Status::BadCommand));
+    }
+}
@@ -136,3 +136,18 @@ macro_rules! pins {
         ($($crate::pin!($gpio, $name)),+)
     }
 }
+
+/// 没有接的输出引脚，比如ESP8266的复位引脚、单线半双工的DE，写什么都不做
+pub struct NoPin;
+
+impl embedded_hal::digital::v2::OutputPin for NoPin {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -17,6 +17,8 @@ pub enum Error {
     NoIoDevice,
     NoNetwork,
     DeviceBusy,
+    Parse(&'static str),
+    Join(crate::net::at::JoinError),
 }
 
 impl core::fmt::Display for Error {
@@ -31,6 +33,8 @@ impl core::fmt::Display for Error {
             Error::NoIoDevice => write!(f, "no io device"),
             Error::NoNetwork => write!(f, "no network"),
             Error::DeviceBusy => write!(f, "device is busy"),
+            Error::Parse(detail) => write!(f, "malformed reply {}", detail),
+            Error::Join(reason) => write!(f, "join failed {:?}", reason),
         }
     }
 }
@@ -5,14 +5,20 @@ extern crate alloc;
 
 pub mod clocks;
 pub mod display;
+pub mod flasher;
 pub mod gpio;
 pub mod io;
 pub mod led;
+#[cfg(test)]
+mod mock;
+pub mod modbus;
 pub mod net;
+pub mod ota;
 pub mod rng;
 pub mod sensor;
 pub mod serial;
 pub mod stdio;
+pub mod storage;
 pub mod timer;
 
 pub use stm32f1xx_hal as hal;
@@ -0,0 +1,77 @@
+//! 主机上测试用的串口和定时器替身，只在`cargo test`时编译
+
+use crate::hal::time::Hertz;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+/// 按脚本应答的串口：每写出一个'\n'就放出下一段应答，
+/// 没有脚本的输入一开始就全部可读，写出的字节都记在output里
+#[derive(Default)]
+pub struct Port {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+    replies: VecDeque<&'static [u8]>,
+}
+
+impl Port {
+    //input一开始就可读
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            ..Self::default()
+        }
+    }
+
+    //主机每发一行(以'\n'结尾)才放出一段应答
+    pub fn scripted(replies: &[&'static str]) -> Self {
+        Self {
+            replies: replies.iter().map(|reply| reply.as_bytes()).collect(),
+            ..Self::default()
+        }
+    }
+
+    //追加可读的输入
+    pub fn feed(&mut self, input: &[u8]) {
+        self.input.extend(input.iter().copied());
+    }
+
+    //取走到目前为止写出的字节
+    pub fn take_output(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.output)
+    }
+}
+
+impl embedded_hal::serial::Read<u8> for Port {
+    type Error = ();
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        self.input.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl embedded_hal::serial::Write<u8> for Port {
+    type Error = ();
+    fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+        self.output.push(byte);
+        if byte == b'\n' {
+            if let Some(reply) = self.replies.pop_front() {
+                self.input.extend(reply.iter().copied());
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Ok(())
+    }
+}
+
+/// 每次wait都立即到期的定时器，超时只按调用次数计算
+pub struct Timer;
+
+impl embedded_hal::timer::CountDown for Timer {
+    type Time = Hertz;
+    fn start<T: Into<Hertz>>(&mut self, _count: T) {}
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+//! Modbus RTU
+//!
+//! 电表、土壤湿度探头这些传感器通过RS-485(MAX485)接在USART上，用`RW::half_duplex`控制DE。
+//! 板子可以当主机去读传感器(`Master`)，也可以当从机让PLC来读(`Slave`)。
+//! frame.rs是帧编解码，主机和从机共用
+
+pub mod frame;
+pub mod master;
+pub mod slave;
+
+pub use frame::{Error, Exception, BROADCAST};
+pub use master::Master;
+pub use slave::Slave;
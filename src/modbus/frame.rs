@@ -0,0 +1,1010 @@
+//! Modbus RTU帧编解码，只依赖core和crc16，主机上可以直接测试
+//!
+//! 帧: 从机地址(1) 功能码(1) 数据 CRC16(2)，CRC16是MODBUS，低字节在前，其余字段都是大端。
+//! 帧之间至少空闲3.5个字符
+//!
+//! | 功能码 | 请求数据                              | 应答数据                  |
+//! |--------|---------------------------------------|---------------------------|
+//! | 01 02  | 地址(2) 数量(2)                       | 字节数(1) 位，低位在前    |
+//! | 03 04  | 地址(2) 数量(2)                       | 字节数(1) 寄存器          |
+//! | 05     | 地址(2) 0xFF00或0x0000                | 同请求                    |
+//! | 06     | 地址(2) 值(2)                         | 同请求                    |
+//! | 15     | 地址(2) 数量(2) 字节数(1) 位          | 地址(2) 数量(2)           |
+//! | 16     | 地址(2) 数量(2) 字节数(1) 寄存器      | 地址(2) 数量(2)           |
+//!
+//! 异常应答: 从机地址(1) 功能码|0x80(1) 异常码(1)
+
+use crc16::{State, MODBUS};
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+//一帧最多256字节
+pub const MAX_ADU: usize = 256;
+//广播地址，从机只执行写操作，不应答
+pub const BROADCAST: u8 = 0;
+
+pub const READ_COILS: u8 = 0x01;
+pub const READ_DISCRETE_INPUTS: u8 = 0x02;
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const READ_INPUT_REGISTERS: u8 = 0x04;
+pub const WRITE_SINGLE_COIL: u8 = 0x05;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub const WRITE_MULTIPLE_COILS: u8 = 0x0F;
+pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+//协议允许的数量，超出时应答IllegalDataValue
+const MAX_READ_BITS: u16 = 2000;
+const MAX_READ_WORDS: u16 = 125;
+const MAX_WRITE_BITS: u16 = 1968;
+const MAX_WRITE_WORDS: u16 = 123;
+
+const COIL_ON: u16 = 0xFF00;
+const COIL_OFF: u16 = 0x0000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Error {
+    Timeout, //从机没有应答
+    Crc,
+    Frame,   //长度不对，或者应答和请求对不上
+    Invalid, //请求的数量超出协议允许的范围，或者缓冲区不够
+    Exception(Exception),
+    Io, //串口读写失败
+}
+
+/// 从机的异常码
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Exception {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    ServerDeviceFailure,
+    Acknowledge,
+    ServerDeviceBusy,
+    MemoryParityError,
+    GatewayPathUnavailable,
+    GatewayTargetFailed,
+    Unknown(u8),
+}
+
+impl Exception {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => Exception::IllegalFunction,
+            0x02 => Exception::IllegalDataAddress,
+            0x03 => Exception::IllegalDataValue,
+            0x04 => Exception::ServerDeviceFailure,
+            0x05 => Exception::Acknowledge,
+            0x06 => Exception::ServerDeviceBusy,
+            0x08 => Exception::MemoryParityError,
+            0x0A => Exception::GatewayPathUnavailable,
+            0x0B => Exception::GatewayTargetFailed,
+            code => Exception::Unknown(code),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            Exception::IllegalFunction => 0x01,
+            Exception::IllegalDataAddress => 0x02,
+            Exception::IllegalDataValue => 0x03,
+            Exception::ServerDeviceFailure => 0x04,
+            Exception::Acknowledge => 0x05,
+            Exception::ServerDeviceBusy => 0x06,
+            Exception::MemoryParityError => 0x08,
+            Exception::GatewayPathUnavailable => 0x0A,
+            Exception::GatewayTargetFailed => 0x0B,
+            Exception::Unknown(code) => code,
+        }
+    }
+}
+
+/// 线圈或离散输入的值，编码时来自应用的数组，解码时直接引用帧里的字节
+#[derive(Debug, Copy, Clone)]
+pub enum Bits<'a> {
+    Values(&'a [bool]),
+    Packed { bytes: &'a [u8], count: u16 }, //低位在前
+}
+
+impl<'a> Bits<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Bits::Values(values) => values.len(),
+            Bits::Packed { count, .. } => *count as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<bool> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(match self {
+            Bits::Values(values) => values[index],
+            Bits::Packed { bytes, .. } => bytes[index / 8] & (1 << (index % 8)) != 0,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len()).map(move |i| self.get(i).unwrap_or(false))
+    }
+}
+
+impl PartialEq for Bits<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+/// 寄存器的值，编码时来自应用的数组，解码时直接引用帧里的字节
+#[derive(Debug, Copy, Clone)]
+pub enum Words<'a> {
+    Values(&'a [u16]),
+    Packed(&'a [u8]), //大端
+}
+
+impl<'a> Words<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            Words::Values(values) => values.len(),
+            Words::Packed(bytes) => bytes.len() / 2,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<u16> {
+        if index >= self.len() {
+            return None;
+        }
+        Some(match self {
+            Words::Values(values) => values[index],
+            Words::Packed(bytes) => u16::from_be_bytes([bytes[index * 2], bytes[index * 2 + 1]]),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len()).map(move |i| self.get(i).unwrap_or(0))
+    }
+}
+
+impl PartialEq for Words<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Request<'a> {
+    ReadCoils { address: u16, count: u16 },
+    ReadDiscreteInputs { address: u16, count: u16 },
+    ReadHoldingRegisters { address: u16, count: u16 },
+    ReadInputRegisters { address: u16, count: u16 },
+    WriteSingleCoil { address: u16, value: bool },
+    WriteSingleRegister { address: u16, value: u16 },
+    WriteMultipleCoils { address: u16, values: Bits<'a> },
+    WriteMultipleRegisters { address: u16, values: Words<'a> },
+}
+
+impl Request<'_> {
+    pub fn function(&self) -> u8 {
+        match self {
+            Request::ReadCoils { .. } => READ_COILS,
+            Request::ReadDiscreteInputs { .. } => READ_DISCRETE_INPUTS,
+            Request::ReadHoldingRegisters { .. } => READ_HOLDING_REGISTERS,
+            Request::ReadInputRegisters { .. } => READ_INPUT_REGISTERS,
+            Request::WriteSingleCoil { .. } => WRITE_SINGLE_COIL,
+            Request::WriteSingleRegister { .. } => WRITE_SINGLE_REGISTER,
+            Request::WriteMultipleCoils { .. } => WRITE_MULTIPLE_COILS,
+            Request::WriteMultipleRegisters { .. } => WRITE_MULTIPLE_REGISTERS,
+        }
+    }
+
+    //广播只能用写操作
+    pub fn is_write(&self) -> bool {
+        self.function() >= WRITE_SINGLE_COIL
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Response<'a> {
+    ReadCoils(Bits<'a>),
+    ReadDiscreteInputs(Bits<'a>),
+    ReadHoldingRegisters(Words<'a>),
+    ReadInputRegisters(Words<'a>),
+    WriteSingleCoil { address: u16, value: bool },
+    WriteSingleRegister { address: u16, value: u16 },
+    WriteMultipleCoils { address: u16, count: u16 },
+    WriteMultipleRegisters { address: u16, count: u16 },
+}
+
+impl Response<'_> {
+    pub fn function(&self) -> u8 {
+        match self {
+            Response::ReadCoils(_) => READ_COILS,
+            Response::ReadDiscreteInputs(_) => READ_DISCRETE_INPUTS,
+            Response::ReadHoldingRegisters(_) => READ_HOLDING_REGISTERS,
+            Response::ReadInputRegisters(_) => READ_INPUT_REGISTERS,
+            Response::WriteSingleCoil { .. } => WRITE_SINGLE_COIL,
+            Response::WriteSingleRegister { .. } => WRITE_SINGLE_REGISTER,
+            Response::WriteMultipleCoils { .. } => WRITE_MULTIPLE_COILS,
+            Response::WriteMultipleRegisters { .. } => WRITE_MULTIPLE_REGISTERS,
+        }
+    }
+}
+
+pub fn crc(data: &[u8]) -> u16 {
+    State::<MODBUS>::calculate(data)
+}
+
+//主机和从机的定时器每100us一次
+pub const TICK_US: u32 = 100;
+
+//帧间隔，波特率高于19200时固定1750us
+pub fn silence_us(baudrate: u32) -> u32 {
+    if baudrate > 19200 {
+        1750
+    } else {
+        //一个字符11位，3.5个字符
+        38_500_000 / baudrate + 1
+    }
+}
+
+//帧间隔折算成定时器计数，多数一次免得提前结束
+pub fn silence_ticks(baudrate: u32) -> u32 {
+    silence_us(baudrate) / TICK_US + 1
+}
+
+//根据已经收到的开头几个字节算出请求的总长度，还不够判断时返回None
+pub fn request_len(frame: &[u8]) -> Option<usize> {
+    match *frame.get(1)? {
+        READ_COILS..=WRITE_SINGLE_REGISTER => Some(8),
+        WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => Some(9 + *frame.get(6)? as usize),
+        _ => None,
+    }
+}
+
+//根据已经收到的开头几个字节算出应答的总长度，还不够判断时返回None
+pub fn response_len(frame: &[u8]) -> Option<usize> {
+    match *frame.get(1)? {
+        function if function & 0x80 != 0 => Some(5),
+        READ_COILS..=READ_INPUT_REGISTERS => Some(5 + *frame.get(2)? as usize),
+        WRITE_SINGLE_COIL | WRITE_SINGLE_REGISTER => Some(8),
+        WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => Some(8),
+        _ => None,
+    }
+}
+
+//n个位打包后的字节数
+fn packed_len(count: u16) -> usize {
+    match count % 8 {
+        0 => count as usize / 8,
+        _ => count as usize / 8 + 1,
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8], slave: u8, function: u8) -> Result<Self> {
+        let mut writer = Self { buf, len: 0 };
+        writer.u8(slave)?;
+        writer.u8(function)?;
+        Ok(writer)
+    }
+
+    fn u8(&mut self, byte: u8) -> Result<()> {
+        *self.buf.get_mut(self.len).ok_or(Error::Invalid)? = byte;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn u16(&mut self, value: u16) -> Result<()> {
+        let [high, low] = value.to_be_bytes();
+        self.u8(high)?;
+        self.u8(low)
+    }
+
+    fn bits(&mut self, bits: &Bits) -> Result<()> {
+        self.u8(packed_len(bits.len() as u16) as u8)?;
+        let mut byte = 0;
+        for (i, bit) in bits.iter().enumerate() {
+            if bit {
+                byte |= 1 << (i % 8);
+            }
+            if i % 8 == 7 {
+                self.u8(byte)?;
+                byte = 0;
+            }
+        }
+        if bits.len() & 7 != 0 {
+            self.u8(byte)?;
+        }
+        Ok(())
+    }
+
+    fn words(&mut self, words: &Words) -> Result<()> {
+        self.u8((words.len() * 2) as u8)?;
+        for word in words.iter() {
+            self.u16(word)?;
+        }
+        Ok(())
+    }
+
+    //加上CRC，返回整帧的长度
+    fn finish(mut self) -> Result<usize> {
+        let [low, high] = crc(&self.buf[..self.len]).to_le_bytes();
+        self.u8(low)?;
+        self.u8(high)?;
+        Ok(self.len)
+    }
+}
+
+//数量是否在1..=max之间
+fn check_count(count: usize, max: u16) -> bool {
+    count >= 1 && count <= max as usize
+}
+
+pub fn encode_request(slave: u8, request: &Request, buf: &mut [u8]) -> Result<usize> {
+    let mut writer = Writer::new(buf, slave, request.function())?;
+    match request {
+        Request::ReadCoils { address, count } | Request::ReadDiscreteInputs { address, count } => {
+            if !check_count(*count as usize, MAX_READ_BITS) {
+                return Err(Error::Invalid);
+            }
+            writer.u16(*address)?;
+            writer.u16(*count)?;
+        }
+        Request::ReadHoldingRegisters { address, count }
+        | Request::ReadInputRegisters { address, count } => {
+            if !check_count(*count as usize, MAX_READ_WORDS) {
+                return Err(Error::Invalid);
+            }
+            writer.u16(*address)?;
+            writer.u16(*count)?;
+        }
+        Request::WriteSingleCoil { address, value } => {
+            writer.u16(*address)?;
+            writer.u16(if *value { COIL_ON } else { COIL_OFF })?;
+        }
+        Request::WriteSingleRegister { address, value } => {
+            writer.u16(*address)?;
+            writer.u16(*value)?;
+        }
+        Request::WriteMultipleCoils { address, values } => {
+            if !check_count(values.len(), MAX_WRITE_BITS) {
+                return Err(Error::Invalid);
+            }
+            writer.u16(*address)?;
+            writer.u16(values.len() as u16)?;
+            writer.bits(values)?;
+        }
+        Request::WriteMultipleRegisters { address, values } => {
+            if !check_count(values.len(), MAX_WRITE_WORDS) {
+                return Err(Error::Invalid);
+            }
+            writer.u16(*address)?;
+            writer.u16(values.len() as u16)?;
+            writer.words(values)?;
+        }
+    }
+    writer.finish()
+}
+
+pub fn encode_response(slave: u8, response: &Response, buf: &mut [u8]) -> Result<usize> {
+    let mut writer = Writer::new(buf, slave, response.function())?;
+    match response {
+        Response::ReadCoils(bits) | Response::ReadDiscreteInputs(bits) => writer.bits(bits)?,
+        Response::ReadHoldingRegisters(words) | Response::ReadInputRegisters(words) => {
+            writer.words(words)?
+        }
+        Response::WriteSingleCoil { address, value } => {
+            writer.u16(*address)?;
+            writer.u16(if *value { COIL_ON } else { COIL_OFF })?;
+        }
+        Response::WriteSingleRegister { address, value } => {
+            writer.u16(*address)?;
+            writer.u16(*value)?;
+        }
+        Response::WriteMultipleCoils { address, count }
+        | Response::WriteMultipleRegisters { address, count } => {
+            writer.u16(*address)?;
+            writer.u16(*count)?;
+        }
+    }
+    writer.finish()
+}
+
+pub fn encode_exception(
+    slave: u8,
+    function: u8,
+    exception: Exception,
+    buf: &mut [u8],
+) -> Result<usize> {
+    let mut writer = Writer::new(buf, slave, function | 0x80)?;
+    writer.u8(exception.code())?;
+    writer.finish()
+}
+
+//检查长度和CRC，返回去掉CRC的部分
+fn check(frame: &[u8]) -> Result<&[u8]> {
+    if frame.len() < 4 || frame.len() > MAX_ADU {
+        return Err(Error::Frame);
+    }
+    let (pdu, tail) = frame.split_at(frame.len() - 2);
+    if crc(pdu) != u16::from_le_bytes([tail[0], tail[1]]) {
+        return Err(Error::Crc);
+    }
+    Ok(pdu)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn coil(value: u16) -> Result<bool> {
+    match value {
+        COIL_ON => Ok(true),
+        COIL_OFF => Ok(false),
+        _ => Err(Error::Exception(Exception::IllegalDataValue)),
+    }
+}
+
+//从机解析请求，返回从机地址和请求。
+//CRC或长度错误时从机不应答；功能码不支持或数量超出范围时返回Exception，从机应答异常
+pub fn decode_request(frame: &[u8]) -> Result<(u8, Request<'_>)> {
+    let pdu = check(frame)?;
+    let (slave, function, data) = (pdu[0], pdu[1], &pdu[2..]);
+    let request = match function {
+        READ_COILS..=WRITE_SINGLE_REGISTER => {
+            if data.len() != 4 {
+                return Err(Error::Frame);
+            }
+            let (address, value) = (u16_at(data, 0), u16_at(data, 2));
+            let max = match function {
+                READ_COILS | READ_DISCRETE_INPUTS => MAX_READ_BITS,
+                _ => MAX_READ_WORDS,
+            };
+            if function <= READ_INPUT_REGISTERS && !check_count(value as usize, max) {
+                return Err(Error::Exception(Exception::IllegalDataValue));
+            }
+            match function {
+                READ_COILS => Request::ReadCoils {
+                    address,
+                    count: value,
+                },
+                READ_DISCRETE_INPUTS => Request::ReadDiscreteInputs {
+                    address,
+                    count: value,
+                },
+                READ_HOLDING_REGISTERS => Request::ReadHoldingRegisters {
+                    address,
+                    count: value,
+                },
+                READ_INPUT_REGISTERS => Request::ReadInputRegisters {
+                    address,
+                    count: value,
+                },
+                WRITE_SINGLE_COIL => Request::WriteSingleCoil {
+                    address,
+                    value: coil(value)?,
+                },
+                _ => Request::WriteSingleRegister { address, value },
+            }
+        }
+        WRITE_MULTIPLE_COILS | WRITE_MULTIPLE_REGISTERS => {
+            if data.len() < 5 || data.len() != 5 + data[4] as usize {
+                return Err(Error::Frame);
+            }
+            let (address, count, bytes) = (u16_at(data, 0), u16_at(data, 2), &data[5..]);
+            if function == WRITE_MULTIPLE_COILS {
+                if !check_count(count as usize, MAX_WRITE_BITS) || bytes.len() != packed_len(count)
+                {
+                    return Err(Error::Exception(Exception::IllegalDataValue));
+                }
+                Request::WriteMultipleCoils {
+                    address,
+                    values: Bits::Packed { bytes, count },
+                }
+            } else {
+                if !check_count(count as usize, MAX_WRITE_WORDS)
+                    || bytes.len() != count as usize * 2
+                {
+                    return Err(Error::Exception(Exception::IllegalDataValue));
+                }
+                Request::WriteMultipleRegisters {
+                    address,
+                    values: Words::Packed(bytes),
+                }
+            }
+        }
+        _ => return Err(Error::Exception(Exception::IllegalFunction)),
+    };
+    Ok((slave, request))
+}
+
+//主机解析应答，检查从机地址、功能码以及写操作的回显和请求一致。
+//从机应答异常时返回Error::Exception
+pub fn decode_response<'a>(slave: u8, request: &Request, frame: &'a [u8]) -> Result<Response<'a>> {
+    let pdu = check(frame)?;
+    let (function, data) = (pdu[1], &pdu[2..]);
+    if pdu[0] != slave {
+        return Err(Error::Frame);
+    }
+    if function == request.function() | 0x80 && data.len() == 1 {
+        return Err(Error::Exception(Exception::from_code(data[0])));
+    }
+    if function != request.function() {
+        return Err(Error::Frame);
+    }
+    let response = match *request {
+        Request::ReadCoils { count, .. } | Request::ReadDiscreteInputs { count, .. } => {
+            if data.is_empty()
+                || data[0] as usize != packed_len(count)
+                || data.len() != 1 + data[0] as usize
+            {
+                return Err(Error::Frame);
+            }
+            let bits = Bits::Packed {
+                bytes: &data[1..],
+                count,
+            };
+            match request {
+                Request::ReadCoils { .. } => Response::ReadCoils(bits),
+                _ => Response::ReadDiscreteInputs(bits),
+            }
+        }
+        Request::ReadHoldingRegisters { count, .. } | Request::ReadInputRegisters { count, .. } => {
+            if data.is_empty()
+                || data[0] as usize != count as usize * 2
+                || data.len() != 1 + data[0] as usize
+            {
+                return Err(Error::Frame);
+            }
+            let words = Words::Packed(&data[1..]);
+            match request {
+                Request::ReadHoldingRegisters { .. } => Response::ReadHoldingRegisters(words),
+                _ => Response::ReadInputRegisters(words),
+            }
+        }
+        _ => {
+            if data.len() != 4 {
+                return Err(Error::Frame);
+            }
+            let (address, value) = (u16_at(data, 0), u16_at(data, 2));
+            let response = match request {
+                Request::WriteSingleCoil { .. } => Response::WriteSingleCoil {
+                    address,
+                    value: coil(value).map_err(|_| Error::Frame)?,
+                },
+                Request::WriteSingleRegister { .. } => {
+                    Response::WriteSingleRegister { address, value }
+                }
+                Request::WriteMultipleCoils { .. } => Response::WriteMultipleCoils {
+                    address,
+                    count: value,
+                },
+                _ => Response::WriteMultipleRegisters {
+                    address,
+                    count: value,
+                },
+            };
+            if !echoes(request, &response) {
+                return Err(Error::Frame);
+            }
+            response
+        }
+    };
+    Ok(response)
+}
+
+//写操作的应答是否和请求一致
+fn echoes(request: &Request, response: &Response) -> bool {
+    match (*request, *response) {
+        (
+            Request::WriteSingleCoil { address, value },
+            Response::WriteSingleCoil {
+                address: echo_address,
+                value: echo_value,
+            },
+        ) => address == echo_address && value == echo_value,
+        (
+            Request::WriteSingleRegister { address, value },
+            Response::WriteSingleRegister {
+                address: echo_address,
+                value: echo_value,
+            },
+        ) => address == echo_address && value == echo_value,
+        (
+            Request::WriteMultipleCoils { address, values },
+            Response::WriteMultipleCoils {
+                address: echo_address,
+                count,
+            },
+        ) => address == echo_address && values.len() == count as usize,
+        (
+            Request::WriteMultipleRegisters { address, values },
+            Response::WriteMultipleRegisters {
+                address: echo_address,
+                count,
+            },
+        ) => address == echo_address && values.len() == count as usize,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    //给PDU加上CRC
+    fn with_crc(pdu: &[u8]) -> Vec<u8> {
+        let mut frame = pdu.to_vec();
+        frame.extend_from_slice(&crc(pdu).to_le_bytes());
+        frame
+    }
+
+    fn encoded_request(slave: u8, request: &Request) -> Vec<u8> {
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_request(slave, request, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    //Modbus规范里的例子
+    #[test]
+    fn known_frames() {
+        let bits = [
+            true, false, true, true, false, false, true, true, true, false,
+        ];
+        let words = [0x000A, 0x0102];
+        let frames: [(Request, &[u8]); 7] = [
+            (
+                Request::ReadCoils {
+                    address: 0x13,
+                    count: 0x25,
+                },
+                &[0x11, 0x01, 0x00, 0x13, 0x00, 0x25, 0x0E, 0x84],
+            ),
+            (
+                Request::ReadHoldingRegisters {
+                    address: 0x6B,
+                    count: 3,
+                },
+                &[0x11, 0x03, 0x00, 0x6B, 0x00, 0x03, 0x76, 0x87],
+            ),
+            (
+                Request::ReadHoldingRegisters {
+                    address: 0,
+                    count: 10,
+                },
+                &[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD],
+            ),
+            (
+                Request::WriteSingleCoil {
+                    address: 0xAC,
+                    value: true,
+                },
+                &[0x11, 0x05, 0x00, 0xAC, 0xFF, 0x00, 0x4E, 0x8B],
+            ),
+            (
+                Request::WriteSingleRegister {
+                    address: 1,
+                    value: 3,
+                },
+                &[0x11, 0x06, 0x00, 0x01, 0x00, 0x03, 0x9A, 0x9B],
+            ),
+            (
+                Request::WriteMultipleCoils {
+                    address: 0x13,
+                    values: Bits::Values(&bits),
+                },
+                &[
+                    0x11, 0x0F, 0x00, 0x13, 0x00, 0x0A, 0x02, 0xCD, 0x01, 0xBF, 0x0B,
+                ],
+            ),
+            (
+                Request::WriteMultipleRegisters {
+                    address: 1,
+                    values: Words::Values(&words),
+                },
+                &[
+                    0x11, 0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0A, 0x01, 0x02, 0xC6, 0xF0,
+                ],
+            ),
+        ];
+        for (request, frame) in frames.iter() {
+            assert_eq!(encoded_request(frame[0], request), *frame);
+            assert_eq!(decode_request(frame), Ok((frame[0], *request)));
+        }
+        assert_eq!(silence_us(9600), 4011);
+        assert_eq!(silence_us(115200), 1750);
+        assert_eq!(silence_ticks(9600), 41);
+        assert_eq!(silence_ticks(115200), 18);
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let bits = [
+            true, false, true, true, false, false, true, false, true, true,
+        ];
+        let words = [1u16, 0xBEEF, 65535];
+        let requests = [
+            Request::ReadCoils {
+                address: 19,
+                count: 37,
+            },
+            Request::ReadDiscreteInputs {
+                address: 196,
+                count: 22,
+            },
+            Request::ReadHoldingRegisters {
+                address: 107,
+                count: 3,
+            },
+            Request::ReadInputRegisters {
+                address: 8,
+                count: 1,
+            },
+            Request::WriteSingleCoil {
+                address: 172,
+                value: false,
+            },
+            Request::WriteSingleRegister {
+                address: 1,
+                value: 3,
+            },
+            Request::WriteMultipleCoils {
+                address: 19,
+                values: Bits::Values(&bits),
+            },
+            Request::WriteMultipleRegisters {
+                address: 1,
+                values: Words::Values(&words),
+            },
+        ];
+        for request in requests.iter() {
+            let mut frame = encoded_request(7, request);
+            assert_eq!(request_len(&frame[..7]), Some(frame.len()));
+            assert_eq!(decode_request(&frame), Ok((7, *request)));
+            frame[3] ^= 1;
+            assert_eq!(decode_request(&frame), Err(Error::Crc));
+            assert_eq!(decode_request(&frame[..3]), Err(Error::Frame));
+        }
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let bits = [true, true, false, true, false, false, false, true, true];
+        let words = [0x1234u16, 0, 0xFFFF];
+        let cases = [
+            (
+                Request::ReadCoils {
+                    address: 0,
+                    count: 9,
+                },
+                Response::ReadCoils(Bits::Values(&bits)),
+            ),
+            (
+                Request::ReadDiscreteInputs {
+                    address: 3,
+                    count: 9,
+                },
+                Response::ReadDiscreteInputs(Bits::Values(&bits)),
+            ),
+            (
+                Request::ReadHoldingRegisters {
+                    address: 1,
+                    count: 3,
+                },
+                Response::ReadHoldingRegisters(Words::Values(&words)),
+            ),
+            (
+                Request::ReadInputRegisters {
+                    address: 5,
+                    count: 3,
+                },
+                Response::ReadInputRegisters(Words::Values(&words)),
+            ),
+            (
+                Request::WriteSingleCoil {
+                    address: 4,
+                    value: true,
+                },
+                Response::WriteSingleCoil {
+                    address: 4,
+                    value: true,
+                },
+            ),
+            (
+                Request::WriteSingleRegister {
+                    address: 2,
+                    value: 0xABCD,
+                },
+                Response::WriteSingleRegister {
+                    address: 2,
+                    value: 0xABCD,
+                },
+            ),
+            (
+                Request::WriteMultipleCoils {
+                    address: 8,
+                    values: Bits::Values(&bits),
+                },
+                Response::WriteMultipleCoils {
+                    address: 8,
+                    count: 9,
+                },
+            ),
+            (
+                Request::WriteMultipleRegisters {
+                    address: 9,
+                    values: Words::Values(&words),
+                },
+                Response::WriteMultipleRegisters {
+                    address: 9,
+                    count: 3,
+                },
+            ),
+        ];
+        let mut buf = [0u8; MAX_ADU];
+        for (request, response) in cases.iter() {
+            let len = encode_response(3, response, &mut buf).unwrap();
+            assert_eq!(response_len(&buf[..3]), Some(len));
+            assert_eq!(decode_response(3, request, &buf[..len]), Ok(*response));
+            //别的从机的应答
+            assert_eq!(decode_response(4, request, &buf[..len]), Err(Error::Frame));
+        }
+        //数量和请求对不上
+        let len = encode_response(3, &cases[2].1, &mut buf).unwrap();
+        let request = Request::ReadHoldingRegisters {
+            address: 1,
+            count: 2,
+        };
+        assert_eq!(decode_response(3, &request, &buf[..len]), Err(Error::Frame));
+    }
+
+    #[test]
+    fn exception_frames() {
+        let mut buf = [0u8; MAX_ADU];
+        let len =
+            encode_exception(0x0A, READ_COILS, Exception::IllegalDataAddress, &mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0x0A, 0x81, 0x02, 0xB0, 0x53]);
+        assert_eq!(response_len(&buf[..2]), Some(len));
+        let request = Request::ReadCoils {
+            address: 0,
+            count: 10,
+        };
+        assert_eq!(
+            decode_response(0x0A, &request, &buf[..len]),
+            Err(Error::Exception(Exception::IllegalDataAddress))
+        );
+        //别的功能码的异常
+        let request = Request::ReadInputRegisters {
+            address: 0,
+            count: 1,
+        };
+        assert_eq!(
+            decode_response(0x0A, &request, &buf[..len]),
+            Err(Error::Frame)
+        );
+        for code in 0..=0x0C {
+            assert_eq!(Exception::from_code(code).code(), code);
+        }
+    }
+
+    #[test]
+    fn request_limits() {
+        //数量为0
+        for function in READ_COILS..=READ_INPUT_REGISTERS {
+            let frame = with_crc(&[1, function, 0, 0, 0, 0]);
+            assert_eq!(
+                decode_request(&frame),
+                Err(Error::Exception(Exception::IllegalDataValue))
+            );
+        }
+        //最多读2000个位、125个寄存器
+        let frame = with_crc(&[1, READ_COILS, 0, 0, 0x07, 0xD0]);
+        assert!(decode_request(&frame).is_ok());
+        let frame = with_crc(&[1, READ_COILS, 0, 0, 0x07, 0xD1]);
+        assert_eq!(
+            decode_request(&frame),
+            Err(Error::Exception(Exception::IllegalDataValue))
+        );
+        let frame = with_crc(&[1, READ_HOLDING_REGISTERS, 0, 0, 0, 126]);
+        assert_eq!(
+            decode_request(&frame),
+            Err(Error::Exception(Exception::IllegalDataValue))
+        );
+        //字节数和后面的数据长度对不上
+        let frame = with_crc(&[1, WRITE_MULTIPLE_COILS, 0, 0, 0, 10, 3, 0xFF, 0x03]);
+        assert_eq!(decode_request(&frame), Err(Error::Frame));
+        //字节数和数量对不上
+        let frame = with_crc(&[1, WRITE_MULTIPLE_COILS, 0, 0, 0, 10, 1, 0xFF]);
+        assert_eq!(
+            decode_request(&frame),
+            Err(Error::Exception(Exception::IllegalDataValue))
+        );
+        let frame = with_crc(&[1, WRITE_MULTIPLE_REGISTERS, 0, 0, 0, 2, 2, 0, 1]);
+        assert_eq!(
+            decode_request(&frame),
+            Err(Error::Exception(Exception::IllegalDataValue))
+        );
+        //线圈的值只能是0xFF00或0x0000
+        let frame = with_crc(&[1, WRITE_SINGLE_COIL, 0, 0, 0x12, 0x34]);
+        assert_eq!(
+            decode_request(&frame),
+            Err(Error::Exception(Exception::IllegalDataValue))
+        );
+        let frame = with_crc(&[1, 0x2B, 0x0E, 0x01]);
+        assert_eq!(
+            decode_request(&frame),
+            Err(Error::Exception(Exception::IllegalFunction))
+        );
+        //编码时也检查数量
+        let mut buf = [0u8; MAX_ADU];
+        let request = Request::ReadCoils {
+            address: 0,
+            count: 2001,
+        };
+        assert_eq!(encode_request(1, &request, &mut buf), Err(Error::Invalid));
+        let request = Request::WriteMultipleRegisters {
+            address: 0,
+            values: Words::Values(&[]),
+        };
+        assert_eq!(encode_request(1, &request, &mut buf), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn write_echo_mismatch() {
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_response(
+            3,
+            &Response::WriteSingleRegister {
+                address: 1,
+                value: 2,
+            },
+            &mut buf,
+        )
+        .unwrap();
+        let request = Request::WriteSingleRegister {
+            address: 1,
+            value: 3,
+        };
+        assert_eq!(decode_response(3, &request, &buf[..len]), Err(Error::Frame));
+        let request = Request::WriteSingleRegister {
+            address: 2,
+            value: 2,
+        };
+        assert_eq!(decode_response(3, &request, &buf[..len]), Err(Error::Frame));
+
+        let len = encode_response(
+            3,
+            &Response::WriteMultipleCoils {
+                address: 8,
+                count: 4,
+            },
+            &mut buf,
+        )
+        .unwrap();
+        let request = Request::WriteMultipleCoils {
+            address: 8,
+            values: Bits::Values(&[true; 5]),
+        };
+        assert_eq!(decode_response(3, &request, &buf[..len]), Err(Error::Frame));
+        //线圈回显的值不合法
+        let frame = with_crc(&[3, WRITE_SINGLE_COIL, 0, 4, 0x12, 0x34]);
+        let request = Request::WriteSingleCoil {
+            address: 4,
+            value: true,
+        };
+        assert_eq!(decode_response(3, &request, &frame), Err(Error::Frame));
+    }
+}
@@ -0,0 +1,387 @@
+use super::frame::{
+    decode_response, encode_request, response_len, silence_ticks, Bits, Error, Request, Response,
+    Result, Words, BROADCAST, MAX_ADU, TICK_US,
+};
+use crate::hal::time::{Hertz, U32Ext};
+
+/// Modbus RTU主机，串口用`RW`或者`HalfDuplex`(RS-485)，timer用来计算帧间隔和超时
+pub struct Master<S, TIM> {
+    serial: S,
+    timer: TIM,
+    silence: u32,    //帧间隔，定时器计数
+    timeout: u32,    //等应答的毫秒数
+    turnaround: u32, //广播之后等待从机处理的毫秒数
+    buf: [u8; MAX_ADU],
+}
+
+impl<S, TIM> Master<S, TIM>
+where
+    S: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    //baudrate要和串口一致，用来计算3.5个字符的帧间隔
+    pub fn new(serial: S, mut timer: TIM, baudrate: u32) -> Self {
+        timer.start((1_000_000 / TICK_US).hz());
+        Self {
+            serial,
+            timer,
+            silence: silence_ticks(baudrate),
+            timeout: 1000,
+            turnaround: 100,
+            buf: [0; MAX_ADU],
+        }
+    }
+
+    //等应答的毫秒数，默认1秒
+    pub fn timeout(mut self, ms: u32) -> Self {
+        self.timeout = ms;
+        self
+    }
+
+    //广播之后的等待毫秒数，默认100
+    pub fn turnaround(mut self, ms: u32) -> Self {
+        self.turnaround = ms;
+        self
+    }
+
+    pub fn release(self) -> (S, TIM) {
+        (self.serial, self.timer)
+    }
+
+    pub fn read_coils(&mut self, slave: u8, address: u16, values: &mut [bool]) -> Result<()> {
+        let request = Request::ReadCoils {
+            address,
+            count: count(values.len())?,
+        };
+        match self.request(slave, &request)? {
+            Some(Response::ReadCoils(bits)) => copy_bits(&bits, values),
+            _ => return Err(Error::Frame),
+        }
+        Ok(())
+    }
+
+    pub fn read_discrete_inputs(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [bool],
+    ) -> Result<()> {
+        let request = Request::ReadDiscreteInputs {
+            address,
+            count: count(values.len())?,
+        };
+        match self.request(slave, &request)? {
+            Some(Response::ReadDiscreteInputs(bits)) => copy_bits(&bits, values),
+            _ => return Err(Error::Frame),
+        }
+        Ok(())
+    }
+
+    pub fn read_holding_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [u16],
+    ) -> Result<()> {
+        let request = Request::ReadHoldingRegisters {
+            address,
+            count: count(values.len())?,
+        };
+        match self.request(slave, &request)? {
+            Some(Response::ReadHoldingRegisters(words)) => copy_words(&words, values),
+            _ => return Err(Error::Frame),
+        }
+        Ok(())
+    }
+
+    pub fn read_input_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &mut [u16],
+    ) -> Result<()> {
+        let request = Request::ReadInputRegisters {
+            address,
+            count: count(values.len())?,
+        };
+        match self.request(slave, &request)? {
+            Some(Response::ReadInputRegisters(words)) => copy_words(&words, values),
+            _ => return Err(Error::Frame),
+        }
+        Ok(())
+    }
+
+    //slave为BROADCAST时所有从机执行，没有应答
+    pub fn write_single_coil(&mut self, slave: u8, address: u16, value: bool) -> Result<()> {
+        self.request(slave, &Request::WriteSingleCoil { address, value })
+            .map(|_| ())
+    }
+
+    pub fn write_single_register(&mut self, slave: u8, address: u16, value: u16) -> Result<()> {
+        self.request(slave, &Request::WriteSingleRegister { address, value })
+            .map(|_| ())
+    }
+
+    pub fn write_multiple_coils(&mut self, slave: u8, address: u16, values: &[bool]) -> Result<()> {
+        let request = Request::WriteMultipleCoils {
+            address,
+            values: Bits::Values(values),
+        };
+        self.request(slave, &request).map(|_| ())
+    }
+
+    pub fn write_multiple_registers(
+        &mut self,
+        slave: u8,
+        address: u16,
+        values: &[u16],
+    ) -> Result<()> {
+        let request = Request::WriteMultipleRegisters {
+            address,
+            values: Words::Values(values),
+        };
+        self.request(slave, &request).map(|_| ())
+    }
+
+    //发送请求并等待应答，广播时返回None
+    pub fn request(&mut self, slave: u8, request: &Request) -> Result<Option<Response<'_>>> {
+        if slave == BROADCAST && !request.is_write() {
+            return Err(Error::Invalid);
+        }
+        let len = encode_request(slave, request, &mut self.buf)?;
+        self.wait_silence();
+        for byte in &self.buf[..len] {
+            nb::block!(self.serial.write(*byte)).map_err(|_| Error::Io)?;
+        }
+        nb::block!(self.serial.flush()).map_err(|_| Error::Io)?;
+        if slave == BROADCAST {
+            self.delay(self.turnaround * 1000 / TICK_US);
+            return Ok(None);
+        }
+        let len = self.receive()?;
+        decode_response(slave, request, &self.buf[..len]).map(Some)
+    }
+
+    //丢掉总线上残留的字节，直到空闲3.5个字符
+    fn wait_silence(&mut self) {
+        let mut idle = 0;
+        while idle < self.silence {
+            if self.serial.read().is_ok() {
+                idle = 0;
+            } else if self.timer.wait().is_ok() {
+                idle += 1;
+            }
+        }
+    }
+
+    fn delay(&mut self, ticks: u32) {
+        for _ in 0..ticks {
+            nb::block!(self.timer.wait()).ok();
+        }
+    }
+
+    //收到完整的应答，或者收到一部分后空闲3.5个字符时返回
+    fn receive(&mut self) -> Result<usize> {
+        let timeout = self.timeout * 1000 / TICK_US;
+        let mut len = 0;
+        let mut idle = 0;
+        loop {
+            match self.serial.read() {
+                Ok(byte) => {
+                    if len < MAX_ADU {
+                        self.buf[len] = byte;
+                        len += 1;
+                    }
+                    idle = 0;
+                    if response_len(&self.buf[..len]) == Some(len) {
+                        return Ok(len);
+                    }
+                    continue;
+                }
+                //出错的字节丢掉，CRC校验会失败
+                Err(nb::Error::Other(_)) => continue,
+                Err(nb::Error::WouldBlock) => {}
+            }
+            if self.timer.wait().is_ok() {
+                idle += 1;
+                if len == 0 && idle >= timeout {
+                    return Err(Error::Timeout);
+                }
+                if len > 0 && idle >= self.silence {
+                    return Ok(len);
+                }
+            }
+        }
+    }
+}
+
+//超出u16的数量当作无效请求，不能截断
+fn count(len: usize) -> Result<u16> {
+    if len > u16::MAX as usize {
+        return Err(Error::Invalid);
+    }
+    Ok(len as u16)
+}
+
+fn copy_bits(bits: &Bits, values: &mut [bool]) {
+    for (value, bit) in values.iter_mut().zip(bits.iter()) {
+        *value = bit;
+    }
+}
+
+fn copy_words(words: &Words, values: &mut [u16]) {
+    for (value, word) in values.iter_mut().zip(words.iter()) {
+        *value = word;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame::{encode_exception, encode_response, Exception};
+    use super::*;
+    use crate::mock::{Port, Timer};
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+
+    //主机发完一帧(flush)后才放出从机的下一段应答
+    #[derive(Default)]
+    struct Bus {
+        port: Port,
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    impl Bus {
+        fn new(replies: &[&[u8]]) -> Self {
+            Self {
+                port: Port::default(),
+                replies: replies.iter().map(|reply| reply.to_vec()).collect(),
+            }
+        }
+    }
+
+    impl embedded_hal::serial::Read<u8> for Bus {
+        type Error = ();
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.port.read()
+        }
+    }
+
+    impl embedded_hal::serial::Write<u8> for Bus {
+        type Error = ();
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            self.port.write(byte)
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            if let Some(reply) = self.replies.pop_front() {
+                self.port.feed(&reply);
+            }
+            Ok(())
+        }
+    }
+
+    //记下等了多少个100us
+    #[derive(Default)]
+    struct Clock {
+        ticks: u32,
+    }
+
+    impl embedded_hal::timer::CountDown for Clock {
+        type Time = Hertz;
+        fn start<T: Into<Hertz>>(&mut self, _count: T) {}
+        fn wait(&mut self) -> nb::Result<(), void::Void> {
+            self.ticks += 1;
+            Ok(())
+        }
+    }
+
+    fn request_frame(slave: u8, request: &Request) -> Vec<u8> {
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_request(slave, request, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn response_frame(slave: u8, response: &Response) -> Vec<u8> {
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_response(slave, response, &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn read_registers() {
+        let reply = response_frame(
+            0x11,
+            &Response::ReadHoldingRegisters(Words::Values(&[0x022B, 0, 0x64])),
+        );
+        let mut bus = Bus::new(&[&reply]);
+        //上一次通信残留在总线上的字节先丢掉
+        bus.port.feed(&[0x11, 0x03]);
+        let mut master = Master::new(bus, Timer, 9600);
+        let mut values = [0u16; 3];
+        master
+            .read_holding_registers(0x11, 0x6B, &mut values)
+            .unwrap();
+        assert_eq!(values, [0x022B, 0, 0x64]);
+        let (mut bus, _) = master.release();
+        assert_eq!(
+            bus.port.take_output(),
+            [0x11, 0x03, 0x00, 0x6B, 0x00, 0x03, 0x76, 0x87]
+        );
+    }
+
+    #[test]
+    fn exception_reply() {
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_exception(0x0A, 0x01, Exception::IllegalDataAddress, &mut buf).unwrap();
+        let mut master = Master::new(Bus::new(&[&buf[..len]]), Timer, 9600);
+        let mut values = [false; 8];
+        assert_eq!(
+            master.read_coils(0x0A, 0x1000, &mut values),
+            Err(Error::Exception(Exception::IllegalDataAddress))
+        );
+    }
+
+    #[test]
+    fn timeout_and_partial_frames() {
+        let reply = response_frame(0x11, &Response::ReadInputRegisters(Words::Values(&[7])));
+        //第一次没有应答，第二次只收到一半，之后空闲3.5个字符
+        let mut master = Master::new(Bus::new(&[&[], &reply[..4]]), Timer, 9600).timeout(10);
+        let mut values = [0u16; 1];
+        assert_eq!(
+            master.read_input_registers(0x11, 8, &mut values),
+            Err(Error::Timeout)
+        );
+        assert_eq!(
+            master.read_input_registers(0x11, 8, &mut values),
+            Err(Error::Crc)
+        );
+    }
+
+    #[test]
+    fn broadcast_write() {
+        let mut master = Master::new(Bus::default(), Clock::default(), 9600).turnaround(5);
+        master.write_single_register(BROADCAST, 1, 3).unwrap();
+        let (mut bus, clock) = master.release();
+        assert_eq!(
+            bus.port.take_output(),
+            request_frame(
+                BROADCAST,
+                &Request::WriteSingleRegister {
+                    address: 1,
+                    value: 3
+                }
+            )
+        );
+        //发送前等一个帧间隔，发完后不读应答，只等turnaround
+        assert_eq!(clock.ticks, silence_ticks(9600) + 5 * 1000 / TICK_US);
+
+        //广播不能读
+        let mut master = Master::new(bus, clock, 9600);
+        let mut values = [0u16; 1];
+        assert_eq!(
+            master.read_holding_registers(BROADCAST, 0, &mut values),
+            Err(Error::Invalid)
+        );
+        assert!(master.release().0.port.output.is_empty());
+    }
+}
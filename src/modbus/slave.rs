@@ -0,0 +1,562 @@
+use super::frame::{
+    decode_request, encode_exception, encode_response, request_len, silence_ticks, Bits, Error,
+    Exception, Request, Response, Result, Words, BROADCAST, MAX_ADU, TICK_US,
+};
+use crate::hal::time::{Hertz, U32Ext};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+//value为None时读，Some时写，返回当前值
+type Handler<'a, T> = Box<dyn FnMut(u16, Option<T>) -> core::result::Result<T, Exception> + 'a>;
+
+//一段连续地址，读写交给应用的回调
+struct Map<'a, T> {
+    start: u16,
+    count: u16,
+    handler: Handler<'a, T>,
+}
+
+//同一类的所有地址段，比如全部保持寄存器
+struct Table<'a, T> {
+    maps: Vec<Map<'a, T>>,
+}
+
+impl<'a, T: Copy> Table<'a, T> {
+    fn new() -> Self {
+        Self { maps: Vec::new() }
+    }
+
+    fn find(&mut self, address: u16) -> Option<&mut Map<'a, T>> {
+        self.maps
+            .iter_mut()
+            .find(|map| address >= map.start && (address - map.start) < map.count)
+    }
+
+    //先检查整个范围都有回调，写到一半才发现地址不对时前面的已经写进去了
+    fn check(&mut self, address: u16, count: usize) -> core::result::Result<(), Exception> {
+        if address as usize + count > 0x10000 {
+            return Err(Exception::IllegalDataAddress);
+        }
+        for offset in 0..count {
+            if self.find(address + offset as u16).is_none() {
+                return Err(Exception::IllegalDataAddress);
+            }
+        }
+        Ok(())
+    }
+
+    fn access(&mut self, address: u16, value: Option<T>) -> core::result::Result<T, Exception> {
+        let map = self.find(address).ok_or(Exception::IllegalDataAddress)?;
+        (map.handler)(address, value)
+    }
+}
+
+/// Modbus RTU从机，PLC或者别的主机来读写应用登记的线圈和寄存器。
+/// 主循环里不停地调用`poll`，串口用`RW`或者`HalfDuplex`(RS-485)
+pub struct Slave<'a, S, TIM> {
+    serial: S,
+    timer: TIM,
+    address: u8,
+    silence: u32, //帧间隔，定时器计数
+    idle: u32,    //收到最后一个字节后的定时器计数
+    buf: [u8; MAX_ADU],
+    len: usize,
+    out: [u8; MAX_ADU],
+    tables: Tables<'a>,
+}
+
+impl<'a, S, TIM> Slave<'a, S, TIM>
+where
+    S: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    //address是从机地址1~247，baudrate要和串口一致
+    pub fn new(serial: S, mut timer: TIM, address: u8, baudrate: u32) -> Self {
+        timer.start((1_000_000 / TICK_US).hz());
+        Self {
+            serial,
+            timer,
+            address,
+            silence: silence_ticks(baudrate),
+            idle: 0,
+            buf: [0; MAX_ADU],
+            len: 0,
+            out: [0; MAX_ADU],
+            tables: Tables {
+                coils: Table::new(),
+                discrete_inputs: Table::new(),
+                holding_registers: Table::new(),
+                input_registers: Table::new(),
+            },
+        }
+    }
+
+    //线圈，功能码01、05、15。handler的value为None时读，Some时写，返回当前值
+    pub fn coils<F>(mut self, start: u16, count: u16, handler: F) -> Self
+    where
+        F: FnMut(u16, Option<bool>) -> core::result::Result<bool, Exception> + 'a,
+    {
+        self.tables.coils.maps.push(Map {
+            start,
+            count,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    //离散输入，功能码02，只读
+    pub fn discrete_inputs<F>(mut self, start: u16, count: u16, mut read: F) -> Self
+    where
+        F: FnMut(u16) -> core::result::Result<bool, Exception> + 'a,
+    {
+        self.tables.discrete_inputs.maps.push(Map {
+            start,
+            count,
+            handler: Box::new(move |address, _| read(address)),
+        });
+        self
+    }
+
+    //保持寄存器，功能码03、06、16。handler的value为None时读，Some时写，返回当前值
+    pub fn holding_registers<F>(mut self, start: u16, count: u16, handler: F) -> Self
+    where
+        F: FnMut(u16, Option<u16>) -> core::result::Result<u16, Exception> + 'a,
+    {
+        self.tables.holding_registers.maps.push(Map {
+            start,
+            count,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    //输入寄存器，功能码04，只读
+    pub fn input_registers<F>(mut self, start: u16, count: u16, mut read: F) -> Self
+    where
+        F: FnMut(u16) -> core::result::Result<u16, Exception> + 'a,
+    {
+        self.tables.input_registers.maps.push(Map {
+            start,
+            count,
+            handler: Box::new(move |address, _| read(address)),
+        });
+        self
+    }
+
+    pub fn release(self) -> (S, TIM) {
+        (self.serial, self.timer)
+    }
+
+    //读完串口里已经收到的字节，处理了一个请求时返回true，不阻塞
+    pub fn poll(&mut self) -> Result<bool> {
+        loop {
+            match self.serial.read() {
+                Ok(byte) => {
+                    if self.len < MAX_ADU {
+                        self.buf[self.len] = byte;
+                        self.len += 1;
+                    }
+                    self.idle = 0;
+                    if request_len(&self.buf[..self.len]) == Some(self.len) {
+                        return self.process();
+                    }
+                }
+                //出错的字节丢掉，CRC校验会失败
+                Err(nb::Error::Other(_)) => self.idle = 0,
+                Err(nb::Error::WouldBlock) => break,
+            }
+        }
+        //不认识的功能码算不出长度，等空闲3.5个字符后再处理，应答IllegalFunction
+        if self.len > 0 && self.timer.wait().is_ok() {
+            self.idle += 1;
+            if self.idle >= self.silence {
+                return self.process();
+            }
+        }
+        Ok(false)
+    }
+
+    fn process(&mut self) -> Result<bool> {
+        let len = core::mem::replace(&mut self.len, 0);
+        self.idle = 0;
+        let frame = &self.buf[..len];
+        let (slave, request) = match decode_request(frame) {
+            Ok((slave, request)) => (slave, Ok(request)),
+            Err(Error::Exception(exception)) => (frame[0], Err(exception)),
+            //CRC错误或者帧不完整，不应答
+            Err(_) => return Ok(false),
+        };
+        if slave != self.address && slave != BROADCAST {
+            return Ok(false);
+        }
+        let function = frame[1];
+        let result = match request {
+            //广播只执行写操作
+            Ok(request) if slave == BROADCAST && !request.is_write() => return Ok(false),
+            Ok(request) => self.tables.execute(&request, self.address, &mut self.out),
+            Err(exception) => Err(exception),
+        };
+        if slave == BROADCAST {
+            return Ok(true);
+        }
+        let len = match result {
+            Ok(len) => len,
+            Err(exception) => encode_exception(self.address, function, exception, &mut self.out)?,
+        };
+        for byte in &self.out[..len] {
+            nb::block!(self.serial.write(*byte)).map_err(|_| Error::Io)?;
+        }
+        nb::block!(self.serial.flush()).map_err(|_| Error::Io)?;
+        Ok(true)
+    }
+}
+
+//应用登记的四类地址
+struct Tables<'a> {
+    coils: Table<'a, bool>,
+    discrete_inputs: Table<'a, bool>,
+    holding_registers: Table<'a, u16>,
+    input_registers: Table<'a, u16>,
+}
+
+impl Tables<'_> {
+    //执行请求，把应答编码到out里
+    fn execute(
+        &mut self,
+        request: &Request,
+        slave: u8,
+        out: &mut [u8],
+    ) -> core::result::Result<usize, Exception> {
+        //最多2000个位或者125个寄存器
+        let mut bits = [0u8; 250];
+        let mut words = [0u16; 125];
+        let response = match *request {
+            Request::ReadCoils { address, count } => {
+                read_bits(&mut self.coils, address, count, &mut bits)?;
+                Response::ReadCoils(Bits::Packed {
+                    bytes: &bits,
+                    count,
+                })
+            }
+            Request::ReadDiscreteInputs { address, count } => {
+                read_bits(&mut self.discrete_inputs, address, count, &mut bits)?;
+                Response::ReadDiscreteInputs(Bits::Packed {
+                    bytes: &bits,
+                    count,
+                })
+            }
+            Request::ReadHoldingRegisters { address, count } => {
+                let words = read_words(&mut self.holding_registers, address, count, &mut words)?;
+                Response::ReadHoldingRegisters(Words::Values(words))
+            }
+            Request::ReadInputRegisters { address, count } => {
+                let words = read_words(&mut self.input_registers, address, count, &mut words)?;
+                Response::ReadInputRegisters(Words::Values(words))
+            }
+            Request::WriteSingleCoil { address, value } => {
+                self.coils.check(address, 1)?;
+                let value = self.coils.access(address, Some(value))?;
+                Response::WriteSingleCoil { address, value }
+            }
+            Request::WriteSingleRegister { address, value } => {
+                self.holding_registers.check(address, 1)?;
+                let value = self.holding_registers.access(address, Some(value))?;
+                Response::WriteSingleRegister { address, value }
+            }
+            Request::WriteMultipleCoils { address, values } => {
+                self.coils.check(address, values.len())?;
+                for (offset, value) in values.iter().enumerate() {
+                    self.coils.access(address + offset as u16, Some(value))?;
+                }
+                Response::WriteMultipleCoils {
+                    address,
+                    count: values.len() as u16,
+                }
+            }
+            Request::WriteMultipleRegisters { address, values } => {
+                self.holding_registers.check(address, values.len())?;
+                for (offset, value) in values.iter().enumerate() {
+                    self.holding_registers
+                        .access(address + offset as u16, Some(value))?;
+                }
+                Response::WriteMultipleRegisters {
+                    address,
+                    count: values.len() as u16,
+                }
+            }
+        };
+        encode_response(slave, &response, out).map_err(|_| Exception::ServerDeviceFailure)
+    }
+}
+
+fn read_bits(
+    table: &mut Table<bool>,
+    address: u16,
+    count: u16,
+    bytes: &mut [u8],
+) -> core::result::Result<(), Exception> {
+    table.check(address, count as usize)?;
+    for offset in 0..count {
+        if table.access(address + offset, None)? {
+            bytes[offset as usize / 8] |= 1 << (offset % 8);
+        }
+    }
+    Ok(())
+}
+
+fn read_words<'w>(
+    table: &mut Table<u16>,
+    address: u16,
+    count: u16,
+    words: &'w mut [u16],
+) -> core::result::Result<&'w [u16], Exception> {
+    table.check(address, count as usize)?;
+    for offset in 0..count {
+        words[offset as usize] = table.access(address + offset, None)?;
+    }
+    Ok(&words[..count as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame::{crc, decode_response, encode_request};
+    use super::*;
+    use crate::mock::{Port, Timer};
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    type TestSlave<'a> = Slave<'a, Port, Timer>;
+
+    //把请求喂给从机，poll到处理完为止，返回应答
+    fn exchange(slave: &mut TestSlave, frame: &[u8]) -> Vec<u8> {
+        slave.serial.feed(frame);
+        for _ in 0..=slave.silence {
+            if slave.poll().unwrap() {
+                break;
+            }
+        }
+        slave.serial.take_output()
+    }
+
+    fn request(slave: &mut TestSlave, address: u8, request: &Request) -> Vec<u8> {
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_request(address, request, &mut buf).unwrap();
+        exchange(slave, &buf[..len])
+    }
+
+    fn registers(
+        holding: &Rc<RefCell<[u16; 4]>>,
+        coils: &Rc<RefCell<[bool; 12]>>,
+    ) -> TestSlave<'static> {
+        let holding = holding.clone();
+        let coils = coils.clone();
+        Slave::new(Port::default(), Timer, 5, 9600)
+            .holding_registers(100, 4, move |address, value| {
+                let mut holding = holding.borrow_mut();
+                let index = (address - 100) as usize;
+                if let Some(value) = value {
+                    if value > 1000 {
+                        return Err(Exception::IllegalDataValue);
+                    }
+                    holding[index] = value;
+                }
+                Ok(holding[index])
+            })
+            .coils(0, 12, move |address, value| {
+                let mut coils = coils.borrow_mut();
+                if let Some(value) = value {
+                    coils[address as usize] = value;
+                }
+                Ok(coils[address as usize])
+            })
+            .input_registers(0, 2, |address| Ok(address + 7))
+            .discrete_inputs(8, 3, |address| Ok(address == 9))
+    }
+
+    #[test]
+    fn read_and_write() {
+        let holding = Rc::new(RefCell::new([0u16; 4]));
+        let coils = Rc::new(RefCell::new([false; 12]));
+        let mut slave = registers(&holding, &coils);
+
+        let write = Request::WriteMultipleRegisters {
+            address: 101,
+            values: Words::Values(&[5, 6]),
+        };
+        let reply = request(&mut slave, 5, &write);
+        assert_eq!(
+            decode_response(5, &write, &reply),
+            Ok(Response::WriteMultipleRegisters {
+                address: 101,
+                count: 2
+            })
+        );
+        assert_eq!(*holding.borrow(), [0, 5, 6, 0]);
+
+        let read = Request::ReadHoldingRegisters {
+            address: 100,
+            count: 4,
+        };
+        let reply = request(&mut slave, 5, &read);
+        assert_eq!(
+            decode_response(5, &read, &reply),
+            Ok(Response::ReadHoldingRegisters(Words::Values(&[0, 5, 6, 0])))
+        );
+
+        let write = Request::WriteSingleCoil {
+            address: 3,
+            value: true,
+        };
+        let reply = request(&mut slave, 5, &write);
+        assert_eq!(
+            decode_response(5, &write, &reply),
+            Ok(Response::WriteSingleCoil {
+                address: 3,
+                value: true
+            })
+        );
+        let read = Request::ReadCoils {
+            address: 0,
+            count: 12,
+        };
+        let reply = request(&mut slave, 5, &read);
+        let mut expected = [false; 12];
+        expected[3] = true;
+        assert_eq!(
+            decode_response(5, &read, &reply),
+            Ok(Response::ReadCoils(Bits::Values(&expected)))
+        );
+
+        let read = Request::ReadInputRegisters {
+            address: 0,
+            count: 2,
+        };
+        let reply = request(&mut slave, 5, &read);
+        assert_eq!(
+            decode_response(5, &read, &reply),
+            Ok(Response::ReadInputRegisters(Words::Values(&[7, 8])))
+        );
+        let read = Request::ReadDiscreteInputs {
+            address: 8,
+            count: 3,
+        };
+        let reply = request(&mut slave, 5, &read);
+        assert_eq!(
+            decode_response(5, &read, &reply),
+            Ok(Response::ReadDiscreteInputs(Bits::Values(&[
+                false, true, false
+            ])))
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        let holding = Rc::new(RefCell::new([0u16; 4]));
+        let coils = Rc::new(RefCell::new([false; 12]));
+        let mut slave = registers(&holding, &coils);
+
+        //一部分地址没有登记
+        let read = Request::ReadHoldingRegisters {
+            address: 102,
+            count: 3,
+        };
+        let reply = request(&mut slave, 5, &read);
+        assert_eq!(
+            decode_response(5, &read, &reply),
+            Err(Error::Exception(Exception::IllegalDataAddress))
+        );
+        //范围里有没登记的地址时一个也不写
+        let write = Request::WriteMultipleCoils {
+            address: 10,
+            values: Bits::Values(&[true, true, true]),
+        };
+        let reply = request(&mut slave, 5, &write);
+        assert_eq!(
+            decode_response(5, &write, &reply),
+            Err(Error::Exception(Exception::IllegalDataAddress))
+        );
+        assert_eq!(&coils.borrow()[10..], &[false, false]);
+        //回调拒绝的值
+        let write = Request::WriteSingleRegister {
+            address: 100,
+            value: 2000,
+        };
+        let reply = request(&mut slave, 5, &write);
+        assert_eq!(
+            decode_response(5, &write, &reply),
+            Err(Error::Exception(Exception::IllegalDataValue))
+        );
+        assert_eq!(*holding.borrow(), [0; 4]);
+    }
+
+    #[test]
+    fn ignored_requests() {
+        let holding = Rc::new(RefCell::new([0u16; 4]));
+        let coils = Rc::new(RefCell::new([false; 12]));
+        let mut slave = registers(&holding, &coils);
+        let read = Request::ReadInputRegisters {
+            address: 0,
+            count: 2,
+        };
+        //别的从机
+        assert!(request(&mut slave, 6, &read).is_empty());
+        //CRC错误
+        let mut buf = [0u8; MAX_ADU];
+        let len = encode_request(5, &read, &mut buf).unwrap();
+        buf[2] ^= 0x40;
+        assert!(exchange(&mut slave, &buf[..len]).is_empty());
+        //之后的请求照常应答
+        let reply = request(&mut slave, 5, &read);
+        assert!(decode_response(5, &read, &reply).is_ok());
+    }
+
+    #[test]
+    fn broadcast() {
+        let holding = Rc::new(RefCell::new([0u16; 4]));
+        let coils = Rc::new(RefCell::new([false; 12]));
+        let mut slave = registers(&holding, &coils);
+        //广播写执行但不应答
+        let write = Request::WriteMultipleCoils {
+            address: 2,
+            values: Bits::Values(&[true, false, true]),
+        };
+        assert!(request(&mut slave, BROADCAST, &write).is_empty());
+        assert_eq!(
+            &coils.borrow()[..6],
+            &[false, false, true, false, true, false]
+        );
+        let write = Request::WriteSingleRegister {
+            address: 103,
+            value: 9,
+        };
+        assert!(request(&mut slave, BROADCAST, &write).is_empty());
+        assert_eq!(holding.borrow()[3], 9);
+        //广播读忽略
+        let read = Request::ReadCoils {
+            address: 0,
+            count: 1,
+        };
+        assert!(request(&mut slave, BROADCAST, &read).is_empty());
+    }
+
+    #[test]
+    fn unknown_function_after_silence() {
+        let holding = Rc::new(RefCell::new([0u16; 4]));
+        let coils = Rc::new(RefCell::new([false; 12]));
+        let mut slave = registers(&holding, &coils);
+        let mut frame = [5u8, 0x2B, 0x0E, 0x01, 0, 0];
+        let check = crc(&frame[..4]).to_le_bytes();
+        frame[4..].copy_from_slice(&check);
+        slave.serial.feed(&frame);
+        //算不出长度，空闲3.5个字符之前不应答
+        for _ in 1..slave.silence {
+            assert!(!slave.poll().unwrap());
+        }
+        assert!(slave.serial.output.is_empty());
+        assert!(slave.poll().unwrap());
+        //IllegalFunction
+        let reply = slave.serial.take_output();
+        assert_eq!(reply[..3], [5, 0x2B | 0x80, 0x01]);
+        assert_eq!(reply[3..], crc(&reply[..3]).to_le_bytes());
+    }
+}
@@ -1,41 +1,122 @@
+pub mod at;
 pub mod esp826601s;
+pub mod http;
+pub mod mqtt;
+pub mod passthrough;
+pub mod provision;
+pub mod sntp;
+pub mod supervisor;
 pub mod tcp;
+pub mod udp;
 
-use crate::io::Result;
+pub use passthrough::Passthrough;
+pub use supervisor::Supervisor;
+pub use tcp::EspTcpStream;
+pub use udp::EspUdpSocket;
+
+use crate::io::{Error, Result};
+use core::fmt;
+use core::str::FromStr;
 use heapless::String;
 
+/// IPv4地址
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Ipv4Addr([u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Self = Self([0, 0, 0, 0]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Self([a, b, c, d])
+    }
+
+    pub fn octets(&self) -> [u8; 4] {
+        self.0
+    }
+}
+
+impl From<[u8; 4]> for Ipv4Addr {
+    fn from(octets: [u8; 4]) -> Self {
+        Self(octets)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut octets = [0u8; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or(Error::Parse("ipv4: too few octets"))?;
+            if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Error::Parse("ipv4: bad octet"));
+            }
+            *octet = part.parse().map_err(|_| Error::Parse("ipv4: bad octet"))?;
+        }
+        if parts.next().is_some() {
+            return Err(Error::Parse("ipv4: too many octets"));
+        }
+        Ok(Self(octets))
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Status {
     Cnnected,
     Disconnect,
 }
 
+#[derive(Debug, Clone)]
 pub struct IfInfo {
-    pub inet4: String<15>,
-    pub netmask: String<15>,
-    pub gateway: String<15>,
+    pub inet4: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
     pub ether: String<17>,
 }
 
-pub trait TcpStream: Sized {
-    fn connect(addr: (&str, u16)) -> Result<Self>;
+pub trait TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
     fn set_write_buffer(&mut self, size: usize) -> Result<()>;
     fn set_read_buffer(&mut self, size: usize) -> Result<()>;
+    //读写超时(毫秒)
+    fn set_timeout(&mut self, milliseconds: u32);
+    fn close(self) -> Result<()>;
+}
+
+pub trait UdpSocket {
+    fn send(&mut self, buf: &[u8]) -> Result<usize>;
+    fn send_to(&mut self, buf: &[u8], addr: (&str, u16)) -> Result<usize>;
+    fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, (Ipv4Addr, u16))>;
+    fn close(self) -> Result<()>;
 }
 
-pub trait Net {
-    fn connect() -> Self;
-    fn disconnect(&mut self) -> Result<()>;
+/// 网络设备
+///
+/// `'a`是`open_tcp`返回的连接借用设备的生命周期，
+/// 泛型代码可以写成`N: for<'a> Net<'a>`
+pub trait Net<'a> {
+    type TcpStream: TcpStream + 'a;
+    type UdpSocket: UdpSocket + 'a;
+
     fn hello(&mut self) -> Result<()>;
     fn state(&mut self) -> Result<Status>;
     fn reset(&mut self) -> Result<()>;
     fn recover(&mut self) -> Result<()>;
     fn ifinfo(&mut self) -> Result<IfInfo>;
     fn mac(&mut self) -> Result<String<17>>;
-    fn ip(&mut self) -> Result<String<15>>;
-    fn resolve(&mut self) -> Result<String<15>>;
+    fn ip(&mut self) -> Result<Ipv4Addr>;
+    fn resolve(&mut self, host: &str) -> Result<Ipv4Addr>;
     fn ping(&mut self, host: &str) -> Result<()>;
-    fn open_tcp<T: TcpStream>(&mut self, addr: (&str, u16)) -> Result<T>;
-    fn close_tcp<T: TcpStream>(&mut self, addr: (&str, u16)) -> Result<T>;
+    fn open_tcp(&'a mut self, addr: (&str, u16)) -> Result<Self::TcpStream>;
+    fn close_tcp(&mut self) -> Result<()>;
+    fn open_udp(&'a mut self, remote: (&str, u16), local_port: u16) -> Result<Self::UdpSocket>;
 }
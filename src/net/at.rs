@@ -0,0 +1,713 @@
+//! ESP8266 AT指令应答解析
+//!
+//! 输入是`Esp8266::request`收集到的整段应答文本(包含末尾的OK/ERROR行)，
+//! 只依赖core和heapless，不碰串口
+
+use super::sntp::DateTime;
+use super::{IfInfo, Ipv4Addr, Status};
+use crate::io::{Error, Result};
+use heapless::{String, Vec};
+
+type List<T> = alloc::vec::Vec<T>;
+
+/// AT+CIFSR 本机地址
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cifsr {
+    pub ap_ip: Option<Ipv4Addr>,
+    pub ap_mac: Option<String<17>>,
+    pub sta_ip: Option<Ipv4Addr>,
+    pub sta_mac: Option<String<17>>,
+}
+
+/// AT+CIPSTA_CUR? 站点IP配置
+#[derive(Debug, Clone, PartialEq)]
+pub struct CipSta {
+    pub ip: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl CipSta {
+    pub fn into_ifinfo(self, ether: String<17>) -> IfInfo {
+        IfInfo {
+            inet4: self.ip,
+            netmask: self.netmask,
+            gateway: self.gateway,
+            ether,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LinkType {
+    Tcp,
+    Udp,
+    Ssl,
+}
+
+/// +CIPSTATUS:<link ID>,<type>,<remote IP>,<remote port>,<local port>,<tetype>
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkStatus {
+    pub id: u8,
+    pub kind: LinkType,
+    pub remote_ip: Ipv4Addr,
+    pub remote_port: u16,
+    pub local_port: u16,
+    pub server: bool, //true表示本机是服务器端
+}
+
+/// AT+CIPSTATUS 连接状态
+#[derive(Debug, Clone, PartialEq)]
+pub struct CipStatus {
+    /// 2 获得IP, 3 已建立连接, 4 断开连接, 5 未连接AP
+    pub stat: u8,
+    pub links: Vec<LinkStatus, 5>,
+}
+
+impl CipStatus {
+    pub fn status(&self) -> Status {
+        match self.stat {
+            2 | 3 => Status::Cnnected,
+            _ => Status::Disconnect,
+        }
+    }
+}
+
+/// AT+GMR 版本信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version {
+    pub at: String<48>,
+    pub sdk: String<48>,
+    pub compile_time: Option<String<32>>,
+}
+
+/// +CWJAP:<error code> 连接AP失败的原因
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JoinError {
+    Timeout,
+    WrongPassword,
+    NotFound,
+    Failed,
+}
+
+/// 加密方式
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encryption {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+    WpaWpa2Psk,
+    Wpa2Enterprise,
+}
+
+impl Encryption {
+    pub fn code(&self) -> u8 {
+        match self {
+            Encryption::Open => 0,
+            Encryption::Wep => 1,
+            Encryption::WpaPsk => 2,
+            Encryption::Wpa2Psk => 3,
+            Encryption::WpaWpa2Psk => 4,
+            Encryption::Wpa2Enterprise => 5,
+        }
+    }
+
+    fn from_code(code: &str) -> Result<Self> {
+        match code {
+            "0" => Ok(Encryption::Open),
+            "1" => Ok(Encryption::Wep),
+            "2" => Ok(Encryption::WpaPsk),
+            "3" => Ok(Encryption::Wpa2Psk),
+            "4" => Ok(Encryption::WpaWpa2Psk),
+            "5" => Ok(Encryption::Wpa2Enterprise),
+            _ => Err(Error::Parse("unknown encryption")),
+        }
+    }
+}
+
+/// +CWLAP:(<ecn>,<ssid>,<rssi>,<mac>,<channel>,...) 扫描到的AP
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessPoint {
+    pub ssid: String<32>,
+    pub rssi: i8,
+    pub mac: String<17>,
+    pub channel: u8,
+    pub encryption: Encryption,
+}
+
+/// +CWJAP_CUR:<ssid>,<bssid>,<channel>,<rssi> 当前连接的AP
+#[derive(Debug, Clone, PartialEq)]
+pub struct Station {
+    pub ssid: String<32>,
+    pub bssid: String<17>,
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// 模块主动上报的消息(URC)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Event {
+    Connected(u8),    //[<link ID>,]CONNECT
+    Closed(u8),       //[<link ID>,]CLOSED 或 CONNECT FAIL
+    WifiConnected,    //WIFI CONNECTED
+    WifiGotIp,        //WIFI GOT IP
+    WifiDisconnected, //WIFI DISCONNECT
+    Overflow(u8),     //接收缓冲区已满，丢弃了数据
+}
+
+//识别一行是否URC，单连接模式下link ID为0
+pub fn urc(line: &str) -> Option<Event> {
+    match line.trim() {
+        "WIFI CONNECTED" => return Some(Event::WifiConnected),
+        "WIFI GOT IP" => return Some(Event::WifiGotIp),
+        "WIFI DISCONNECT" => return Some(Event::WifiDisconnected),
+        _ => {}
+    }
+    let (id, msg) = match line.trim().split_once(',') {
+        Some((id, msg)) => (id.parse().ok()?, msg),
+        None => (0, line.trim()),
+    };
+    match msg {
+        "CONNECT" => Some(Event::Connected(id)),
+        "CLOSED" | "CONNECT FAIL" => Some(Event::Closed(id)),
+        _ => None,
+    }
+}
+
+//固件一个+IPD帧最多带的字节数，帧头被干扰时长度可能很大
+pub const MAX_IPD: usize = 2048;
+
+/// +IPD帧头
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ipd {
+    pub link: u8, //单连接模式下为0
+    pub len: usize,
+    pub remote: Option<(Ipv4Addr, u16)>, //AT+CIPDINFO=1时带对端地址
+}
+
+//解析"+IPD,[<link ID>,]<len>[,<remote IP>,<remote port>]"(不含冒号)
+pub fn ipd(header: &str) -> Result<Ipd> {
+    let rest = header
+        .strip_prefix("+IPD,")
+        .ok_or(Error::Parse("+IPD: bad header"))?;
+    let mut fields: Vec<&str, 4> = Vec::new();
+    for field in rest.split(',') {
+        fields
+            .push(field.trim_matches('"'))
+            .map_err(|_| Error::Parse("+IPD: bad header"))?;
+    }
+    let (link, len, remote) = match fields.as_slice() {
+        [len] => ("0", len, None),
+        [link, len] => (*link, len, None),
+        [len, ip, port] => ("0", len, Some((ip, port))),
+        [link, len, ip, port] => (*link, len, Some((ip, port))),
+        _ => return Err(Error::Parse("+IPD: bad header")),
+    };
+    let remote = match remote {
+        Some((ip, port)) => Some((
+            ip.parse()?,
+            port.parse()
+                .map_err(|_| Error::Parse("+IPD: bad remote port"))?,
+        )),
+        None => None,
+    };
+    let len = len
+        .parse()
+        .ok()
+        .filter(|len| *len <= MAX_IPD)
+        .ok_or(Error::Parse("+IPD: bad length"))?;
+    Ok(Ipd {
+        link: link
+            .parse()
+            .map_err(|_| Error::Parse("+IPD: bad link id"))?,
+        len,
+        remote,
+    })
+}
+
+pub fn cifsr(reply: &str) -> Result<Cifsr> {
+    let mut info = Cifsr::default();
+    let mut found = false;
+    for value in values(reply, "+CIFSR:") {
+        let (key, value) = value
+            .split_once(',')
+            .ok_or(Error::Parse("+CIFSR: missing ','"))?;
+        let value = unquote(value)?;
+        match key {
+            "APIP" => info.ap_ip = Some(value.parse()?),
+            "APMAC" => info.ap_mac = Some(string(value)?),
+            "STAIP" => info.sta_ip = Some(value.parse()?),
+            "STAMAC" => info.sta_mac = Some(string(value)?),
+            _ => return Err(Error::Parse("+CIFSR: unknown key")),
+        }
+        found = true;
+    }
+    if !found {
+        return Err(Error::Parse("+CIFSR: not found"));
+    }
+    Ok(info)
+}
+
+//兼容+CIPSTA_CUR:/+CIPSTA_DEF:/+CIPSTA:
+pub fn cipsta(reply: &str) -> Result<CipSta> {
+    let mut ip = None;
+    let mut gateway = None;
+    let mut netmask = None;
+    for line in reply.lines().map(str::trim) {
+        if !line.starts_with("+CIPSTA") {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .and_then(|(_, rest)| rest.split_once(':'))
+            .ok_or(Error::Parse("+CIPSTA: missing ':'"))?;
+        let value = unquote(value)?.parse()?;
+        match key {
+            "ip" => ip = Some(value),
+            "gateway" => gateway = Some(value),
+            "netmask" => netmask = Some(value),
+            _ => return Err(Error::Parse("+CIPSTA: unknown key")),
+        }
+    }
+    Ok(CipSta {
+        ip: ip.ok_or(Error::Parse("+CIPSTA: missing ip"))?,
+        gateway: gateway.ok_or(Error::Parse("+CIPSTA: missing gateway"))?,
+        netmask: netmask.ok_or(Error::Parse("+CIPSTA: missing netmask"))?,
+    })
+}
+
+pub fn cipstatus(reply: &str) -> Result<CipStatus> {
+    let stat = value(reply, "STATUS:")?
+        .parse()
+        .map_err(|_| Error::Parse("STATUS: not a number"))?;
+    let mut links = Vec::new();
+    for value in values(reply, "+CIPSTATUS:") {
+        links
+            .push(link_status(value)?)
+            .map_err(|_| Error::Parse("+CIPSTATUS: too many links"))?;
+    }
+    Ok(CipStatus { stat, links })
+}
+
+fn link_status(value: &str) -> Result<LinkStatus> {
+    let mut fields = value.split(',');
+    let mut next = |what| fields.next().ok_or(Error::Parse(what));
+    let id = next("+CIPSTATUS: missing link id")?
+        .parse()
+        .map_err(|_| Error::Parse("+CIPSTATUS: bad link id"))?;
+    let kind = match unquote(next("+CIPSTATUS: missing type")?)? {
+        "TCP" => LinkType::Tcp,
+        "UDP" => LinkType::Udp,
+        "SSL" => LinkType::Ssl,
+        _ => return Err(Error::Parse("+CIPSTATUS: unknown type")),
+    };
+    let remote_ip = unquote(next("+CIPSTATUS: missing remote ip")?)?.parse()?;
+    let remote_port = next("+CIPSTATUS: missing remote port")?
+        .parse()
+        .map_err(|_| Error::Parse("+CIPSTATUS: bad remote port"))?;
+    let local_port = next("+CIPSTATUS: missing local port")?
+        .parse()
+        .map_err(|_| Error::Parse("+CIPSTATUS: bad local port"))?;
+    let server = match next("+CIPSTATUS: missing tetype")? {
+        "0" => false,
+        "1" => true,
+        _ => return Err(Error::Parse("+CIPSTATUS: bad tetype")),
+    };
+    Ok(LinkStatus {
+        id,
+        kind,
+        remote_ip,
+        remote_port,
+        local_port,
+        server,
+    })
+}
+
+pub fn cipdomain(reply: &str) -> Result<Ipv4Addr> {
+    value(reply, "+CIPDOMAIN:")?.trim_matches('"').parse()
+}
+
+//AT+CIPDNS_CUR? 每行一个DNS服务器
+pub fn cipdns(reply: &str) -> Result<List<Ipv4Addr>> {
+    values(reply, "+CIPDNS_CUR:")
+        .map(|ip| ip.trim_matches('"').parse())
+        .collect()
+}
+
+//NonOS固件应答"+<time>"，返回往返时间(毫秒)；超时时是"+timeout"
+pub fn ping(reply: &str) -> Result<u32> {
+    if reply.lines().any(|line| line.trim() == "+timeout") {
+        return Err(Error::Timeout);
+    }
+    value(reply, "+")?
+        .parse()
+        .map_err(|_| Error::Parse("+PING: not a number"))
+}
+
+//+CIPSNTPTIME:Thu Aug 04 14:48:05 2016，是按AT+CIPSNTPCFG时区换算后的本地时间
+pub fn cipsntptime(reply: &str) -> Result<DateTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    const BAD: Error = Error::Parse("+CIPSNTPTIME: bad time");
+    let mut fields = value(reply, "+CIPSNTPTIME:")?.split_whitespace().skip(1);
+    let month = fields.next().ok_or(BAD)?;
+    let month = MONTHS.iter().position(|m| *m == month).ok_or(BAD)? as u8 + 1;
+    let day = fields.next().and_then(|d| d.parse().ok()).ok_or(BAD)?;
+    let mut clock = fields.next().ok_or(BAD)?.split(':');
+    let mut next = || clock.next().and_then(|v| v.parse().ok()).ok_or(BAD);
+    let (hour, minute, second) = (next()?, next()?, next()?);
+    let year = fields.next().and_then(|y| y.parse().ok()).ok_or(BAD)?;
+    Ok(DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    })
+}
+
+pub fn gmr(reply: &str) -> Result<Version> {
+    let compile_time = match value(reply, "compile time:") {
+        Ok(time) => Some(string(time)?),
+        Err(_) => None,
+    };
+    Ok(Version {
+        at: string(value(reply, "AT version:")?)?,
+        sdk: string(value(reply, "SDK version:")?)?,
+        compile_time,
+    })
+}
+
+pub fn cwjap_error(reply: &str) -> Result<JoinError> {
+    match value(reply, "+CWJAP:")? {
+        "1" => Ok(JoinError::Timeout),
+        "2" => Ok(JoinError::WrongPassword),
+        "3" => Ok(JoinError::NotFound),
+        "4" => Ok(JoinError::Failed),
+        _ => Err(Error::Parse("+CWJAP: unknown error code")),
+    }
+}
+
+pub fn cwlap(reply: &str) -> Result<List<AccessPoint>> {
+    let mut aps = List::new();
+    for value in values(reply, "+CWLAP:") {
+        let value = value
+            .strip_prefix('(')
+            .and_then(|v| v.strip_suffix(')'))
+            .ok_or(Error::Parse("+CWLAP: missing parentheses"))?;
+        let (ecn, rest) = value
+            .split_once(',')
+            .ok_or(Error::Parse("+CWLAP: missing ssid"))?;
+        //SSID里可能有逗号，按引号取
+        let (ssid, rest) = quoted_field(rest).ok_or(Error::Parse("+CWLAP: bad ssid"))?;
+        let mut fields = rest.split(',');
+        let mut next = |what| fields.next().ok_or(Error::Parse(what));
+        let rssi = next("+CWLAP: missing rssi")?
+            .parse()
+            .map_err(|_| Error::Parse("+CWLAP: bad rssi"))?;
+        let mac = string(unquote(next("+CWLAP: missing mac")?)?)?;
+        let channel = next("+CWLAP: missing channel")?
+            .parse()
+            .map_err(|_| Error::Parse("+CWLAP: bad channel"))?;
+        aps.push(AccessPoint {
+            ssid: string(ssid)?,
+            rssi,
+            mac,
+            channel,
+            encryption: Encryption::from_code(ecn)?,
+        });
+    }
+    Ok(aps)
+}
+
+//未连接AP时返回None
+pub fn cwjap_cur(reply: &str) -> Result<Option<Station>> {
+    if reply.lines().any(|line| line.trim() == "No AP") {
+        return Ok(None);
+    }
+    let value = value(reply, "+CWJAP_CUR:")?;
+    let (ssid, rest) = quoted_field(value).ok_or(Error::Parse("+CWJAP_CUR: bad ssid"))?;
+    let mut fields = rest.split(',');
+    let mut next = |what| fields.next().ok_or(Error::Parse(what));
+    let bssid = string(unquote(next("+CWJAP_CUR: missing bssid")?)?)?;
+    let channel = next("+CWJAP_CUR: missing channel")?
+        .parse()
+        .map_err(|_| Error::Parse("+CWJAP_CUR: bad channel"))?;
+    let rssi = next("+CWJAP_CUR: missing rssi")?
+        .parse()
+        .map_err(|_| Error::Parse("+CWJAP_CUR: bad rssi"))?;
+    Ok(Some(Station {
+        ssid: string(ssid)?,
+        bssid,
+        channel,
+        rssi,
+    }))
+}
+
+//取出开头用引号括起来的字段，返回(字段内容, 后面逗号之后的部分)
+fn quoted_field(value: &str) -> Option<(&str, &str)> {
+    let value = value.strip_prefix('"')?;
+    let end = value.find("\",")?;
+    Some((&value[..end], &value[end + 2..]))
+}
+
+//所有以prefix开头的行，去掉prefix
+fn values<'r>(reply: &'r str, prefix: &'r str) -> impl Iterator<Item = &'r str> + 'r {
+    reply
+        .lines()
+        .filter_map(move |line| line.trim().strip_prefix(prefix))
+}
+
+//第一行以prefix开头的行，去掉prefix
+fn value<'r>(reply: &'r str, prefix: &'static str) -> Result<&'r str> {
+    values(reply, prefix).next().ok_or(Error::Parse(prefix))
+}
+
+//去掉首尾双引号
+fn unquote(value: &str) -> Result<&str> {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .ok_or(Error::Parse("expected quoted string"))
+}
+
+fn string<const N: usize>(value: &str) -> Result<String<N>> {
+    let mut s = String::new();
+    s.push_str(value)
+        .map_err(|_| Error::Parse("value too long"))?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //解析失败时返回Error::Parse，说明是哪个字段
+    fn malformed<T: core::fmt::Debug>(result: Result<T>) -> &'static str {
+        match result {
+            Err(Error::Parse(detail)) => detail,
+            other => panic!("expected Error::Parse, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cifsr_reply() {
+        let reply = concat!(
+            "AT+CIFSR\r\n",
+            "+CIFSR:APIP,\"192.168.4.1\"\r\n",
+            "+CIFSR:APMAC,\"5e:cf:7f:aa:bb:cc\"\r\n",
+            "+CIFSR:STAIP,\"192.168.1.5\"\r\n",
+            "+CIFSR:STAMAC,\"5c:cf:7f:aa:bb:cc\"\r\n",
+            "\r\nOK\r\n"
+        );
+        let info = cifsr(reply).unwrap();
+        assert_eq!(info.ap_ip, Some(Ipv4Addr::new(192, 168, 4, 1)));
+        assert_eq!(info.sta_ip, Some(Ipv4Addr::new(192, 168, 1, 5)));
+        assert_eq!(info.sta_mac.unwrap().as_str(), "5c:cf:7f:aa:bb:cc");
+
+        malformed(cifsr("OK\r\n"));
+        malformed(cifsr("+CIFSR:STAIP\r\nOK\r\n"));
+        malformed(cifsr("+CIFSR:ETHIP,\"1.2.3.4\"\r\nOK\r\n"));
+        malformed(cifsr("+CIFSR:STAIP,\"192.168.100.100.1\"\r\nOK\r\n"));
+    }
+
+    #[test]
+    fn cipsta_reply() {
+        let reply = concat!(
+            "+CIPSTA_CUR:ip:\"192.168.1.5\"\r\n",
+            "+CIPSTA_CUR:gateway:\"192.168.1.1\"\r\n",
+            "+CIPSTA_CUR:netmask:\"255.255.255.0\"\r\n",
+            "OK\r\n"
+        );
+        let sta = cipsta(reply).unwrap();
+        assert_eq!(sta.ip, Ipv4Addr::new(192, 168, 1, 5));
+        assert_eq!(sta.gateway, Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(sta.netmask, Ipv4Addr::new(255, 255, 255, 0));
+
+        assert_eq!(
+            malformed(cipsta("+CIPSTA_CUR:ip:\"192.168.1.5\"\r\nOK\r\n")),
+            "+CIPSTA: missing gateway"
+        );
+        malformed(cipsta("+CIPSTA_CUR:dns:\"8.8.8.8\"\r\nOK\r\n"));
+    }
+
+    #[test]
+    fn cipstatus_reply() {
+        let reply = concat!(
+            "STATUS:3\r\n",
+            "+CIPSTATUS:0,\"TCP\",\"192.168.1.2\",8080,12345,0\r\n",
+            "+CIPSTATUS:1,\"UDP\",\"192.168.1.3\",123,1234,1\r\n",
+            "OK\r\n"
+        );
+        let status = cipstatus(reply).unwrap();
+        assert_eq!(status.stat, 3);
+        assert_eq!(status.status(), Status::Cnnected);
+        assert_eq!(status.links.len(), 2);
+        let link = &status.links[0];
+        assert_eq!((link.id, link.kind), (0, LinkType::Tcp));
+        assert_eq!(link.remote_ip, Ipv4Addr::new(192, 168, 1, 2));
+        assert_eq!(
+            (link.remote_port, link.local_port, link.server),
+            (8080, 12345, false)
+        );
+        assert_eq!(status.links[1].kind, LinkType::Udp);
+        assert!(status.links[1].server);
+
+        let idle = cipstatus("STATUS:5\r\nOK\r\n").unwrap();
+        assert_eq!(idle.status(), Status::Disconnect);
+        assert!(idle.links.is_empty());
+
+        malformed(cipstatus("STATUS:x\r\nOK\r\n"));
+        malformed(cipstatus(
+            "STATUS:3\r\n+CIPSTATUS:0,\"ICMP\",\"1.2.3.4\",1,2,0\r\nOK\r\n",
+        ));
+        malformed(cipstatus(
+            "STATUS:3\r\n+CIPSTATUS:0,\"TCP\",\"1.2.3.4\",70000,2,0\r\nOK\r\n",
+        ));
+        malformed(cipstatus(
+            "STATUS:3\r\n+CIPSTATUS:0,\"TCP\",\"1.2.3.4\"\r\nOK\r\n",
+        ));
+    }
+
+    #[test]
+    fn cwlap_reply() {
+        let reply = concat!(
+            "+CWLAP:(3,\"Wosai, Guest\",-56,\"5c:cf:7f:aa:bb:cc\",6,-13,0)\r\n",
+            "+CWLAP:(0,\"open\",-80,\"5c:cf:7f:aa:bb:cd\",11,3,0)\r\n",
+            "\r\nOK\r\n"
+        );
+        let aps = cwlap(reply).unwrap();
+        assert_eq!(aps.len(), 2);
+        //SSID里的逗号不影响后面的字段
+        assert_eq!(aps[0].ssid.as_str(), "Wosai, Guest");
+        assert_eq!((aps[0].rssi, aps[0].channel), (-56, 6));
+        assert_eq!(aps[0].encryption, Encryption::Wpa2Psk);
+        assert_eq!(aps[1].mac.as_str(), "5c:cf:7f:aa:bb:cd");
+        assert_eq!(aps[1].encryption, Encryption::Open);
+        assert!(cwlap("OK\r\n").unwrap().is_empty());
+
+        malformed(cwlap(
+            "+CWLAP:3,\"a\",-56,\"5c:cf:7f:aa:bb:cc\",6\r\nOK\r\n",
+        ));
+        malformed(cwlap(
+            "+CWLAP:(9,\"a\",-56,\"5c:cf:7f:aa:bb:cc\",6)\r\nOK\r\n",
+        ));
+        malformed(cwlap(
+            "+CWLAP:(3,\"a\",loud,\"5c:cf:7f:aa:bb:cc\",6)\r\nOK\r\n",
+        ));
+        malformed(cwlap("+CWLAP:(3,\"a\",-56)\r\nOK\r\n"));
+    }
+
+    #[test]
+    fn cwjap_replies() {
+        let station = cwjap_cur("+CWJAP_CUR:\"home\",\"5c:cf:7f:aa:bb:cc\",6,-40\r\nOK\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(station.ssid.as_str(), "home");
+        assert_eq!(station.bssid.as_str(), "5c:cf:7f:aa:bb:cc");
+        assert_eq!((station.channel, station.rssi), (6, -40));
+        assert!(cwjap_cur("No AP\r\nOK\r\n").unwrap().is_none());
+        malformed(cwjap_cur("+CWJAP_CUR:home,6\r\nOK\r\n"));
+
+        assert_eq!(
+            cwjap_error("+CWJAP:2\r\n\r\nFAIL\r\n").unwrap(),
+            JoinError::WrongPassword
+        );
+        assert_eq!(
+            cwjap_error("+CWJAP:3\r\nFAIL\r\n").unwrap(),
+            JoinError::NotFound
+        );
+        malformed(cwjap_error("+CWJAP:7\r\nFAIL\r\n"));
+    }
+
+    #[test]
+    fn ipd_header() {
+        let single = ipd("+IPD,5").unwrap();
+        assert_eq!((single.link, single.len, single.remote), (0, 5, None));
+        let mux = ipd("+IPD,2,128").unwrap();
+        assert_eq!((mux.link, mux.len), (2, 128));
+        let udp = ipd("+IPD,1,4,\"192.168.1.2\",123").unwrap();
+        assert_eq!(
+            (udp.link, udp.len, udp.remote),
+            (1, 4, Some((Ipv4Addr::new(192, 168, 1, 2), 123)))
+        );
+
+        malformed(ipd("IPD,5"));
+        malformed(ipd("+IPD,x"));
+        malformed(ipd("+IPD,9999,1"));
+        assert_eq!(ipd("+IPD,0,2048").unwrap().len, MAX_IPD);
+        malformed(ipd("+IPD,0,2049"));
+        malformed(ipd("+IPD,0,99999"));
+        malformed(ipd("+IPD,1,4,1.2.3.4,http"));
+        malformed(ipd("+IPD,1,2,3,4,5"));
+        malformed(ipd("+IPD,1,4,\"192.168.1\",123"));
+    }
+
+    #[test]
+    fn urc_lines() {
+        assert_eq!(urc("CONNECT\r\n"), Some(Event::Connected(0)));
+        assert_eq!(urc("3,CLOSED"), Some(Event::Closed(3)));
+        assert_eq!(urc("1,CONNECT FAIL"), Some(Event::Closed(1)));
+        assert_eq!(urc("WIFI GOT IP"), Some(Event::WifiGotIp));
+        assert_eq!(urc("WIFI DISCONNECT"), Some(Event::WifiDisconnected));
+        assert_eq!(urc("OK"), None);
+        assert_eq!(urc("x,CONNECT"), None);
+    }
+
+    #[test]
+    fn misc_replies() {
+        assert_eq!(ping("+12\r\n\r\nOK\r\n").unwrap(), 12);
+        assert!(matches!(
+            ping("+timeout\r\n\r\nERROR\r\n"),
+            Err(Error::Timeout)
+        ));
+        malformed(ping("+fast\r\n\r\nOK\r\n"));
+        malformed(ping("\r\nOK\r\n"));
+
+        assert_eq!(
+            cipdomain("+CIPDOMAIN:14.215.177.38\r\nOK").unwrap(),
+            Ipv4Addr::new(14, 215, 177, 38)
+        );
+        malformed(cipdomain("+CIPDOMAIN:14.215.177\r\nOK"));
+        let servers = cipdns("+CIPDNS_CUR:8.8.8.8\r\n+CIPDNS_CUR:1.1.1.1\r\nOK\r\n").unwrap();
+        assert_eq!(
+            servers,
+            [Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(1, 1, 1, 1)]
+        );
+
+        let version = gmr(concat!(
+            "AT version:1.2.0.0(Jul  1 2016 20:04:45)\r\n",
+            "SDK version:1.5.4.1(39cb9a32)\r\n",
+            "compile time:Dec 25 2016 14:21:42\r\n",
+            "OK\r\n"
+        ))
+        .unwrap();
+        assert_eq!(version.at.as_str(), "1.2.0.0(Jul  1 2016 20:04:45)");
+        assert_eq!(version.sdk.as_str(), "1.5.4.1(39cb9a32)");
+        assert!(version.compile_time.is_some());
+        malformed(gmr("SDK version:1.5.4.1\r\nOK\r\n"));
+    }
+
+    #[test]
+    fn sntp_time_reply() {
+        let time = cipsntptime("+CIPSNTPTIME:Thu Aug 04 14:48:05 2016\r\nOK\r\n").unwrap();
+        assert_eq!(
+            time,
+            DateTime {
+                year: 2016,
+                month: 8,
+                day: 4,
+                hour: 14,
+                minute: 48,
+                second: 5,
+            }
+        );
+        malformed(cipsntptime(
+            "+CIPSNTPTIME:Thu Foo 04 14:48:05 2016\r\nOK\r\n",
+        ));
+        malformed(cipsntptime("+CIPSNTPTIME:Thu Aug 04 14:48 2016\r\nOK\r\n"));
+    }
+}
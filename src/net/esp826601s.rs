@@ -8,30 +8,152 @@
 //! AT+CIPSTATUS 查询连接状态
 //! AT+CIFSR 查询设备IP地址
 //! AT+CIPDOMAIN="www.baidu.com" 域名解析
+//! AT+CIPDNS_CUR=1,"208.67.222.222","8.8.8.8" 自定义DNS服务器
+//! AT+CIPSNTPCFG=1,8,"cn.ntp.org.cn","ntp.sjtu.edu.cn" 开启SNTP，东八区
+//! AT+CIPSNTPTIME? 查询SNTP时间
 //! AT+CIPSTART="TCP","iot.espressif.cn",8000 建立TCP连接
 //! AT+CIPSTART="TCP","192.168.101.110",1000 建立TCP连接
-//! AT+CIPSSLSIZE=4096 设置TCP缓冲区
+//! AT+CIPSSLSIZE=4096 设置SSL缓冲区
+//! AT+CIPSSLCCONF=2 SSL证书校验方式
+//! AT+CIPSTART="SSL","iot.espressif.cn",8443 建立SSL连接
 //! AT+CIPSENDBUF=16 发送16字节数据到TCP缓冲区，满16自己后发送
 //! AT+CIPBUFSTATUS 查询 TCP 发包缓存的状态
 //! AT+CIPCLOSE=<link ID> 关闭TCP连接
+//! AT+CIPMUX=1 多连接模式，最多5个连接(link ID 0~4)
+
+//! 透传
+//! AT+CIPMODE=1 透传模式，AT+CIPSEND后进入透传，单独发送"+++"退出
+
+//! UDP
+//! AT+CIPDINFO=1 +IPD带上对端地址
+//! AT+CIPSTART=0,"UDP","192.168.101.110",1000,1002,0 建立UDP连接
+//! AT+CIPSEND=0,10,"192.168.101.110",1000 发送数据报到指定地址
 
 //! TCP服务器
 //! AT+CIPSERVER=1,3333 监听3333端口
 //! AT+CIPSERVER=0,3333 关闭监听3333端口
+//! AT+CIPSTO=180 服务器超时时间(秒)
+//! WIFI管理
+//! AT+CWLAP 扫描AP
+//! AT+CWJAP_CUR? 查询当前连接的AP
+//! AT+CWMODE_CUR=3 站点+热点模式
+//! AT+CWSAP_CUR="bluepill","12345678",5,3 配置热点
+//! AT+CIPSTA_DEF="192.168.1.100","192.168.1.1","255.255.255.0" 静态IP
+//! AT+CWDHCP_DEF=1,1 开启站点DHCP
+
 //! AT测试
 
-use crate::hal::time::Hertz;
-use crate::io::{Error, Result, TimeoutReader};
+use crate::hal::time::{Hertz, U32Ext};
+use crate::io::{Error, Result};
+use crate::net::at::{self, AccessPoint, CipStatus, Encryption, Ipd, Station, Version};
+use crate::net::sntp::{self, Timestamp};
+use crate::net::{
+    EspTcpStream, EspUdpSocket, IfInfo, Ipv4Addr, Net, Passthrough, Status, UdpSocket,
+};
+use alloc::collections::VecDeque;
 use alloc::format;
 use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_hal::digital::v2::OutputPin;
+
+pub use crate::net::at::Event;
 
 const OK: &str = "OK";
 const ERROR: &str = "ERROR";
+const FAIL: &str = "FAIL";
 const BUSY: &str = "busy";
 
+//最多5个连接(link ID 0~4)
+const LINKS: usize = 5;
+//未处理事件最多保留的个数
+const EVENTS: usize = 16;
+//未读取的UDP数据报最多保留的个数
+const DATAGRAMS: usize = 8;
+//域名解析缓存的条数
+const RESOLVED: usize = 8;
+
+/// 指令重试策略，用于模块忙(busy p.../busy s...)以及
+/// "link is not valid"、"ALREADY CONNECTED"这类暂时性的ERROR
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u8,  //最多尝试次数，含第一次
+    pub delay: u32,    //两次尝试之间等待的毫秒数
+    pub deadline: u32, //从第一次发送算起的总时限(毫秒)
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            delay: 200,
+            deadline: 20000,
+        }
+    }
+}
+
+/// AT+CWMODE_CUR 工作模式
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WifiMode {
+    Station,
+    SoftAp,
+    Both,
+}
+
+/// AT+CIPSSLCCONF 证书校验方式，证书需事先烧录到模块
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SslAuth {
+    None,       //不校验
+    ClientCert, //加载客户端证书和私钥
+    ServerCa,   //用CA证书校验服务器
+    Both,       //双向校验
+}
+
+/// 多连接模式下的连接句柄，不可复制，关闭时被消耗；
+/// 远端关闭后link ID被新连接复用时，旧句柄也会失效
+#[derive(Debug, PartialEq)]
+pub struct Link {
+    id: u8,
+    generation: u32,
+}
+
+impl Link {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+struct Resolved {
+    host: String,
+    addr: Ipv4Addr,
+    expires: u32,
+}
+
+struct Datagram {
+    link: u8,
+    remote: Option<(Ipv4Addr, u16)>,
+    data: Vec<u8>,
+}
+
 pub struct Esp8266<T, TIM> {
     port: T,
     timer: TIM,
+    mux: bool,
+    rx: [VecDeque<u8>; LINKS], //每个连接的接收缓冲区
+    rx_capacity: usize,
+    open: [bool; LINKS],
+    generation: [u32; LINKS],
+    events: VecDeque<Event>,
+    listening: Option<u16>,
+    incoming: VecDeque<u8>, //等待accept的客户端连接
+    udp: [bool; LINKS],
+    dinfo: bool, //是否已开启AT+CIPDINFO=1
+    datagrams: VecDeque<Datagram>,
+    passthrough: bool, //透传模式下不能发送AT指令
+    retry: RetryPolicy,
+    now: u32, //驱动等待串口或延时时累计的毫秒数
+    resolved: VecDeque<Resolved>,
+    dns_ttl: u32,
+    timezone: i8, //AT+CIPSNTPCFG设置的时区，AT+CIPSNTPTIME?返回的是本地时间
 }
 
 impl<T, TIM> Esp8266<T, TIM>
@@ -40,20 +162,63 @@ where
     TIM: embedded_hal::timer::CountDown<Time = Hertz>,
 {
     pub fn new(port: T, timer: TIM) -> Self {
-        Self { port, timer }
+        Self {
+            port,
+            timer,
+            mux: false,
+            rx: Default::default(),
+            rx_capacity: 512,
+            open: [false; LINKS],
+            generation: [0; LINKS],
+            events: VecDeque::new(),
+            listening: None,
+            incoming: VecDeque::new(),
+            udp: [false; LINKS],
+            dinfo: false,
+            datagrams: VecDeque::new(),
+            passthrough: false,
+            retry: RetryPolicy::default(),
+            now: 0,
+            resolved: VecDeque::new(),
+            dns_ttl: 60000,
+            timezone: 0,
+        }
     }
-    //打个招呼
-    pub fn hello(&mut self) -> Result<String> {
-        self.request(b"AT\r\n", 5000)
+
+    //每个连接接收缓冲区的大小，超出的数据被丢弃并上报Event::Overflow
+    pub fn set_rx_capacity(&mut self, capacity: usize) {
+        self.rx_capacity = capacity;
     }
-    //设备信息
-    pub fn device_info(&mut self) -> Result<String> {
-        self.request(b"AT+GMR\r\n", 5000)
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
     }
 
-    //重置
-    pub fn reset(&mut self) -> Result<String> {
-        self.request(b"AT+RST\r\n", 5000)
+    //驱动累计的毫秒数，只在等待串口或延时时增加
+    pub fn uptime(&self) -> u32 {
+        self.now
+    }
+
+    //取出一个模块上报的事件
+    pub fn event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    //不发送指令，处理模块主动发来的数据和事件，直到timeout毫秒内没有新的输入
+    pub fn poll(&mut self, timeout: u32) -> Result<()> {
+        loop {
+            match self.read_input(timeout) {
+                Ok(_) => {}
+                Err(Error::Timeout) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    //设备信息
+    pub fn device_info(&mut self) -> Result<Version> {
+        let reply = self.request(b"AT+GMR\r\n", 5000)?;
+        at::gmr(&reply)
     }
 
     //恢复出厂设置
@@ -61,21 +226,25 @@ where
         self.request(b"AT+RESTORE\r\n", 5000)
     }
 
-    //连接AP
-    pub fn dial(&mut self, ssid: &str, password: &str, autoconnect: bool) -> Result<String> {
-        let mut cmd = String::from("AT+CWJAP_DEF=\"");
-        cmd.push_str(ssid);
-        cmd.push_str("\",\"");
-        cmd.push_str(password);
-        cmd.push_str("\"\r\n");
-        let reply = self.request(cmd.as_bytes(), 15000)?;
+    //连接AP，失败时返回Error::Join说明原因
+    pub fn dial(&mut self, ssid: &str, password: &str, autoconnect: bool) -> Result<()> {
+        let cmd = format!("AT+CWJAP_DEF={},{}\r\n", quote(ssid)?, quote(password)?);
+        match self.request(cmd.as_bytes(), 15000) {
+            Ok(_) => {}
+            Err(Error::Other(reply)) => {
+                return Err(match at::cwjap_error(&reply) {
+                    Ok(reason) => Error::Join(reason),
+                    Err(_) => Error::Other(reply),
+                })
+            }
+            Err(err) => return Err(err),
+        }
         if autoconnect {
             self.request(b"AT+CWAUTOCONN=1\r\n", 5000)?;
         } else {
             self.request(b"AT+CWAUTOCONN=0\r\n", 5000)?;
         }
-
-        Ok(reply)
+        Ok(())
     }
 
     //断开与AP的连接
@@ -83,34 +252,239 @@ where
         self.request(b"AT+CWQAP\r\n", 5000)
     }
 
-    #[inline]
+    //扫描AP
+    pub fn scan(&mut self) -> Result<Vec<AccessPoint>> {
+        let reply = self.request(b"AT+CWLAP\r\n", 10000)?;
+        at::cwlap(&reply)
+    }
+
+    //当前连接的AP，未连接时返回None
+    pub fn station(&mut self) -> Result<Option<Station>> {
+        let reply = self.request(b"AT+CWJAP_CUR?\r\n", 5000)?;
+        at::cwjap_cur(&reply)
+    }
+
+    //AT+CWMODE_CUR=<mode> 设置工作模式
+    pub fn set_mode(&mut self, mode: WifiMode) -> Result<()> {
+        let cmd = match mode {
+            WifiMode::Station => b"AT+CWMODE_CUR=1\r\n",
+            WifiMode::SoftAp => b"AT+CWMODE_CUR=2\r\n",
+            WifiMode::Both => b"AT+CWMODE_CUR=3\r\n",
+        };
+        self.request(cmd, 5000)?;
+        Ok(())
+    }
+
+    //AT+CWSAP_CUR="<ssid>","<pwd>",<chl>,<ecn> 配置热点，需先设置SoftAp或Both模式
+    pub fn soft_ap(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        channel: u8,
+        encryption: Encryption,
+    ) -> Result<()> {
+        if encryption == Encryption::Wep || encryption == Encryption::Wpa2Enterprise {
+            return Err(Error::Other(String::from(
+                "soft-AP does not support this encryption",
+            )));
+        }
+        let cmd = format!(
+            "AT+CWSAP_CUR={},{},{},{}\r\n",
+            quote(ssid)?,
+            quote(password)?,
+            channel,
+            encryption.code()
+        );
+        self.request(cmd.as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //AT+CIPSTA_DEF="<ip>","<gateway>","<netmask>" 设置静态IP，同时关闭站点DHCP
+    pub fn set_static_ip(&mut self, ip: &str, gateway: &str, netmask: &str) -> Result<()> {
+        let cmd = format!(
+            "AT+CIPSTA_DEF={},{},{}\r\n",
+            quote(ip)?,
+            quote(gateway)?,
+            quote(netmask)?
+        );
+        self.request(cmd.as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //AT+CWDHCP_DEF=<mode>,<en> 开关DHCP，mode指定站点、热点或两者
+    pub fn set_dhcp(&mut self, mode: WifiMode, enable: bool) -> Result<()> {
+        let mode = match mode {
+            WifiMode::SoftAp => 0,
+            WifiMode::Station => 1,
+            WifiMode::Both => 2,
+        };
+        let cmd = format!("AT+CWDHCP_DEF={},{}\r\n", mode, enable as u8);
+        self.request(cmd.as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //发送指令并收集应答，模块忙或连接暂时不可用时按重试策略重发
     fn request(&mut self, cmd: &[u8], timeout: u32) -> Result<String> {
+        if self.passthrough {
+            return Err(Error::DeviceBusy);
+        }
+        let start = self.now;
+        let mut attempt = 1;
+        loop {
+            let err = match self.request_once(cmd, timeout) {
+                Err(Error::DeviceBusy) => Error::DeviceBusy,
+                Err(Error::Other(reply)) if retryable(&reply) => Error::Other(reply),
+                result => return result,
+            };
+            let elapsed = self.now.wrapping_sub(start);
+            if attempt >= self.retry.attempts
+                || elapsed.saturating_add(self.retry.delay) >= self.retry.deadline
+            {
+                return Err(err);
+            }
+            attempt += 1;
+            self.delay(self.retry.delay);
+        }
+    }
+
+    #[inline]
+    fn request_once(&mut self, cmd: &[u8], timeout: u32) -> Result<String> {
         self.write_exact(cmd).ok();
         let mut buf = String::new();
-        let mut reader = TimeoutReader(&mut self.port, &mut self.timer);
         loop {
-            match reader.read_line(timeout)? {
+            match self.read_line(timeout)? {
                 line if line.starts_with(OK) => {
                     buf.push_str(line.as_str());
                     return Ok(buf);
                 }
-                line if line.starts_with(ERROR) => {
+                line if line.starts_with(ERROR) || line.starts_with(FAIL) => {
                     buf.push_str(line.as_str());
                     return Err(Error::Other(buf));
                 }
                 line if line.starts_with(BUSY) => {
-                    return self.request(cmd, timeout);
-                    // buf.push_str(line.as_str());
-                    // return Err(Error::DeviceBusy);
+                    return Err(Error::DeviceBusy);
                 }
                 line => {
-                    crate::sprint!(line.as_str());
                     buf.push_str(line.as_str());
                 }
             }
         }
     }
 
+    //读一行应答，期间收到的+IPD数据放入对应连接的接收缓冲区，URC转成事件，都不返回给调用者
+    fn read_line(&mut self, timeout: u32) -> Result<String> {
+        loop {
+            if let Some(line) = self.read_input(timeout)? {
+                return Ok(line);
+            }
+        }
+    }
+
+    //读一段输入：普通应答行返回Some，+IPD数据或URC处理完后返回None
+    //发送数据时模块给出的提示符'>'没有换行，单独作为一行返回
+    fn read_input(&mut self, timeout: u32) -> Result<Option<String>> {
+        let mut line = Vec::new();
+        loop {
+            match self.read_byte(timeout)? {
+                b'>' if line.is_empty() => return Ok(Some(String::from(">"))),
+                b':' if line.starts_with(b"+IPD,") => {
+                    let ipd = at::ipd(&String::from_utf8_lossy(&line))?;
+                    self.receive(ipd, timeout)?;
+                    return Ok(None);
+                }
+                b'\n' => {
+                    line.push(b'\n');
+                    let text = String::from_utf8_lossy(&line).into_owned();
+                    return match at::urc(&text) {
+                        Some(event) => {
+                            self.dispatch(event);
+                            Ok(None)
+                        }
+                        None => Ok(Some(text)),
+                    };
+                }
+                b => line.push(b),
+            }
+        }
+    }
+
+    //读一个字节，timeout毫秒内没有数据返回Error::Timeout
+    fn read_byte(&mut self, timeout: u32) -> Result<u8> {
+        self.timer.start(1.khz());
+        let mut waited = 0;
+        loop {
+            match self.port.read() {
+                Ok(b) => return Ok(b),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(_)) => return Err(Error::ReadError),
+            }
+            if self.timer.wait().is_ok() {
+                self.now = self.now.wrapping_add(1);
+                waited += 1;
+                if waited >= timeout {
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+    }
+
+    //读出+IPD帧的数据，TCP数据放入连接的接收缓冲区，UDP数据保留报文边界和来源地址
+    fn receive(&mut self, ipd: Ipd, timeout: u32) -> Result<()> {
+        let id = ipd.link as usize;
+        if id >= LINKS {
+            return Err(Error::Parse("+IPD: bad link id"));
+        }
+        if self.udp[id] {
+            let mut data = Vec::with_capacity(ipd.len);
+            for _ in 0..ipd.len {
+                data.push(self.read_byte(timeout)?);
+            }
+            if self.datagrams.len() < DATAGRAMS {
+                self.datagrams.push_back(Datagram {
+                    link: ipd.link,
+                    remote: ipd.remote,
+                    data,
+                });
+            } else {
+                self.dispatch(Event::Overflow(ipd.link));
+            }
+            return Ok(());
+        }
+        let mut overflow = false;
+        for _ in 0..ipd.len {
+            let byte = self.read_byte(timeout)?;
+            if self.rx[id].len() < self.rx_capacity {
+                self.rx[id].push_back(byte);
+            } else {
+                overflow = true;
+            }
+        }
+        if overflow {
+            self.dispatch(Event::Overflow(ipd.link));
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self, event: Event) {
+        match event {
+            Event::Connected(id) if (id as usize) < LINKS => {
+                self.open[id as usize] = true;
+                if self.listening.is_some() {
+                    self.incoming.push_back(id);
+                }
+            }
+            Event::Closed(id) if (id as usize) < LINKS => {
+                self.open[id as usize] = false;
+                self.incoming.retain(|i| *i != id);
+            }
+            _ => {}
+        }
+        if self.events.len() == EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
     /////////////////////////////////////////////////////////////
 
     pub fn ifconfig(&mut self) -> Result<String> {
@@ -120,37 +494,137 @@ where
         Ok(reply1)
     }
 
-    pub fn ping(&mut self, domain: &str) -> Result<String> {
-        //AT+PING="www.shouqianba.com"
-        let mut cmd = String::from("AT+PING=\"");
-        cmd.push_str(domain);
-        cmd.push_str("\"\r\n");
+    pub fn reslove(&mut self, domain: &str) -> Result<String> {
+        let cmd = format!("AT+CIPDOMAIN={}\r\n", quote(domain)?);
         self.request(cmd.as_bytes(), 5000)
     }
 
-    pub fn reslove(&mut self, domain: &str) -> Result<String> {
-        let mut cmd = String::from("AT+CIPDOMAIN=\"");
-        cmd.push_str(domain);
-        cmd.push_str("\"\r\n");
-        self.request(cmd.as_bytes(), 5000)
+    //域名解析结果缓存的毫秒数，0表示不缓存
+    pub fn set_dns_ttl(&mut self, milliseconds: u32) {
+        self.dns_ttl = milliseconds;
+        if milliseconds == 0 {
+            self.resolved.clear();
+        }
+    }
+
+    pub fn flush_dns(&mut self) {
+        self.resolved.clear();
+    }
+
+    //最多两个自定义DNS服务器，为空时恢复默认
+    pub fn set_dns_servers(&mut self, servers: &[Ipv4Addr]) -> Result<()> {
+        let cmd = match servers {
+            [] => String::from("AT+CIPDNS_CUR=0\r\n"),
+            [first] => format!("AT+CIPDNS_CUR=1,\"{}\"\r\n", first),
+            [first, second] => format!("AT+CIPDNS_CUR=1,\"{}\",\"{}\"\r\n", first, second),
+            _ => return Err(Error::Other(String::from("CIPDNS at most 2 servers"))),
+        };
+        self.request(cmd.as_bytes(), 5000)?;
+        self.resolved.clear();
+        Ok(())
+    }
+
+    pub fn dns_servers(&mut self) -> Result<Vec<Ipv4Addr>> {
+        let reply = self.request(b"AT+CIPDNS_CUR?\r\n", 5000)?;
+        at::cipdns(&reply)
+    }
+
+    //未过期的解析结果
+    fn cached(&mut self, host: &str) -> Option<Ipv4Addr> {
+        let now = self.now;
+        self.resolved
+            .retain(|entry| (entry.expires.wrapping_sub(now) as i32) > 0);
+        self.resolved
+            .iter()
+            .find(|entry| entry.host.eq_ignore_ascii_case(host))
+            .map(|entry| entry.addr)
+    }
+
+    fn cache(&mut self, host: &str, addr: Ipv4Addr) {
+        if self.dns_ttl == 0 {
+            return;
+        }
+        if self.resolved.len() == RESOLVED {
+            self.resolved.pop_front();
+        }
+        self.resolved.push_back(Resolved {
+            host: String::from(host),
+            addr,
+            expires: self.now.wrapping_add(self.dns_ttl),
+        });
+    }
+
+    //开启模块内置的SNTP，timezone为-11~13，最多3个服务器
+    pub fn set_sntp(&mut self, timezone: i8, servers: &[&str]) -> Result<()> {
+        if !(-11..=13).contains(&timezone) {
+            return Err(Error::Other(String::from(
+                "CIPSNTPCFG timezone out of range",
+            )));
+        }
+        if servers.len() > 3 {
+            return Err(Error::Other(String::from("CIPSNTPCFG at most 3 servers")));
+        }
+        let mut cmd = format!("AT+CIPSNTPCFG=1,{}", timezone);
+        for server in servers {
+            cmd.push(',');
+            cmd.push_str(&quote(server)?);
+        }
+        cmd.push_str("\r\n");
+        self.request(cmd.as_bytes(), 5000)?;
+        self.timezone = timezone;
+        Ok(())
+    }
+
+    //模块内置SNTP的Unix时间戳，尚未同步(仍是1970年)时返回Error::Timeout
+    pub fn sntp_time(&mut self) -> Result<u32> {
+        let reply = self.request(b"AT+CIPSNTPTIME?\r\n", 5000)?;
+        let local = at::cipsntptime(&reply)?;
+        if local.year == 1970 {
+            return Err(Error::Timeout);
+        }
+        let local = local
+            .to_unix()
+            .ok_or(Error::Parse("+CIPSNTPTIME: bad time"))?;
+        Ok((local as i64 - self.timezone as i64 * 3600) as u32)
     }
 
-    pub fn net_state(&mut self) -> Result<String> {
-        self.request(b"AT+CIPSTATUS\r\n", 5000)
+    //不依赖模块SNTP，直接通过UDP向NTP服务器查询Unix时间戳
+    pub fn ntp_time(&mut self, server: &str, local_port: u16, timeout: u32) -> Result<u32> {
+        //没有本地时钟，用驱动的毫秒数区分前后两次请求
+        let transmit = Timestamp {
+            seconds: 0,
+            fraction: self.now | 1,
+        };
+        let link = if self.mux {
+            Some(self.open_udp_link((server, sntp::PORT), local_port)?)
+        } else {
+            self.connect_udp((server, sntp::PORT), local_port)?;
+            None
+        };
+        let mut socket = match link {
+            Some(link) => EspUdpSocket::with_link(self, link),
+            None => EspUdpSocket::new(self),
+        };
+        socket.set_timeout(timeout);
+        let result = sntp::query(&mut socket, transmit);
+        socket.close().ok();
+        result
+    }
+
+    pub fn net_state(&mut self) -> Result<CipStatus> {
+        let reply = self.request(b"AT+CIPSTATUS\r\n", 5000)?;
+        at::cipstatus(&reply)
     }
 
     pub fn connect(&mut self, addr: (&str, &str)) -> Result<String> {
         //AT+CIPSTART="TCP","iot.espressif.cn",8000 建立TCP连接
-        let mut cmd = String::from("AT+CIPSTART=\"TCP\",\"");
-        cmd.push_str(addr.0);
-        cmd.push_str("\",");
-        cmd.push_str(addr.1);
-        cmd.push_str("\r\n");
-        self.request(cmd.as_bytes(), 15000)
+        self.cipstart(None, "TCP", addr.0, addr.1)
     }
 
     pub fn disconnect(&mut self) -> Result<String> {
-        self.request(b"AT+CIPCLOSE\r\n", 5000)
+        let reply = self.request(b"AT+CIPCLOSE\r\n", 5000)?;
+        self.open[0] = false;
+        Ok(reply)
     }
 
     pub fn write_exact(&mut self, buf: &[u8]) -> Result<usize> {
@@ -160,28 +634,385 @@ where
         Ok(buf.len())
     }
 
-    // pub fn read_exact<const N: usize>(&mut self, buf: &mut [u8], timeout: u32) -> Result<()> {
-    //     let mut reader = TimeoutReader(&mut self.port, &mut self.timer);
-    //     reader.read_exact(buf, timeout)?;
-    //     Ok(())
-    // }
-
     pub fn send_data(&mut self, buf: &[u8], timeout: u32) -> Result<usize> {
-        let mut cmd = String::from("AT+CIPSEND=");
-        cmd.push_str(format!("{}", buf.len()).as_str());
+        self.cipsend(None, buf, None, timeout)
+    }
+
+    //读取连接收到的数据，接收缓冲区为空时等待模块推送+IPD，连接已关闭返回Error::EOF
+    pub fn read_data(&mut self, buf: &mut [u8], timeout: u32) -> Result<usize> {
+        self.read_link(0, buf, timeout)
+    }
+
+    /////////////////////////////////////////////////////////////
+    //多连接模式(AT+CIPMUX=1)
+
+    //开启/关闭多连接模式，需在没有连接时切换
+    pub fn set_mux(&mut self, multiple: bool) -> Result<()> {
+        if multiple {
+            self.request(b"AT+CIPMUX=1\r\n", 5000)?;
+        } else {
+            self.request(b"AT+CIPMUX=0\r\n", 5000)?;
+        }
+        self.mux = multiple;
+        Ok(())
+    }
+
+    pub fn is_mux(&self) -> bool {
+        self.mux
+    }
+
+    //多连接模式下建立TCP连接，占用一个空闲的link ID
+    pub fn open_link(&mut self, addr: (&str, u16)) -> Result<Link> {
+        let id = self.free_link()?;
+        self.cipstart(Some(id), "TCP", addr.0, addr.1)?;
+        Ok(self.link(id))
+    }
+
+    pub fn send(&mut self, link: &Link, buf: &[u8], timeout: u32) -> Result<usize> {
+        self.check(link)?;
+        self.cipsend(Some(link.id), buf, None, timeout)
+    }
+
+    //连接被远端关闭后仍可读完缓冲区里的数据，之后返回Error::EOF
+    pub fn recv(&mut self, link: &Link, buf: &mut [u8], timeout: u32) -> Result<usize> {
+        if self.generation[link.id as usize] != link.generation {
+            return Err(Error::EOF);
+        }
+        self.read_link(link.id as usize, buf, timeout)
+    }
+
+    //关闭连接，句柄随之失效
+    pub fn close(&mut self, link: Link) -> Result<()> {
+        let id = link.id as usize;
+        if self.generation[id] != link.generation {
+            return Ok(());
+        }
+        self.generation[id] = self.generation[id].wrapping_add(1);
+        self.rx[id].clear();
+        self.datagrams.retain(|d| d.link != link.id);
+        if !self.open[id] {
+            return Ok(());
+        }
+        let result = self.request(format!("AT+CIPCLOSE={}\r\n", id).as_bytes(), 5000);
+        self.open[id] = false;
+        result.map(|_| ())
+    }
+
+    /////////////////////////////////////////////////////////////
+    //UDP
+
+    //多连接模式下建立UDP连接，remote是默认的发送目的地址
+    pub fn open_udp_link(&mut self, remote: (&str, u16), local_port: u16) -> Result<Link> {
+        let id = self.free_link()?;
+        self.udpstart(Some(id), remote, local_port)?;
+        Ok(self.link(id))
+    }
+
+    //单连接模式下建立UDP连接
+    pub fn connect_udp(&mut self, remote: (&str, u16), local_port: u16) -> Result<()> {
+        self.udpstart(None, remote, local_port)
+    }
+
+    //发送数据报到指定地址，link为None表示单连接模式
+    pub fn send_to(
+        &mut self,
+        link: Option<&Link>,
+        buf: &[u8],
+        addr: (&str, u16),
+        timeout: u32,
+    ) -> Result<usize> {
+        let id = self.udp_link(link)?;
+        if !self.open[id as usize] {
+            return Err(Error::EOF);
+        }
+        self.cipsend(link.map(|_| id), buf, Some(addr), timeout)
+    }
+
+    //接收一个数据报，超出buf的部分被丢弃，返回数据长度和来源地址
+    pub fn recv_from(
+        &mut self,
+        link: Option<&Link>,
+        buf: &mut [u8],
+        timeout: u32,
+    ) -> Result<(usize, (Ipv4Addr, u16))> {
+        let id = self.udp_link(link)?;
+        loop {
+            if let Some(index) = self.datagrams.iter().position(|d| d.link == id) {
+                let datagram = self.datagrams.remove(index).unwrap();
+                let len = buf.len().min(datagram.data.len());
+                buf[..len].copy_from_slice(&datagram.data[..len]);
+                let remote = datagram
+                    .remote
+                    .ok_or(Error::Parse("+IPD: missing remote address"))?;
+                return Ok((len, remote));
+            }
+            if !self.open[id as usize] {
+                return Err(Error::EOF);
+            }
+            self.read_input(timeout)?;
+        }
+    }
+
+    fn udp_link(&self, link: Option<&Link>) -> Result<u8> {
+        let id = match link {
+            Some(link) => {
+                if self.generation[link.id as usize] != link.generation {
+                    return Err(Error::EOF);
+                }
+                link.id
+            }
+            None => 0,
+        };
+        if !self.udp[id as usize] {
+            return Err(Error::Other(String::from("not a UDP link")));
+        }
+        Ok(id)
+    }
+
+    //AT+CIPSTART=[<link ID>,]"UDP",<remote IP>,<remote port>,<local port>,0
+    fn udpstart(&mut self, id: Option<u8>, remote: (&str, u16), local_port: u16) -> Result<()> {
+        if !self.dinfo {
+            //+IPD带上对端地址
+            self.request(b"AT+CIPDINFO=1\r\n", 5000)?;
+            self.dinfo = true;
+        }
+        let port = format!("{},{},0", remote.1, local_port);
+        self.cipstart(id, "UDP", remote.0, port).map(|_| ())
+    }
+
+    /////////////////////////////////////////////////////////////
+    //SSL，握手和加解密都由模块完成
+
+    //AT+CIPSSLSIZE=<size> SSL缓冲区大小(2048~4096)，需在建立SSL连接前设置
+    pub fn set_ssl_buffer(&mut self, size: u16) -> Result<()> {
+        if !(2048..=4096).contains(&size) {
+            return Err(Error::Other(String::from("CIPSSLSIZE out of range")));
+        }
+        self.request(format!("AT+CIPSSLSIZE={}\r\n", size).as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //AT+CIPSSLCCONF=<mode> 配置证书校验方式
+    pub fn set_ssl_auth(&mut self, auth: SslAuth) -> Result<()> {
+        let mode = match auth {
+            SslAuth::None => 0,
+            SslAuth::ClientCert => 1,
+            SslAuth::ServerCa => 2,
+            SslAuth::Both => 3,
+        };
+        self.request(format!("AT+CIPSSLCCONF={}\r\n", mode).as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //AT+CIPSSLCPSK="<psk>","<hint>" 配置PSK，固件不支持时返回ERROR
+    pub fn set_ssl_psk(&mut self, psk: &str, hint: &str) -> Result<()> {
+        let cmd = format!("AT+CIPSSLCPSK={},{}\r\n", quote(psk)?, quote(hint)?);
+        self.request(cmd.as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //多连接模式下建立SSL连接
+    pub fn open_ssl_link(&mut self, addr: (&str, u16)) -> Result<Link> {
+        let id = self.free_link()?;
+        self.cipstart(Some(id), "SSL", addr.0, addr.1)?;
+        Ok(self.link(id))
+    }
+
+    //建立SSL连接，返回的TcpStream与普通TCP连接用法相同
+    pub fn open_ssl(&mut self, addr: (&str, u16)) -> Result<EspTcpStream<'_, T, TIM>> {
+        if self.mux {
+            let link = self.open_ssl_link(addr)?;
+            return Ok(EspTcpStream::with_link(self, link));
+        }
+        self.cipstart(None, "SSL", addr.0, addr.1)?;
+        Ok(EspTcpStream::new(self))
+    }
+
+    /////////////////////////////////////////////////////////////
+    //透传模式(AT+CIPMODE=1)，只能在单连接模式下、连接建立后进入
+
+    //进入透传模式，之后通过返回的Passthrough直接收发数据，exit退出
+    pub fn passthrough(&mut self, timeout: u32) -> Result<Passthrough<'_, T, TIM>> {
+        if self.mux {
+            return Err(Error::Other(String::from("CIPMUX=1")));
+        }
+        if !self.open[0] {
+            return Err(Error::EOF);
+        }
+        self.request(b"AT+CIPMODE=1\r\n", 5000)?;
+        if let Err(err) = self.request(b"AT+CIPSEND\r\n", timeout) {
+            self.request(b"AT+CIPMODE=0\r\n", 5000).ok();
+            return Err(err);
+        }
+        //read '>'
+        while self.read_line(timeout)? != ">" {}
+        self.passthrough = true;
+        Ok(Passthrough::new(self))
+    }
+
+    //退出透传：发送"+++"，前后各保持1秒静默，再恢复AT+CIPMODE=0
+    pub(crate) fn exit_passthrough(&mut self) -> Result<()> {
+        if !self.passthrough {
+            return Ok(());
+        }
+        self.delay(1000);
+        for b in b"+++" {
+            nb::block!(self.port.write(*b)).map_err(|_| Error::WriteError)?;
+        }
+        self.delay(1000);
+        self.passthrough = false;
+        self.request(b"AT+CIPMODE=0\r\n", 5000)?;
+        Ok(())
+    }
+
+    //透传模式下读数据，先读完进入透传前已缓存的数据
+    pub(crate) fn read_raw(&mut self) -> nb::Result<u8, Error> {
+        if let Some(b) = self.rx[0].pop_front() {
+            return Ok(b);
+        }
+        self.port.read().map_err(|err| match err {
+            nb::Error::WouldBlock => nb::Error::WouldBlock,
+            nb::Error::Other(_) => nb::Error::Other(Error::ReadError),
+        })
+    }
+
+    pub(crate) fn write_raw(&mut self, word: u8) -> nb::Result<(), Error> {
+        self.port.write(word).map_err(|err| match err {
+            nb::Error::WouldBlock => nb::Error::WouldBlock,
+            nb::Error::Other(_) => nb::Error::Other(Error::WriteError),
+        })
+    }
+
+    pub(crate) fn flush_raw(&mut self) -> nb::Result<(), Error> {
+        self.port.flush().map_err(|err| match err {
+            nb::Error::WouldBlock => nb::Error::WouldBlock,
+            nb::Error::Other(_) => nb::Error::Other(Error::WriteError),
+        })
+    }
+
+    //等待milliseconds毫秒，期间不收发数据
+    fn delay(&mut self, milliseconds: u32) {
+        self.timer.start(1.khz());
+        for _ in 0..milliseconds {
+            nb::block!(self.timer.wait()).ok();
+        }
+        self.now = self.now.wrapping_add(milliseconds);
+    }
+
+    /////////////////////////////////////////////////////////////
+    //TCP服务器，需要多连接模式，未开启时自动开启
+
+    //AT+CIPSERVER=1,<port> 开始监听
+    pub fn listen(&mut self, port: u16) -> Result<()> {
+        if !self.mux {
+            self.set_mux(true)?;
+        }
+        self.request(format!("AT+CIPSERVER=1,{}\r\n", port).as_bytes(), 5000)?;
+        self.listening = Some(port);
+        Ok(())
+    }
+
+    //AT+CIPSERVER=0 停止监听，已接入的连接不受影响
+    pub fn unlisten(&mut self) -> Result<()> {
+        self.request(b"AT+CIPSERVER=0\r\n", 5000)?;
+        self.listening = None;
+        self.incoming.clear();
+        Ok(())
+    }
+
+    //AT+CIPSTO=<time> 服务器断开空闲客户端的超时时间(0~7200秒，0表示永不超时)
+    pub fn set_server_timeout(&mut self, seconds: u16) -> Result<()> {
+        if seconds > 7200 {
+            return Err(Error::Other(String::from("CIPSTO out of range")));
+        }
+        self.request(format!("AT+CIPSTO={}\r\n", seconds).as_bytes(), 5000)?;
+        Ok(())
+    }
+
+    //等待客户端接入(<link ID>,CONNECT)，timeout毫秒内没有输入返回Error::Timeout
+    pub fn accept(&mut self, timeout: u32) -> Result<Link> {
+        if self.listening.is_none() {
+            return Err(Error::NoNetwork);
+        }
+        loop {
+            if let Some(id) = self.incoming.pop_front() {
+                return Ok(self.link(id));
+            }
+            self.read_input(timeout)?;
+        }
+    }
+
+    fn free_link(&self) -> Result<u8> {
+        if !self.mux {
+            return Err(Error::Other(String::from("CIPMUX=0")));
+        }
+        (0..LINKS)
+            .find(|id| !self.open[*id])
+            .map(|id| id as u8)
+            .ok_or(Error::BufferFull)
+    }
+
+    fn link(&mut self, id: u8) -> Link {
+        let generation = self.generation[id as usize].wrapping_add(1);
+        self.generation[id as usize] = generation;
+        Link { id, generation }
+    }
+
+    fn check(&self, link: &Link) -> Result<()> {
+        let id = link.id as usize;
+        if self.generation[id] != link.generation || !self.open[id] {
+            return Err(Error::EOF);
+        }
+        Ok(())
+    }
+
+    //AT+CIPSTART=[<link ID>,]<type>,<remote IP>,<remote port>
+    fn cipstart<P: core::fmt::Display>(
+        &mut self,
+        id: Option<u8>,
+        kind: &str,
+        host: &str,
+        port: P,
+    ) -> Result<String> {
+        let host = quote(host)?;
+        let cmd = match id {
+            Some(id) => format!("AT+CIPSTART={},\"{}\",{},{}\r\n", id, kind, host, port),
+            None => format!("AT+CIPSTART=\"{}\",{},{}\r\n", kind, host, port),
+        };
+        let slot = id.unwrap_or(0);
+        self.rx[slot as usize].clear();
+        self.datagrams.retain(|d| d.link != slot);
+        self.udp[slot as usize] = kind == "UDP";
+        let reply = self.request(cmd.as_bytes(), 15000);
+        if let Some(id) = id {
+            //主动建立的连接不是客户端接入
+            self.incoming.retain(|i| *i != id);
+        }
+        reply
+    }
+
+    //AT+CIPSEND=[<link ID>,]<length>[,<remote IP>,<remote port>]
+    fn cipsend(
+        &mut self,
+        id: Option<u8>,
+        buf: &[u8],
+        to: Option<(&str, u16)>,
+        timeout: u32,
+    ) -> Result<usize> {
+        let mut cmd = match id {
+            Some(id) => format!("AT+CIPSEND={},{}", id, buf.len()),
+            None => format!("AT+CIPSEND={}", buf.len()),
+        };
+        if let Some((ip, port)) = to {
+            cmd.push_str(format!(",{},{}", quote(ip)?, port).as_str());
+        }
         cmd.push_str("\r\n");
         self.request(cmd.as_bytes(), timeout)?;
-        {
-            let mut reader = TimeoutReader(&mut self.port, &mut self.timer);
-            let mut reply = [0; 1];
-            //read '>'
-            reader.read_exact(&mut reply, timeout)?;
-        }
-        // self.request(cmd.as_bytes(), 5000)?;
+        //read '>'
+        while self.read_line(timeout)? != ">" {}
         self.write_exact(buf)?;
-        let mut reader = TimeoutReader(&mut self.port, &mut self.timer);
         loop {
-            match reader.read_line(5000)? {
+            match self.read_line(5000)?.trim() {
                 line if line.starts_with("SEND OK") || line.starts_with("OK") => {
                     return Ok(buf.len())
                 }
@@ -192,11 +1023,365 @@ where
             }
         }
     }
-    pub fn read_data(&mut self, len: usize, timeout: u32) -> Result<String> {
-        //AT+CIPRECVDATA=<len>
-        let mut cmd = String::from("AT+CIPSEND=");
-        cmd.push_str(format!("{}", len).as_str());
-        cmd.push_str("\r\n");
-        self.request(cmd.as_bytes(), timeout)
+
+    fn read_link(&mut self, id: usize, buf: &mut [u8], timeout: u32) -> Result<usize> {
+        while self.rx[id].is_empty() {
+            if !self.open[id] {
+                return Err(Error::EOF);
+            }
+            self.read_input(timeout)?;
+        }
+        let len = buf.len().min(self.rx[id].len());
+        buf.iter_mut()
+            .zip(self.rx[id].drain(..len))
+            .for_each(|(b, r)| *b = r);
+        Ok(len)
+    }
+
+    //模块重启后所有连接、监听、透传状态都已丢失，已有的句柄全部失效
+    pub(crate) fn forget(&mut self) {
+        self.mux = false;
+        self.dinfo = false;
+        self.passthrough = false;
+        self.listening = None;
+        self.incoming.clear();
+        self.datagrams.clear();
+        for id in 0..LINKS {
+            self.open[id] = false;
+            self.udp[id] = false;
+            self.generation[id] = self.generation[id].wrapping_add(1);
+            self.rx[id].clear();
+        }
+    }
+
+    //通过RST(或CH_PD)引脚硬件复位，拉低100毫秒后释放并等待"ready"
+    pub fn hard_reset<P: OutputPin>(&mut self, pin: &mut P) -> Result<()> {
+        pin.set_low().map_err(|_| Error::WriteError)?;
+        self.delay(100);
+        pin.set_high().map_err(|_| Error::WriteError)?;
+        self.forget();
+        self.wait_for("ready", 5000)
+    }
+
+    //等待模块输出以token开头的一行，如重启后的"ready"
+    fn wait_for(&mut self, token: &str, timeout: u32) -> Result<()> {
+        loop {
+            if self.read_line(timeout)?.starts_with(token) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<'a, T, TIM> Net<'a> for Esp8266<T, TIM>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8> + 'a,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz> + 'a,
+{
+    type TcpStream = EspTcpStream<'a, T, TIM>;
+    type UdpSocket = EspUdpSocket<'a, T, TIM>;
+
+    //打个招呼
+    fn hello(&mut self) -> Result<()> {
+        self.request(b"AT\r\n", 5000).map(|_| ())
+    }
+
+    fn state(&mut self) -> Result<Status> {
+        self.net_state().map(|state| state.status())
+    }
+
+    //重置
+    fn reset(&mut self) -> Result<()> {
+        self.request(b"AT+RST\r\n", 5000)?;
+        self.forget();
+        self.wait_for("ready", 5000)
+    }
+
+    //恢复出厂设置
+    fn recover(&mut self) -> Result<()> {
+        self.restore()?;
+        self.forget();
+        self.wait_for("ready", 5000)
+    }
+
+    fn ifinfo(&mut self) -> Result<IfInfo> {
+        let ether = self.mac()?;
+        let reply = self.request(b"AT+CIPSTA_CUR?\r\n", 5000)?;
+        at::cipsta(&reply).map(|sta| sta.into_ifinfo(ether))
+    }
+
+    fn mac(&mut self) -> Result<heapless::String<17>> {
+        let reply = self.request(b"AT+CIFSR\r\n", 5000)?;
+        at::cifsr(&reply)?
+            .sta_mac
+            .ok_or(Error::Parse("+CIFSR: missing STAMAC"))
+    }
+
+    fn ip(&mut self) -> Result<Ipv4Addr> {
+        let reply = self.request(b"AT+CIFSR\r\n", 5000)?;
+        at::cifsr(&reply)?
+            .sta_ip
+            .ok_or(Error::Parse("+CIFSR: missing STAIP"))
+    }
+
+    //IP地址直接返回，域名先查缓存
+    fn resolve(&mut self, host: &str) -> Result<Ipv4Addr> {
+        if let Ok(addr) = host.parse() {
+            return Ok(addr);
+        }
+        if let Some(addr) = self.cached(host) {
+            return Ok(addr);
+        }
+        let reply = self.reslove(host)?;
+        let addr = at::cipdomain(&reply)?;
+        self.cache(host, addr);
+        Ok(addr)
+    }
+
+    fn ping(&mut self, host: &str) -> Result<()> {
+        //AT+PING="www.shouqianba.com"
+        let cmd = format!("AT+PING={}\r\n", quote(host)?);
+        match self.request(cmd.as_bytes(), 5000) {
+            Ok(reply) => at::ping(&reply).map(|_| ()),
+            Err(Error::Other(reply)) if reply.contains("+timeout") => Err(Error::Timeout),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn open_tcp(&'a mut self, addr: (&str, u16)) -> Result<Self::TcpStream> {
+        if self.mux {
+            let link = self.open_link(addr)?;
+            return Ok(EspTcpStream::with_link(self, link));
+        }
+        self.cipstart(None, "TCP", addr.0, addr.1)?;
+        Ok(EspTcpStream::new(self))
+    }
+
+    //多连接模式下关闭所有连接，已有的句柄全部失效
+    fn close_tcp(&mut self) -> Result<()> {
+        if !self.mux {
+            return self.disconnect().map(|_| ());
+        }
+        self.request(b"AT+CIPCLOSE=5\r\n", 5000)?;
+        for id in 0..LINKS {
+            self.open[id] = false;
+            self.generation[id] = self.generation[id].wrapping_add(1);
+            self.rx[id].clear();
+        }
+        Ok(())
+    }
+
+    fn open_udp(&'a mut self, remote: (&str, u16), local_port: u16) -> Result<Self::UdpSocket> {
+        if self.mux {
+            let link = self.open_udp_link(remote, local_port)?;
+            return Ok(EspUdpSocket::with_link(self, link));
+        }
+        self.connect_udp(remote, local_port)?;
+        Ok(EspUdpSocket::new(self))
+    }
+}
+
+//指令里的字符串参数：加上双引号，其中的" , \前面加反斜杠；
+//换行会把后面的内容变成另一条指令，直接拒绝
+fn quote(value: &str) -> Result<String> {
+    if value.contains(['\r', '\n']) {
+        return Err(Error::Other(String::from("AT string contains CR/LF")));
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == ',' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    Ok(quoted)
+}
+
+//可以重试的ERROR应答
+fn retryable(reply: &str) -> bool {
+    reply.contains("link is not valid") || reply.contains("ALREADY CONNECTED")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Port, Timer};
+
+    fn esp(script: &str) -> Esp8266<Port, Timer> {
+        Esp8266::new(Port::new(script.as_bytes()), Timer)
+    }
+
+    fn output(esp: &mut Esp8266<Port, Timer>) -> String {
+        String::from_utf8(esp.port.take_output()).unwrap()
+    }
+
+    #[test]
+    fn request_collects_reply() {
+        let mut esp = esp("AT\r\n\r\nOK\r\n");
+        assert!(esp.hello().is_ok());
+        assert_eq!(output(&mut esp), "AT\r\n");
+    }
+
+    #[test]
+    fn ping_reply() {
+        let mut esp = esp("+12\r\n\r\nOK\r\n+timeout\r\n\r\nERROR\r\n");
+        esp.ping("www.espressif.com").unwrap();
+        assert!(matches!(esp.ping("10.0.0.9"), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn quoted_parameters() {
+        let mut esp = esp("+timeout\r\n\r\nERROR\r\n");
+        assert!(matches!(esp.ping("h\"o,st\\"), Err(Error::Timeout)));
+        assert_eq!(output(&mut esp), "AT+PING=\"h\\\"o\\,st\\\\\"\r\n");
+        //换行会变成另一条指令，不发送
+        assert!(esp.ping("h\r\nAT+RESTORE").is_err());
+        assert!(esp.port.output.is_empty());
+    }
+
+    #[test]
+    fn mux_links() {
+        let mut esp = esp(concat!(
+            "OK\r\n",
+            "0,CONNECT\r\n\r\nOK\r\n",
+            "1,CONNECT\r\n\r\nOK\r\n",
+            "+IPD,1,3:abc+IPD,0,2:xyOK\r\n> \r\nSEND OK\r\n",
+            "1,CLOSED\r\n"
+        ));
+        esp.set_mux(true).unwrap();
+        let a = esp.open_link(("a", 1)).unwrap();
+        let b = esp.open_link(("b", 2)).unwrap();
+        assert_eq!((a.id(), b.id()), (0, 1));
+        esp.send(&a, b"hi", 100).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(esp.recv(&a, &mut buf, 100).unwrap(), 2);
+        assert_eq!(&buf[..2], b"xy");
+        assert_eq!(esp.recv(&b, &mut buf, 100).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+        //远端关闭后旧句柄失效
+        assert!(esp.recv(&b, &mut buf, 100).is_err());
+        assert!(esp.send(&b, b"x", 100).is_err());
+        let out = output(&mut esp);
+        assert!(out.contains("AT+CIPSTART=1,\"TCP\",\"b\",2\r\n"), "{}", out);
+        assert!(out.contains("AT+CIPSEND=0,2\r\nhi"), "{}", out);
+    }
+
+    #[test]
+    fn server_accept() {
+        let mut esp = esp("OK\r\nOK\r\nOK\r\n0,CONNECT\r\n+IPD,0,4:ping");
+        esp.listen(3333).unwrap();
+        esp.set_server_timeout(60).unwrap();
+        let client = esp.accept(100).unwrap();
+        assert_eq!(client.id(), 0);
+        let mut buf = [0u8; 8];
+        assert_eq!(esp.recv(&client, &mut buf, 100).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert!(esp.accept(10).is_err());
+    }
+
+    #[test]
+    fn udp_datagram() {
+        let mut esp =
+            esp("OK\r\nCONNECT\r\n\r\nOK\r\nOK\r\n> \r\nSEND OK\r\n+IPD,4,1.2.3.4,123:abcd");
+        let mut socket = esp.open_udp(("1.2.3.4", 123), 1234).unwrap();
+        socket.send_to(b"x", ("1.2.3.4", 123)).unwrap();
+        //缓冲区不够时数据报被截断
+        let mut buf = [0u8; 2];
+        let (len, (ip, port)) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!((len, ip, port), (2, Ipv4Addr::new(1, 2, 3, 4), 123));
+        assert_eq!(&buf, b"ab");
+    }
+
+    #[test]
+    fn passthrough_session() {
+        use embedded_hal::serial::{Read, Write};
+        let mut esp = esp("CONNECT\r\n\r\nOK\r\nOK\r\nOK\r\n\r\n>rawOK\r\n");
+        esp.connect(("h", "1")).unwrap();
+        {
+            let mut session = esp.passthrough(100).unwrap();
+            session.write(b'a').unwrap();
+            assert_eq!(session.read().unwrap(), b'r');
+            assert_eq!(session.read().unwrap(), b'a');
+            assert_eq!(session.read().unwrap(), b'w');
+            session.exit().unwrap();
+        }
+        //退出透传后剩下的输入都已经读完
+        assert!(esp.device_info().is_err());
+    }
+
+    #[test]
+    fn quoted_wifi_parameters() {
+        let mut esp = esp("OK\r\nOK\r\nOK\r\n");
+        esp.dial("a\"b,c\\d", "pass\",\"word", true).unwrap();
+        esp.soft_ap("x,y", "12345678", 5, Encryption::Wpa2Psk)
+            .unwrap();
+        assert_eq!(
+            output(&mut esp),
+            concat!(
+                "AT+CWJAP_DEF=\"a\\\"b\\,c\\\\d\",\"pass\\\"\\,\\\"word\"\r\n",
+                "AT+CWAUTOCONN=1\r\n",
+                "AT+CWSAP_CUR=\"x\\,y\",\"12345678\",5,3\r\n"
+            )
+        );
+        assert!(esp.dial("a\r\nAT+RESTORE", "", false).is_err());
+        assert!(esp.port.output.is_empty());
+    }
+
+    #[test]
+    fn busy_retry() {
+        let mut esp = self::esp("busy p...\r\nbusy p...\r\nOK\r\n");
+        assert!(esp.hello().is_ok());
+
+        let mut esp = self::esp("busy p...\r\nbusy p...\r\nOK\r\n");
+        esp.set_retry_policy(RetryPolicy {
+            attempts: 2,
+            delay: 10,
+            deadline: 1000,
+        });
+        assert!(matches!(esp.hello(), Err(Error::DeviceBusy)));
+
+        let mut esp = self::esp("link is not valid\r\n\r\nERROR\r\n\r\nOK\r\n");
+        assert!(esp.hello().is_ok());
+    }
+
+    #[test]
+    fn resolve_and_cache() {
+        let mut esp = esp(concat!(
+            "+CIPDOMAIN:1.2.3.4\r\nOK\r\n",
+            "OK\r\n",
+            "+CIPDNS_CUR:8.8.8.8\r\n+CIPDNS_CUR:1.1.1.1\r\nOK\r\n"
+        ));
+        assert_eq!(esp.resolve("a.com").unwrap(), Ipv4Addr::new(1, 2, 3, 4));
+        //第二次走缓存，不区分大小写
+        assert_eq!(esp.resolve("A.com").unwrap(), Ipv4Addr::new(1, 2, 3, 4));
+        assert_eq!(esp.resolve("9.8.7.6").unwrap(), Ipv4Addr::new(9, 8, 7, 6));
+        esp.set_dns_servers(&[Ipv4Addr::new(8, 8, 8, 8)]).unwrap();
+        assert_eq!(esp.dns_servers().unwrap().len(), 2);
+        //换了DNS服务器后缓存清空
+        assert!(esp.resolve("a.com").is_err());
+    }
+
+    #[test]
+    fn sntp_time() {
+        let mut esp = esp(concat!(
+            "OK\r\n",
+            "+CIPSNTPTIME:Thu Jan 01 00:00:00 1970\r\nOK\r\n",
+            "+CIPSNTPTIME:Thu Aug 04 22:48:05 2016\r\nOK\r\n"
+        ));
+        esp.set_sntp(8, &["pool.ntp.org"]).unwrap();
+        //还没同步时模块返回1970年
+        assert!(matches!(esp.sntp_time(), Err(Error::Timeout)));
+        assert_eq!(esp.sntp_time().unwrap(), 1470322085);
+        let out = output(&mut esp);
+        assert!(
+            out.starts_with("AT+CIPSNTPCFG=1,8,\"pool.ntp.org\"\r\n"),
+            "{}",
+            out
+        );
+        //换行会变成另一条指令，不发送
+        assert!(esp.set_sntp(8, &["pool.ntp.org\n"]).is_err());
+        assert!(esp.port.output.is_empty());
     }
 }
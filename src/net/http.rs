@@ -0,0 +1,629 @@
+//! HTTP/1.1客户端
+//!
+//! 在任意`TcpStream`上发送一个请求并把应答读进调用者提供的缓冲区，
+//! 应答头和正文(Content-Length、chunked或读到连接关闭)都放在同一块缓冲区里，
+//! 缓冲区放不下时返回Error::BufferFull。正文比内存大时(比如下载固件)用`fetch`边收边处理
+
+use super::TcpStream;
+use crate::io::{Error, Result};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+pub struct Request<'r> {
+    method: Method,
+    host: &'r str,
+    path: &'r str,
+    headers: Vec<(&'r str, &'r str)>,
+    body: &'r [u8],
+    timeout: u32,
+}
+
+impl<'r> Request<'r> {
+    pub fn new(method: Method, host: &'r str, path: &'r str) -> Self {
+        Self {
+            method,
+            host,
+            path,
+            headers: Vec::new(),
+            body: &[],
+            timeout: 5000,
+        }
+    }
+
+    pub fn get(host: &'r str, path: &'r str) -> Self {
+        Self::new(Method::Get, host, path)
+    }
+
+    pub fn post(host: &'r str, path: &'r str, body: &'r [u8]) -> Self {
+        Self::new(Method::Post, host, path).body(body)
+    }
+
+    //POST JSON，自动加上Content-Type
+    pub fn post_json(host: &'r str, path: &'r str, json: &'r str) -> Self {
+        Self::post(host, path, json.as_bytes()).header("Content-Type", "application/json")
+    }
+
+    //Host、Content-Length、Connection未指定时自动添加；
+    //名字或值带CR/LF时send/fetch返回错误，不会发出去
+    pub fn header(mut self, name: &'r str, value: &'r str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub fn body(mut self, body: &'r [u8]) -> Self {
+        self.body = body;
+        self
+    }
+
+    //本次请求每次读写的超时(毫秒)
+    pub fn timeout(mut self, milliseconds: u32) -> Self {
+        self.timeout = milliseconds;
+        self
+    }
+
+    //请求行和请求头，以空行结束，不含正文
+    pub fn head(&self) -> Result<String> {
+        //换行会拆出额外的请求头甚至另一个请求
+        let bad = |field: &str| field.contains(['\r', '\n']);
+        if bad(self.path) || bad(self.host) || self.headers.iter().any(|(n, v)| bad(n) || bad(v)) {
+            return Err(Error::Other(String::from("HTTP request contains CR/LF")));
+        }
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method.as_str(), self.path);
+        if !self.has_header("Host") {
+            head.push_str(&format!("Host: {}\r\n", self.host));
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        let has_body = !self.body.is_empty() || matches!(self.method, Method::Post | Method::Put);
+        if has_body && !self.has_header("Content-Length") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        if !self.has_header("Connection") {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        Ok(head)
+    }
+
+    fn has_header(&self, name: &str) -> bool {
+        self.headers
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case(name))
+    }
+}
+
+pub struct Response<'b> {
+    pub status: u16,
+    pub reason: &'b str,
+    head: &'b str, //状态行之后的响应头
+    pub body: &'b [u8],
+}
+
+impl<'b> Response<'b> {
+    pub fn headers(&self) -> impl Iterator<Item = (&'b str, &'b str)> + 'b {
+        self.head.split("\r\n").filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim(), value.trim()))
+        })
+    }
+
+    //名字不区分大小写
+    pub fn header(&self, name: &str) -> Option<&'b str> {
+        self.headers()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+//发送请求并读取应答，连接是否关闭由调用者决定
+pub fn send<'b, S: TcpStream>(
+    stream: &mut S,
+    request: &Request,
+    buf: &'b mut [u8],
+) -> Result<Response<'b>> {
+    stream.set_timeout(request.timeout);
+    write_all(stream, request.head()?.as_bytes())?;
+    write_all(stream, request.body)?;
+
+    let mut filled = 0;
+    let head_len = loop {
+        if let Some(pos) = find(&buf[..filled], b"\r\n\r\n") {
+            break pos + 4;
+        }
+        filled += read_more(stream, buf, filled)?;
+    };
+    let head = parse_head(&buf[..head_len])?;
+    let body_len = if request.method == Method::Head
+        || head.status / 100 == 1
+        || head.status == 204
+        || head.status == 304
+    {
+        0
+    } else if head.chunked {
+        dechunk(stream, &mut buf[head_len..], filled - head_len)?
+    } else if let Some(len) = head.content_length {
+        //Content-Length由服务器给出，相加可能溢出
+        let end = head_len
+            .checked_add(len)
+            .filter(|end| *end <= buf.len())
+            .ok_or(Error::BufferFull)?;
+        while filled < end {
+            filled += read_more(stream, buf, filled)?;
+        }
+        len
+    } else {
+        //既没有长度也不是chunked，读到连接关闭
+        loop {
+            match read_more(stream, buf, filled) {
+                Ok(len) => filled += len,
+                Err(Error::EOF) => break filled - head_len,
+                Err(err) => return Err(err),
+            }
+        }
+    };
+
+    let (head_buf, body) = buf.split_at(head_len);
+    let text = core::str::from_utf8(head_buf).map_err(|_| Error::Parse("http: head not utf-8"))?;
+    let status_line = &text[..head.status_end];
+    let reason = status_line.splitn(3, ' ').nth(2).unwrap_or("");
+    Ok(Response {
+        status: head.status,
+        reason,
+        head: &text[head.status_end..],
+        body: &body[..body_len],
+    })
+}
+
+//发送请求，2xx应答的正文分段交给sink，buf只需要放得下应答头，返回状态码和正文长度；
+//非2xx应答不读正文。不支持chunked正文
+pub fn fetch<S, F>(
+    stream: &mut S,
+    request: &Request,
+    buf: &mut [u8],
+    mut sink: F,
+) -> Result<(u16, usize)>
+where
+    S: TcpStream,
+    F: FnMut(&[u8]) -> Result<()>,
+{
+    stream.set_timeout(request.timeout);
+    write_all(stream, request.head()?.as_bytes())?;
+    write_all(stream, request.body)?;
+
+    let mut filled = 0;
+    let head_len = loop {
+        if let Some(pos) = find(&buf[..filled], b"\r\n\r\n") {
+            break pos + 4;
+        }
+        filled += read_more(stream, buf, filled)?;
+    };
+    let head = parse_head(&buf[..head_len])?;
+    if !(200..300).contains(&head.status) || request.method == Method::Head {
+        return Ok((head.status, 0));
+    }
+    if head.chunked {
+        return Err(Error::Parse("http: chunked body not supported"));
+    }
+    //头后面多读的部分先交出去
+    let mut body = filled - head_len;
+    if let Some(len) = head.content_length {
+        body = body.min(len);
+    }
+    sink(&buf[head_len..head_len + body])?;
+    loop {
+        if Some(body) == head.content_length {
+            return Ok((head.status, body));
+        }
+        let want = match head.content_length {
+            Some(len) => buf.len().min(len - body),
+            None => buf.len(),
+        };
+        match stream.read(&mut buf[..want]) {
+            //没有长度时读到连接关闭
+            Ok(0) | Err(Error::EOF) if head.content_length.is_none() => {
+                return Ok((head.status, body))
+            }
+            Ok(0) => return Err(Error::EOF),
+            Err(err) => return Err(err),
+            Ok(len) => {
+                sink(&buf[..len])?;
+                body += len;
+            }
+        }
+    }
+}
+
+struct Head {
+    status: u16,
+    status_end: usize, //状态行的长度，不含\r\n
+    content_length: Option<usize>,
+    chunked: bool,
+}
+
+fn parse_head(buf: &[u8]) -> Result<Head> {
+    let text = core::str::from_utf8(buf).map_err(|_| Error::Parse("http: head not utf-8"))?;
+    let status_end = text
+        .find("\r\n")
+        .ok_or(Error::Parse("http: no status line"))?;
+    let mut parts = text[..status_end].splitn(3, ' ');
+    if !parts.next().unwrap_or("").starts_with("HTTP/1.") {
+        return Err(Error::Parse("http: bad version"));
+    }
+    let status = parts
+        .next()
+        .and_then(|code| code.parse().ok())
+        .ok_or(Error::Parse("http: bad status code"))?;
+    let mut head = Head {
+        status,
+        status_end,
+        content_length: None,
+        chunked: false,
+    };
+    for line in text[status_end..].split("\r\n") {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let len = value
+                .parse()
+                .map_err(|_| Error::Parse("http: bad Content-Length"))?;
+            head.content_length = Some(len);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            //最后一个编码是chunked时才按chunked读
+            let last = value.rsplit(',').next().unwrap_or("");
+            head.chunked = last.trim().eq_ignore_ascii_case("chunked");
+        }
+    }
+    Ok(head)
+}
+
+//在buf里原地解码chunked正文，buf开头已有filled字节，返回正文长度
+fn dechunk<S: TcpStream>(stream: &mut S, buf: &mut [u8], mut filled: usize) -> Result<usize> {
+    let mut body = 0; //已解码的正文末尾，也是下一个chunk头的位置
+    loop {
+        let line = read_line(stream, buf, &mut filled, body)?;
+        let size = line_str(&buf[body..body + line])?;
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| Error::Parse("http: bad chunk size"))?;
+        let start = body + line + 2;
+        if size == 0 {
+            //跳过trailer直到空行
+            let mut pos = start;
+            loop {
+                let line = read_line(stream, buf, &mut filled, pos)?;
+                if line == 0 {
+                    return Ok(body);
+                }
+                pos += line + 2;
+            }
+        }
+        //chunk长度由服务器给出，相加可能溢出
+        let end = start
+            .checked_add(size)
+            .and_then(|end| end.checked_add(2))
+            .filter(|end| *end <= buf.len())
+            .ok_or(Error::BufferFull)?;
+        while filled < end {
+            filled += read_more(stream, buf, filled)?;
+        }
+        if &buf[end - 2..end] != b"\r\n" {
+            return Err(Error::Parse("http: bad chunk"));
+        }
+        //正文前移盖住chunk头，后面多读的数据跟着前移
+        buf.copy_within(start..start + size, body);
+        body += size;
+        buf.copy_within(end..filled, body);
+        filled -= end - body;
+    }
+}
+
+//确保从pos开始有完整的一行，返回不含\r\n的长度
+fn read_line<S: TcpStream>(
+    stream: &mut S,
+    buf: &mut [u8],
+    filled: &mut usize,
+    pos: usize,
+) -> Result<usize> {
+    loop {
+        if let Some(len) = find(&buf[pos..*filled], b"\r\n") {
+            return Ok(len);
+        }
+        *filled += read_more(stream, buf, *filled)?;
+    }
+}
+
+fn line_str(line: &[u8]) -> Result<&str> {
+    core::str::from_utf8(line).map_err(|_| Error::Parse("http: bad chunk size"))
+}
+
+//读到buf[filled..]，缓冲区满时返回Error::BufferFull，连接关闭时返回Error::EOF
+fn read_more<S: TcpStream>(stream: &mut S, buf: &mut [u8], filled: usize) -> Result<usize> {
+    if filled == buf.len() {
+        return Err(Error::BufferFull);
+    }
+    match stream.read(&mut buf[filled..])? {
+        0 => Err(Error::EOF),
+        len => Ok(len),
+    }
+}
+
+fn write_all<S: TcpStream>(stream: &mut S, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match stream.write(buf)? {
+            0 => return Err(Error::WriteError),
+            len => buf = &buf[len..],
+        }
+    }
+    Ok(())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //每次最多读step字节，模拟应答分多次到达
+    struct Stream {
+        input: Vec<u8>,
+        pos: usize,
+        step: usize,
+        output: Vec<u8>,
+    }
+
+    impl Stream {
+        fn new(input: &str, step: usize) -> Self {
+            Self {
+                input: input.as_bytes().to_vec(),
+                pos: 0,
+                step,
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl TcpStream for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.pos == self.input.len() {
+                return Err(Error::EOF);
+            }
+            let len = buf.len().min(self.step).min(self.input.len() - self.pos);
+            buf[..len].copy_from_slice(&self.input[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn set_write_buffer(&mut self, _size: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_read_buffer(&mut self, _size: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, _milliseconds: u32) {}
+
+        fn close(self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    const STEPS: [usize; 4] = [1, 3, 7, 100];
+
+    #[test]
+    fn request_head() {
+        let request = Request::post_json("example.com", "/api", "{\"a\":1}");
+        assert_eq!(
+            request.head().unwrap(),
+            concat!(
+                "POST /api HTTP/1.1\r\n",
+                "Host: example.com\r\n",
+                "Content-Type: application/json\r\n",
+                "Content-Length: 7\r\n",
+                "Connection: close\r\n\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn header_injection() {
+        let mut stream = Stream::new("HTTP/1.1 200 OK\r\n\r\n", 100);
+        let mut buf = [0u8; 64];
+        let requests = [
+            Request::get("example.com", "/").header("X-Token", "a\r\nHost: evil.com"),
+            Request::get("example.com", "/").header("X-Token\n", "a"),
+            Request::get("example.com", "/ HTTP/1.1\r\n\r\nGET /admin"),
+        ];
+        for request in &requests {
+            assert!(matches!(request.head(), Err(Error::Other(_))));
+            assert!(send(&mut stream, request, &mut buf).is_err());
+            assert!(fetch(&mut stream, request, &mut buf, |_| Ok(())).is_err());
+        }
+        //什么都没有发出去
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn head_fields() {
+        let head = parse_head(
+            b"HTTP/1.1 200 OK\r\ncontent-length: 12\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!((head.status, head.status_end), (200, 15));
+        assert_eq!(head.content_length, Some(12));
+        assert!(head.chunked);
+        let head = parse_head(b"HTTP/1.0 404 Not Found\r\n\r\n").unwrap();
+        assert_eq!(
+            (head.status, head.content_length, head.chunked),
+            (404, None, false)
+        );
+
+        for bad in [
+            &b"HTTP/1.1 200 OK"[..],
+            b"SIP/2.0 200 OK\r\n\r\n",
+            b"HTTP/1.1 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            b"HTTP/1.1 200 \xff\r\n\r\n",
+        ] {
+            assert!(matches!(parse_head(bad), Err(Error::Parse(_))));
+        }
+    }
+
+    #[test]
+    fn length_delimited_body() {
+        let request = Request::post_json("example.com", "/api", "{\"a\":1}");
+        for step in STEPS {
+            let mut stream = Stream::new(
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-A: b\r\n\r\nhello",
+                step,
+            );
+            let mut buf = [0u8; 128];
+            let response = send(&mut stream, &request, &mut buf).unwrap();
+            assert_eq!((response.status, response.reason), (200, "OK"));
+            assert_eq!(
+                (response.body, response.header("x-a")),
+                (&b"hello"[..], Some("b"))
+            );
+            assert!(stream.output.ends_with(b"\r\n\r\n{\"a\":1}"));
+        }
+        //没有长度时读到连接关闭
+        let mut stream = Stream::new("HTTP/1.0 404 Not Found\r\n\r\ngone", 3);
+        let mut buf = [0u8; 128];
+        let response = send(&mut stream, &Request::get("h", "/"), &mut buf).unwrap();
+        assert_eq!((response.status, response.reason), (404, "Not Found"));
+        assert_eq!(response.body, b"gone");
+    }
+
+    #[test]
+    fn oversized_length() {
+        let mut buf = [0u8; 128];
+        let mut stream = Stream::new("HTTP/1.1 200 OK\r\nContent-Length: 500\r\n\r\nhello", 10);
+        assert!(matches!(
+            send(&mut stream, &Request::get("h", "/"), &mut buf),
+            Err(Error::BufferFull)
+        ));
+        //加上应答头的长度会溢出
+        let head = alloc::format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        let mut stream = Stream::new(&head, 10);
+        assert!(matches!(
+            send(&mut stream, &Request::get("h", "/"), &mut buf),
+            Err(Error::BufferFull)
+        ));
+    }
+
+    #[test]
+    fn chunked_body() {
+        let input = concat!(
+            "HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "4;x=y\r\nWiki\r\n6\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nT: 1\r\n\r\n"
+        );
+        for step in STEPS {
+            let mut stream = Stream::new(input, step);
+            let mut buf = [0u8; 128];
+            let response = send(&mut stream, &Request::get("h", "/"), &mut buf).unwrap();
+            assert_eq!(response.status, 201);
+            assert_eq!(response.body, b"Wikipedia in \r\n\r\nchunks.");
+        }
+
+        let mut buf = [0u8; 128];
+        let chunked = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for (body, error) in [
+            ("zz\r\n", "parse"),
+            ("4\r\nWikiXX0\r\n\r\n", "parse"),
+            ("400\r\n", "full"),
+            ("ffffffffffffffff\r\n", "full"),
+        ] {
+            let mut stream = Stream::new(&alloc::format!("{}{}", chunked, body), 7);
+            let result = send(&mut stream, &Request::get("h", "/"), &mut buf);
+            match error {
+                "parse" => assert!(matches!(result, Err(Error::Parse(_))), "{}", body),
+                _ => assert!(matches!(result, Err(Error::BufferFull)), "{}", body),
+            }
+        }
+    }
+
+    #[test]
+    fn no_body_statuses() {
+        for status in ["204 No Content", "304 Not Modified"] {
+            //就算带了长度和数据也不读正文
+            let input = alloc::format!("HTTP/1.1 {}\r\nContent-Length: 4\r\n\r\n", status);
+            let mut stream = Stream::new(&input, 5);
+            let mut buf = [0u8; 128];
+            let response = send(&mut stream, &Request::get("h", "/"), &mut buf).unwrap();
+            assert!(response.body.is_empty());
+        }
+        let mut stream = Stream::new("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n", 5);
+        let mut buf = [0u8; 128];
+        let request = Request::new(Method::Head, "h", "/");
+        let response = send(&mut stream, &request, &mut buf).unwrap();
+        assert_eq!((response.status, response.body), (200, &b""[..]));
+    }
+
+    #[test]
+    fn fetch_streams_body() {
+        for step in STEPS {
+            let body: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+            let mut stream = Stream::new("HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n", step);
+            stream.input.extend_from_slice(&body);
+            stream.input.extend_from_slice(b"junk");
+            let mut received = Vec::new();
+            let mut buf = [0u8; 64];
+            let result = fetch(&mut stream, &Request::get("h", "/fw"), &mut buf, |chunk| {
+                received.extend_from_slice(chunk);
+                Ok(())
+            });
+            assert_eq!(result.unwrap(), (200, 1000));
+            assert_eq!(received, body);
+
+            let mut stream = Stream::new(
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\ngone",
+                step,
+            );
+            let result = fetch(&mut stream, &Request::get("h", "/"), &mut buf, |_| panic!());
+            assert_eq!(result.unwrap(), (404, 0));
+            let mut stream =
+                Stream::new("HTTP/1.1 200 OK\r\nContent-Length: 40\r\n\r\nshort", step);
+            let result = fetch(&mut stream, &Request::get("h", "/"), &mut buf, |_| Ok(()));
+            assert!(matches!(result, Err(Error::EOF)));
+        }
+    }
+}
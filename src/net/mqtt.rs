@@ -0,0 +1,987 @@
+//! MQTT 3.1.1客户端
+//!
+//! 报文编解码只用调用者给的缓冲区，不分配内存；`Client`在任意`TcpStream`上
+//! 收发报文，QoS只支持0和1。没有系统时钟，心跳由调用者把毫秒数传给`poll`，
+//! 如`Esp8266::uptime`。断线后用`reconnect`换上新的TCP连接，
+//! 没收到PUBACK的QoS1报文会带DUP标志重发
+
+use super::TcpStream;
+use crate::io::{Error, Result};
+use alloc::string::String;
+
+//剩余长度最多4个字节
+const MAX_REMAINING: usize = 268_435_455;
+//等待PUBACK的QoS1报文最多个数
+const INFLIGHT: usize = 8;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn bits(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0,
+            QoS::AtLeastOnce => 1,
+        }
+    }
+}
+
+/// 遗嘱消息
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub message: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub keep_alive: u16, //秒，0表示不发心跳
+    pub clean_session: bool,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+    pub will: Option<Will<'a>>,
+}
+
+impl<'a> Connect<'a> {
+    pub fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            keep_alive: 60,
+            clean_session: true,
+            username: None,
+            password: None,
+            will: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Publish<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+    pub dup: bool,
+    pub id: Option<u16>, //QoS1才有
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Packet<'a> {
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish<'a>),
+    PubAck(u16),
+    SubAck { id: u16, codes: &'a [u8] }, //0x80表示订阅失败
+    UnsubAck(u16),
+    PingResp,
+}
+
+/////////////////////////////////////////////////////////////
+//编码，返回写入buf的字节数，放不下时返回Error::BufferFull
+
+pub fn encode_connect(buf: &mut [u8], connect: &Connect) -> Result<usize> {
+    let mut flags = 0u8;
+    let mut len = 10 + 2 + connect.client_id.len();
+    if connect.clean_session {
+        flags |= 0x02;
+    }
+    if let Some(will) = &connect.will {
+        flags |= 0x04 | will.qos.bits() << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+        len += 2 + will.topic.len() + 2 + will.message.len();
+    }
+    if let Some(username) = connect.username {
+        flags |= 0x80;
+        len += 2 + username.len();
+    }
+    if let Some(password) = connect.password {
+        flags |= 0x40;
+        len += 2 + password.len();
+    }
+    let mut w = Writer::new(buf);
+    w.header(CONNECT << 4, len)?;
+    w.string("MQTT")?;
+    w.u8(4)?; //协议级别3.1.1
+    w.u8(flags)?;
+    w.u16(connect.keep_alive)?;
+    w.string(connect.client_id)?;
+    if let Some(will) = &connect.will {
+        w.string(will.topic)?;
+        w.binary(will.message)?;
+    }
+    if let Some(username) = connect.username {
+        w.string(username)?;
+    }
+    if let Some(password) = connect.password {
+        w.binary(password)?;
+    }
+    Ok(w.pos)
+}
+
+pub fn encode_publish(buf: &mut [u8], publish: &Publish) -> Result<usize> {
+    let mut header = PUBLISH << 4 | publish.qos.bits() << 1;
+    if publish.dup {
+        header |= 0x08;
+    }
+    if publish.retain {
+        header |= 0x01;
+    }
+    let id = match (publish.qos, publish.id) {
+        (QoS::AtMostOnce, _) => None,
+        (QoS::AtLeastOnce, Some(id)) if id != 0 => Some(id),
+        _ => return Err(Error::Parse("mqtt: QoS1 needs a packet id")),
+    };
+    let len = 2 + publish.topic.len() + id.map_or(0, |_| 2) + publish.payload.len();
+    let mut w = Writer::new(buf);
+    w.header(header, len)?;
+    w.string(publish.topic)?;
+    if let Some(id) = id {
+        w.u16(id)?;
+    }
+    w.bytes(publish.payload)?;
+    Ok(w.pos)
+}
+
+pub fn encode_subscribe(buf: &mut [u8], id: u16, filters: &[(&str, QoS)]) -> Result<usize> {
+    let len = 2 + filters.iter().map(|(f, _)| 2 + f.len() + 1).sum::<usize>();
+    let mut w = Writer::new(buf);
+    w.header(SUBSCRIBE << 4 | 0x02, len)?;
+    w.u16(id)?;
+    for (filter, qos) in filters {
+        w.string(filter)?;
+        w.u8(qos.bits())?;
+    }
+    Ok(w.pos)
+}
+
+pub fn encode_unsubscribe(buf: &mut [u8], id: u16, filters: &[&str]) -> Result<usize> {
+    let len = 2 + filters.iter().map(|f| 2 + f.len()).sum::<usize>();
+    let mut w = Writer::new(buf);
+    w.header(UNSUBSCRIBE << 4 | 0x02, len)?;
+    w.u16(id)?;
+    for filter in filters {
+        w.string(filter)?;
+    }
+    Ok(w.pos)
+}
+
+pub fn encode_puback(buf: &mut [u8], id: u16) -> Result<usize> {
+    let mut w = Writer::new(buf);
+    w.header(PUBACK << 4, 2)?;
+    w.u16(id)?;
+    Ok(w.pos)
+}
+
+pub fn encode_pingreq(buf: &mut [u8]) -> Result<usize> {
+    let mut w = Writer::new(buf);
+    w.header(PINGREQ << 4, 0)?;
+    Ok(w.pos)
+}
+
+pub fn encode_disconnect(buf: &mut [u8]) -> Result<usize> {
+    let mut w = Writer::new(buf);
+    w.header(DISCONNECT << 4, 0)?;
+    Ok(w.pos)
+}
+
+/////////////////////////////////////////////////////////////
+//解码
+
+//buf开头是一个完整报文时返回(报文, 长度)，还不完整时返回None
+pub fn decode(buf: &[u8]) -> Result<Option<(Packet<'_>, usize)>> {
+    let (len, offset) = match remaining_length(buf)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if buf.len() < offset + len {
+        return Ok(None);
+    }
+    let header = buf[0];
+    let body = &buf[offset..offset + len];
+    let mut r = Reader { buf: body, pos: 0 };
+    let packet = match header >> 4 {
+        CONNACK => Packet::ConnAck {
+            session_present: r.u8()? & 0x01 != 0,
+            code: r.u8()?,
+        },
+        PUBLISH => {
+            let qos = match (header >> 1) & 0x03 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => return Err(Error::Parse("mqtt: unsupported QoS")),
+            };
+            let topic = r.string()?;
+            let id = match qos {
+                QoS::AtMostOnce => None,
+                QoS::AtLeastOnce => Some(r.u16()?),
+            };
+            Packet::Publish(Publish {
+                topic,
+                payload: r.rest(),
+                qos,
+                retain: header & 0x01 != 0,
+                dup: header & 0x08 != 0,
+                id,
+            })
+        }
+        PUBACK => Packet::PubAck(r.u16()?),
+        SUBACK => Packet::SubAck {
+            id: r.u16()?,
+            codes: r.rest(),
+        },
+        UNSUBACK => Packet::UnsubAck(r.u16()?),
+        PINGRESP => Packet::PingResp,
+        _ => return Err(Error::Parse("mqtt: unexpected packet type")),
+    };
+    Ok(Some((packet, offset + len)))
+}
+
+//返回(剩余长度, 固定报头长度)
+fn remaining_length(buf: &[u8]) -> Result<Option<(usize, usize)>> {
+    let mut value = 0usize;
+    for i in 0..4 {
+        let byte = match buf.get(1 + i) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, 2 + i)));
+        }
+    }
+    Err(Error::Parse("mqtt: bad remaining length"))
+}
+
+//buf开头的报文总长度，不管内容能否解码；还不完整时返回None
+fn packet_len(buf: &[u8]) -> Result<Option<usize>> {
+    Ok(remaining_length(buf)?
+        .map(|(len, offset)| offset + len)
+        .filter(|len| *len <= buf.len()))
+}
+
+//主题过滤器是否匹配主题，支持+和#通配符
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    //$开头的系统主题不匹配以通配符开头的过滤器
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(t)) if level == t => {}
+            _ => return false,
+        }
+    }
+    topic.next().is_none()
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn header(&mut self, header: u8, mut len: usize) -> Result<()> {
+        if len > MAX_REMAINING {
+            return Err(Error::BufferFull);
+        }
+        self.u8(header)?;
+        loop {
+            let mut byte = (len & 0x7f) as u8;
+            len >>= 7;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            self.u8(byte)?;
+            if len == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn u8(&mut self, b: u8) -> Result<()> {
+        self.bytes(&[b])
+    }
+
+    fn u16(&mut self, v: u16) -> Result<()> {
+        self.bytes(&v.to_be_bytes())
+    }
+
+    fn string(&mut self, s: &str) -> Result<()> {
+        self.binary(s.as_bytes())
+    }
+
+    fn binary(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > u16::MAX as usize {
+            return Err(Error::BufferFull);
+        }
+        self.u16(data.len() as u16)?;
+        self.bytes(data)
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<()> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            return Err(Error::BufferFull);
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8]> {
+        let data = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(Error::Parse("mqtt: truncated packet"))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn string(&mut self) -> Result<&'b str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.take(len)?).map_err(|_| Error::Parse("mqtt: topic not utf-8"))
+    }
+
+    fn rest(&mut self) -> &'b [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+}
+
+/////////////////////////////////////////////////////////////
+//客户端
+
+/// N是收发缓冲区各自的大小，决定了能收发的最大报文；
+/// 另有INFLIGHT个N字节的槽保存待确认的QoS1报文，不占堆
+pub struct Client<S, const N: usize> {
+    stream: S,
+    tx: [u8; N],
+    rx: [u8; N],
+    filled: usize,
+    consumed: usize, //上次poll返回的PUBLISH，下次poll时丢弃
+    keep_alive: u32, //毫秒
+    last_sent: u32,
+    ping_sent: Option<u32>,
+    next_id: u16,
+    inflight: heapless::Vec<(u16, heapless::Vec<u8, N>), INFLIGHT>, //(报文ID, 编码好的PUBLISH)
+    timeout: u32,
+    broken: bool, //收到的数据找不到报文边界，只能重新连接
+}
+
+impl<S: TcpStream, const N: usize> Client<S, N> {
+    //在已建立的TCP连接上发送CONNECT并等待CONNACK，now为当前毫秒数
+    pub fn connect(stream: S, connect: &Connect, now: u32) -> Result<Self> {
+        let mut client = Self {
+            stream,
+            tx: [0; N],
+            rx: [0; N],
+            filled: 0,
+            consumed: 0,
+            keep_alive: 0,
+            last_sent: now,
+            ping_sent: None,
+            next_id: 0,
+            inflight: heapless::Vec::new(),
+            timeout: 5000,
+            broken: false,
+        };
+        client.handshake(connect, now)?;
+        Ok(client)
+    }
+
+    //断线后在新的TCP连接上重新发送CONNECT，交还旧连接；
+    //成功后把还没收到PUBACK的QoS1报文带DUP标志按原顺序重发
+    pub fn reconnect(&mut self, stream: S, connect: &Connect, now: u32) -> Result<S> {
+        let old = core::mem::replace(&mut self.stream, stream);
+        self.filled = 0;
+        self.consumed = 0;
+        self.ping_sent = None;
+        self.broken = false;
+        self.handshake(connect, now)?;
+        for i in 0..self.inflight.len() {
+            let len = self.inflight[i].1.len();
+            self.tx[..len].copy_from_slice(&self.inflight[i].1);
+            self.tx[0] |= 0x08;
+            self.transmit(len, now)?;
+        }
+        Ok(old)
+    }
+
+    //发送CONNECT并等待CONNACK
+    fn handshake(&mut self, connect: &Connect, now: u32) -> Result<()> {
+        self.keep_alive = connect.keep_alive as u32 * 1000;
+        let len = encode_connect(&mut self.tx, connect)?;
+        self.transmit(len, now)?;
+        self.stream.set_timeout(self.timeout);
+        loop {
+            match decode(&self.rx[..self.filled])? {
+                Some((Packet::ConnAck { code: 0, .. }, len)) => {
+                    self.consume(len);
+                    return Ok(());
+                }
+                Some((Packet::ConnAck { code, .. }, _)) => return Err(refused(code)),
+                Some(_) => return Err(Error::Parse("mqtt: expected CONNACK")),
+                None => self.fill()?,
+            }
+        }
+    }
+
+    //poll每次最多等待的毫秒数
+    pub fn set_timeout(&mut self, milliseconds: u32) {
+        self.timeout = milliseconds;
+    }
+
+    //QoS1返回报文ID，收到PUBACK前is_pending为true
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+        now: u32,
+    ) -> Result<Option<u16>> {
+        let id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => {
+                if self.inflight.is_full() {
+                    return Err(Error::DeviceBusy);
+                }
+                Some(self.packet_id())
+            }
+        };
+        let publish = Publish {
+            topic,
+            payload,
+            qos,
+            retain,
+            dup: false,
+            id,
+        };
+        let len = encode_publish(&mut self.tx, &publish)?;
+        //先记下来，发送失败时重连后也会重发
+        if let Some(id) = id {
+            //报文是在N字节的tx里编码的，总放得下
+            if let Ok(packet) = heapless::Vec::from_slice(&self.tx[..len]) {
+                self.inflight.push((id, packet)).ok();
+            }
+        }
+        self.transmit(len, now)?;
+        Ok(id)
+    }
+
+    pub fn is_pending(&self, id: u16) -> bool {
+        self.inflight.iter().any(|(i, _)| *i == id)
+    }
+
+    //SUBACK在poll中处理，订阅被拒绝时poll返回错误
+    pub fn subscribe(&mut self, filters: &[(&str, QoS)], now: u32) -> Result<u16> {
+        let id = self.packet_id();
+        let len = encode_subscribe(&mut self.tx, id, filters)?;
+        self.transmit(len, now)?;
+        Ok(id)
+    }
+
+    pub fn unsubscribe(&mut self, filters: &[&str], now: u32) -> Result<u16> {
+        let id = self.packet_id();
+        let len = encode_unsubscribe(&mut self.tx, id, filters)?;
+        self.transmit(len, now)?;
+        Ok(id)
+    }
+
+    //处理收到的报文并按需发送心跳，收到PUBLISH时返回它(QoS1已自动回复PUBACK)；
+    //超过keep_alive没有收到PINGRESP返回Error::Timeout，应重新连接。
+    //解不开的报文(如QoS2的PUBLISH)丢掉后返回Error::Parse，可以继续poll；
+    //剩余长度错误、报文超过缓冲区或者读出错时连接不能再用，之后一直返回Error::EOF
+    pub fn poll(&mut self, now: u32) -> Result<Option<Publish<'_>>> {
+        if self.broken {
+            return Err(Error::EOF);
+        }
+        if self.consumed > 0 {
+            let len = self.consumed;
+            self.consumed = 0;
+            self.consume(len);
+        }
+        self.heartbeat(now)?;
+        self.stream.set_timeout(self.timeout);
+        let len = loop {
+            let (id, len) = match decode(&self.rx[..self.filled]) {
+                Ok(Some((Packet::Publish(publish), len))) => (publish.id, len),
+                Ok(Some((packet, len))) => {
+                    let control = Control::from(&packet);
+                    self.consume(len);
+                    self.handle(control)?;
+                    continue;
+                }
+                Ok(None) => match self.fill() {
+                    Ok(()) => continue,
+                    Err(Error::Timeout) => return Ok(None),
+                    Err(err) => {
+                        self.broken = true;
+                        return Err(err);
+                    }
+                },
+                Err(err) => {
+                    match packet_len(&self.rx[..self.filled]) {
+                        Ok(Some(len)) => self.consume(len),
+                        //找不到下一个报文的开头
+                        _ => {
+                            self.filled = 0;
+                            self.broken = true;
+                        }
+                    }
+                    return Err(err);
+                }
+            };
+            if let Some(id) = id {
+                let ack = encode_puback(&mut self.tx, id)?;
+                self.transmit(ack, now)?;
+            }
+            break len;
+        };
+        self.consumed = len;
+        match decode(&self.rx[..len])? {
+            Some((Packet::Publish(publish), _)) => Ok(Some(publish)),
+            _ => Ok(None),
+        }
+    }
+
+    //发送DISCONNECT并交还TCP连接
+    pub fn disconnect(mut self) -> Result<S> {
+        let len = encode_disconnect(&mut self.tx)?;
+        self.transmit(len, self.last_sent)?;
+        Ok(self.stream)
+    }
+
+    fn handle(&mut self, control: Control) -> Result<()> {
+        match control {
+            Control::PubAck(id) => self.inflight.retain(|(i, _)| *i != id),
+            Control::Refused => return Err(Error::Other(String::from("SUBSCRIBE refused"))),
+            Control::PingResp => self.ping_sent = None,
+            Control::Ignore => {}
+        }
+        Ok(())
+    }
+
+    fn heartbeat(&mut self, now: u32) -> Result<()> {
+        if self.keep_alive == 0 {
+            return Ok(());
+        }
+        if let Some(sent) = self.ping_sent {
+            if now.wrapping_sub(sent) >= self.keep_alive {
+                return Err(Error::Timeout);
+            }
+        } else if now.wrapping_sub(self.last_sent) >= self.keep_alive {
+            let len = encode_pingreq(&mut self.tx)?;
+            self.transmit(len, now)?;
+            self.ping_sent = Some(now);
+        }
+        Ok(())
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.next_id
+    }
+
+    fn transmit(&mut self, len: usize, now: u32) -> Result<()> {
+        let mut buf = &self.tx[..len];
+        while !buf.is_empty() {
+            match self.stream.write(buf)? {
+                0 => return Err(Error::WriteError),
+                n => buf = &buf[n..],
+            }
+        }
+        self.last_sent = now;
+        Ok(())
+    }
+
+    //缓冲区满了还拼不出完整报文时返回Error::BufferFull
+    fn fill(&mut self) -> Result<()> {
+        if self.filled == N {
+            return Err(Error::BufferFull);
+        }
+        match self.stream.read(&mut self.rx[self.filled..])? {
+            0 => Err(Error::EOF),
+            n => {
+                self.filled += n;
+                Ok(())
+            }
+        }
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.rx.copy_within(len..self.filled, 0);
+        self.filled -= len;
+    }
+}
+
+//除PUBLISH以外收到的报文，不借用接收缓冲区
+enum Control {
+    PubAck(u16),
+    Refused,
+    PingResp,
+    Ignore,
+}
+
+impl From<&Packet<'_>> for Control {
+    fn from(packet: &Packet) -> Self {
+        match packet {
+            Packet::PubAck(id) => Control::PubAck(*id),
+            Packet::SubAck { codes, .. } if codes.contains(&0x80) => Control::Refused,
+            Packet::PingResp => Control::PingResp,
+            _ => Control::Ignore,
+        }
+    }
+}
+
+fn refused(code: u8) -> Error {
+    let reason = match code {
+        1 => "CONNACK unacceptable protocol version",
+        2 => "CONNACK identifier rejected",
+        3 => "CONNACK server unavailable",
+        4 => "CONNACK bad user name or password",
+        5 => "CONNACK not authorized",
+        _ => "CONNACK refused",
+    };
+    Error::Other(String::from(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    //每次最多读3个字节，读完后超时
+    struct Stream {
+        input: Vec<u8>,
+        pos: usize,
+        output: Vec<u8>,
+    }
+
+    impl Stream {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: input.to_vec(),
+                pos: 0,
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl TcpStream for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.pos == self.input.len() {
+                return Err(Error::Timeout);
+            }
+            let len = buf.len().min(3).min(self.input.len() - self.pos);
+            buf[..len].copy_from_slice(&self.input[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn set_write_buffer(&mut self, _size: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_read_buffer(&mut self, _size: usize) -> Result<()> {
+            Ok(())
+        }
+
+        fn set_timeout(&mut self, _milliseconds: u32) {}
+
+        fn close(self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    const CONNACK_OK: &[u8] = b"\x20\x02\x00\x00";
+
+    fn client(input: &[u8]) -> Client<Stream, 64> {
+        let mut stream = CONNACK_OK.to_vec();
+        stream.extend_from_slice(input);
+        Client::connect(Stream::new(&stream), &Connect::new("x"), 0).unwrap()
+    }
+
+    #[test]
+    fn encode_packets() {
+        let mut buf = [0u8; 64];
+        let mut connect = Connect::new("c1");
+        connect.keep_alive = 10;
+        connect.username = Some("u");
+        connect.password = Some(b"p");
+        let len = encode_connect(&mut buf, &connect).unwrap();
+        assert_eq!(
+            &buf[..len],
+            b"\x10\x14\x00\x04MQTT\x04\xc2\x00\x0a\x00\x02c1\x00\x01u\x00\x01p"
+        );
+        connect.will = Some(Will {
+            topic: "w",
+            message: b"bye",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        });
+        let len = encode_connect(&mut buf, &connect).unwrap();
+        assert_eq!(buf[9], 0xc2 | 0x04 | 0x08 | 0x20);
+        assert_eq!(&buf[12..len - 6], b"\x00\x02c1\x00\x01w\x00\x03bye");
+
+        let len = encode_subscribe(&mut buf, 1, &[("a/+", QoS::AtLeastOnce)]).unwrap();
+        assert_eq!(&buf[..len], b"\x82\x08\x00\x01\x00\x03a/+\x01");
+        let len = encode_unsubscribe(&mut buf, 2, &["a/+"]).unwrap();
+        assert_eq!(&buf[..len], b"\xa2\x07\x00\x02\x00\x03a/+");
+        let len = encode_puback(&mut buf, 0x1234).unwrap();
+        assert_eq!(&buf[..len], b"\x40\x02\x12\x34");
+        let len = encode_pingreq(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\xc0\x00");
+        let len = encode_disconnect(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\xe0\x00");
+
+        let publish = Publish {
+            topic: "t",
+            payload: b"x",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            dup: true,
+            id: Some(7),
+        };
+        let len = encode_publish(&mut buf, &publish).unwrap();
+        assert_eq!(&buf[..len], b"\x3b\x06\x00\x01t\x00\x07x");
+        let no_id = Publish {
+            id: None,
+            ..publish
+        };
+        assert!(matches!(
+            encode_publish(&mut buf, &no_id),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            encode_publish(&mut buf[..5], &publish),
+            Err(Error::BufferFull)
+        ));
+    }
+
+    #[test]
+    fn decode_packets() {
+        //剩余长度200需要两个字节
+        let mut buf = [0u8; 300];
+        let payload = [7u8; 200];
+        let publish = Publish {
+            topic: "t",
+            payload: &payload,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            dup: false,
+            id: None,
+        };
+        let len = encode_publish(&mut buf, &publish).unwrap();
+        assert_eq!(&buf[..3], b"\x30\xcb\x01");
+        assert_eq!(
+            decode(&buf[..len]).unwrap(),
+            Some((Packet::Publish(publish), len))
+        );
+        assert_eq!(decode(&buf[..len - 1]).unwrap(), None);
+        assert_eq!(decode(&buf[..2]).unwrap(), None);
+
+        let qos1 = decode(b"\x32\x07\x00\x01x\x00\x05hi").unwrap().unwrap().0;
+        assert!(matches!(
+            qos1,
+            Packet::Publish(Publish {
+                topic: "x",
+                payload: b"hi",
+                id: Some(5),
+                ..
+            })
+        ));
+        assert_eq!(
+            decode(b"\x20\x02\x01\x05").unwrap(),
+            Some((
+                Packet::ConnAck {
+                    session_present: true,
+                    code: 5
+                },
+                4
+            ))
+        );
+        assert_eq!(
+            decode(b"\x40\x02\x00\x01").unwrap(),
+            Some((Packet::PubAck(1), 4))
+        );
+        assert_eq!(
+            decode(b"\x90\x04\x00\x02\x01\x80").unwrap(),
+            Some((
+                Packet::SubAck {
+                    id: 2,
+                    codes: b"\x01\x80"
+                },
+                6
+            ))
+        );
+        assert_eq!(
+            decode(b"\xb0\x02\x00\x03").unwrap(),
+            Some((Packet::UnsubAck(3), 4))
+        );
+        assert_eq!(decode(b"\xd0\x00").unwrap(), Some((Packet::PingResp, 2)));
+
+        //QoS2、不认识的类型、字段不完整、剩余长度超过4字节
+        for bad in [
+            &b"\x34\x05\x00\x01x\x00\x01"[..],
+            b"\xf0\x00",
+            b"\x40\x01\x00",
+            b"\x30\x04\x00\x09ab",
+            b"\x30\xff\xff\xff\xff\x01",
+        ] {
+            assert!(matches!(decode(bad), Err(Error::Parse(_))), "{:x?}", bad);
+        }
+    }
+
+    #[test]
+    fn topic_filters() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("#", "x/y"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(!topic_matches("a/b", "a"));
+        assert!(!topic_matches("+/x", "$SYS/x"));
+    }
+
+    #[test]
+    fn client_session() {
+        let mut c = client(
+            b"\x40\x02\x00\x01\x90\x03\x00\x02\x01\x32\x07\x00\x01x\x00\x05hi\xd0\x00\x30\x04\x00\x01ab",
+        );
+        assert_eq!(
+            c.publish("t", b"1", QoS::AtLeastOnce, false, 0).unwrap(),
+            Some(1)
+        );
+        assert!(c.is_pending(1));
+        c.subscribe(&[("t", QoS::AtLeastOnce)], 0).unwrap();
+        let publish = c.poll(0).unwrap().unwrap();
+        assert_eq!(
+            (publish.topic, publish.payload, publish.id),
+            ("x", &b"hi"[..], Some(5))
+        );
+        let publish = c.poll(0).unwrap().unwrap();
+        assert_eq!((publish.topic, publish.payload), ("a", &b"b"[..]));
+        assert!(!c.is_pending(1));
+        assert!(c.poll(0).unwrap().is_none());
+        //一个keep_alive没发东西就发PINGREQ，再过一个还没有PINGRESP就超时
+        assert!(c.poll(60_000).unwrap().is_none());
+        assert!(matches!(c.poll(120_001), Err(Error::Timeout)));
+        let stream = c.disconnect().unwrap();
+        assert!(
+            stream.output.ends_with(b"\x40\x02\x00\x05\xc0\x00\xe0\x00"),
+            "{:x?}",
+            stream.output
+        );
+    }
+
+    #[test]
+    fn refused_connection() {
+        let stream = Stream::new(b"\x20\x02\x00\x05");
+        let result = Client::<_, 64>::connect(stream, &Connect::new("x"), 0);
+        assert!(matches!(result, Err(Error::Other(_))));
+    }
+
+    #[test]
+    fn undecodable_packets_are_skipped() {
+        //QoS2的PUBLISH和不认识的报文丢掉，后面的报文照常处理
+        let mut c = client(b"\x34\x05\x00\x01x\x00\x01\xf0\x00\x30\x04\x00\x01ab");
+        assert!(matches!(c.poll(0), Err(Error::Parse(_))));
+        assert!(matches!(c.poll(0), Err(Error::Parse(_))));
+        let publish = c.poll(0).unwrap().unwrap();
+        assert_eq!((publish.topic, publish.payload), ("a", &b"b"[..]));
+        assert!(c.poll(0).unwrap().is_none());
+    }
+
+    #[test]
+    fn broken_stream() {
+        let mut c = client(b"\x30\xff\xff\xff\xff\x01\x30\x04\x00\x01ab");
+        assert!(matches!(c.poll(0), Err(Error::Parse(_))));
+        assert!(matches!(c.poll(0), Err(Error::EOF)));
+
+        //报文比接收缓冲区大，永远收不完
+        let mut c = client(&[0x30, 100]);
+        c.stream.input.extend_from_slice(&[0u8; 100]);
+        assert!(matches!(c.poll(0), Err(Error::BufferFull)));
+        assert!(matches!(c.poll(0), Err(Error::EOF)));
+    }
+
+    #[test]
+    fn reconnect_resends_inflight() {
+        let mut c = client(b"");
+        assert_eq!(
+            c.publish("t", b"1", QoS::AtLeastOnce, false, 0).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            c.publish("u", b"2", QoS::AtLeastOnce, true, 0).unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            c.publish("v", b"3", QoS::AtMostOnce, false, 0).unwrap(),
+            None
+        );
+
+        let stream = Stream::new(b"\x20\x02\x01\x00\x40\x02\x00\x01");
+        let old = c.reconnect(stream, &Connect::new("x"), 10).unwrap();
+        assert!(old.output.ends_with(b"\x30\x04\x00\x01v3"));
+        //CONNECT之后按原顺序带DUP重发，QoS0的不重发
+        let output = &c.stream.output;
+        let connect = output.len() - 2 * 8;
+        assert_eq!(output[0], CONNECT << 4);
+        assert_eq!(
+            &output[connect..],
+            b"\x3a\x06\x00\x01t\x00\x011\x3b\x06\x00\x01u\x00\x022"
+        );
+        assert!(c.poll(10).unwrap().is_none());
+        assert!(!c.is_pending(1));
+        assert!(c.is_pending(2));
+    }
+}
@@ -0,0 +1,71 @@
+//! ESP8266透传模式
+//!
+//! 透传期间串口上只有连接的数据，Passthrough按字节读写；
+//! 它持有设备的可变借用，退出透传之前不能再发AT指令
+
+use super::esp826601s::Esp8266;
+use crate::hal::time::Hertz;
+use crate::io::{Error, Result};
+use embedded_hal::serial::{Read, Write};
+
+pub struct Passthrough<'a, T, TIM>
+where
+    T: Read<u8> + Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    esp: &'a mut Esp8266<T, TIM>,
+}
+
+impl<'a, T, TIM> Passthrough<'a, T, TIM>
+where
+    T: Read<u8> + Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    pub(crate) fn new(esp: &'a mut Esp8266<T, TIM>) -> Self {
+        Self { esp }
+    }
+
+    //退出透传模式，连接保持打开
+    pub fn exit(self) -> Result<()> {
+        self.esp.exit_passthrough()
+    }
+}
+
+//未调用exit就丢弃时也尝试退出透传
+impl<'a, T, TIM> Drop for Passthrough<'a, T, TIM>
+where
+    T: Read<u8> + Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    fn drop(&mut self) {
+        self.esp.exit_passthrough().ok();
+    }
+}
+
+impl<'a, T, TIM> Read<u8> for Passthrough<'a, T, TIM>
+where
+    T: Read<u8> + Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        self.esp.read_raw()
+    }
+}
+
+impl<'a, T, TIM> Write<u8> for Passthrough<'a, T, TIM>
+where
+    T: Read<u8> + Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    type Error = Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        self.esp.write_raw(word)
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.esp.flush_raw()
+    }
+}
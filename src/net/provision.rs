@@ -0,0 +1,498 @@
+//! WIFI配网
+//!
+//! 首次上电(没有保存过WIFI)或按住按键上电时，ESP8266开热点并在80端口提供一个网页表单，
+//! 手机连上热点打开http://192.168.4.1 填写SSID和密码，以站点模式连上WIFI后保存账号。
+//! 账号通过`CredentialStore`保存，`KvStore`已经实现了它，存在flash里掉电不丢。
+//! 表单请求的解析不碰串口，可以在主机上测试
+
+use super::at::Encryption;
+use super::esp826601s::{Esp8266, Link, WifiMode};
+use crate::hal::time::Hertz;
+use crate::io::{Error, Result};
+use crate::storage::{Flash, KvStore};
+use heapless::String;
+
+//SSID最长32字节，WPA密码最长64字节
+pub type Ssid = String<32>;
+pub type Password = String<64>;
+
+//一个请求(头和正文)最多的字节数，也是接收缓冲区的大小
+const MAX_REQUEST: usize = 512;
+
+const FORM: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width\"><title>WIFI</title></head><body>\
+<form method=\"post\" action=\"/\">\
+<p>SSID<br><input name=\"ssid\" maxlength=\"32\"></p>\
+<p>Password<br><input name=\"password\" type=\"password\" maxlength=\"64\"></p>\
+<p><input type=\"submit\" value=\"Save\"></p></form></body></html>";
+
+const SAVED: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>WIFI</title></head>\
+<body><p>Saved, connecting...</p></body></html>";
+
+/// 保存的WIFI账号
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub ssid: Ssid,
+    pub password: Password,
+}
+
+/// 账号保存在哪里由应用决定，通常是flash
+pub trait CredentialStore {
+    fn load(&mut self) -> Option<Credentials>;
+    fn save(&mut self, credentials: &Credentials) -> Result<()>;
+}
+
+//账号保存在键值存储的wifi.ssid和wifi.password里
+impl<F: Flash> CredentialStore for KvStore<F> {
+    fn load(&mut self) -> Option<Credentials> {
+        let mut ssid = [0u8; 32];
+        let mut password = [0u8; 64];
+        let ssid_len = self.get_bytes("wifi.ssid", &mut ssid).ok()??;
+        let password_len = self.get_bytes("wifi.password", &mut password).ok()??;
+        let mut credentials = Credentials {
+            ssid: String::new(),
+            password: String::new(),
+        };
+        credentials
+            .ssid
+            .push_str(core::str::from_utf8(&ssid[..ssid_len]).ok()?)
+            .ok()?;
+        credentials
+            .password
+            .push_str(core::str::from_utf8(&password[..password_len]).ok()?)
+            .ok()?;
+        Some(credentials)
+    }
+
+    fn save(&mut self, credentials: &Credentials) -> Result<()> {
+        self.set_bytes("wifi.ssid", credentials.ssid.as_bytes())?;
+        self.set_bytes("wifi.password", credentials.password.as_bytes())
+    }
+}
+
+/// 浏览器发来的请求
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormRequest {
+    Form,                  //GET /，返回表单
+    Submit(Credentials),   //POST /，提交的账号
+    Invalid(&'static str), //表单不完整，重新显示表单
+    NotFound,
+}
+
+//buf是目前收到的数据，请求还不完整时返回None；超过MAX_REQUEST的请求返回Error::Parse
+pub fn parse_request(buf: &[u8]) -> Result<Option<FormRequest>> {
+    let head_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None => return Ok(None),
+    };
+    let head =
+        core::str::from_utf8(&buf[..head_len]).map_err(|_| Error::Parse("http: head not utf-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line
+        .next()
+        .ok_or(Error::Parse("http: bad request line"))?;
+    let path = path.split('?').next().unwrap_or(path);
+    let mut content_length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Error::Parse("http: bad Content-Length"))?;
+            }
+        }
+    }
+    if path != "/" {
+        return Ok(Some(FormRequest::NotFound));
+    }
+    match method {
+        "GET" | "HEAD" => Ok(Some(FormRequest::Form)),
+        "POST" => {
+            //Content-Length由浏览器给出，相加可能溢出
+            let end = head_len
+                .checked_add(content_length)
+                .filter(|end| *end <= MAX_REQUEST)
+                .ok_or(Error::Parse("http: request too large"))?;
+            if buf.len() < end {
+                return Ok(None);
+            }
+            let body = &buf[head_len..end];
+            Ok(Some(parse_form(body)?))
+        }
+        _ => Ok(Some(FormRequest::NotFound)),
+    }
+}
+
+//application/x-www-form-urlencoded: ssid=...&password=...
+pub fn parse_form(body: &[u8]) -> Result<FormRequest> {
+    let body = core::str::from_utf8(body).map_err(|_| Error::Parse("form: not utf-8"))?;
+    let mut ssid = None;
+    let mut password = None;
+    for pair in body.split('&') {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "ssid" => ssid = Some(decode::<32>(value)?),
+            "password" => password = Some(decode::<64>(value)?),
+            _ => {}
+        }
+    }
+    let ssid = match ssid {
+        Some(ssid) if !ssid.is_empty() => ssid,
+        _ => return Ok(FormRequest::Invalid("SSID is required")),
+    };
+    let password = password.unwrap_or_default();
+    //WPA密码8~63个字符或64位十六进制，空表示开放网络
+    if !password.is_empty() && password.len() < 8 {
+        return Ok(FormRequest::Invalid("password too short"));
+    }
+    if password.len() > 63 && !password.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(FormRequest::Invalid("password too long"));
+    }
+    Ok(FormRequest::Submit(Credentials { ssid, password }))
+}
+
+//URL解码，+是空格，%XX是一个字节
+fn decode<const N: usize>(value: &str) -> Result<String<N>> {
+    let mut bytes = heapless::Vec::<u8, N>::new();
+    let mut input = value.bytes();
+    while let Some(b) = input.next() {
+        let b = match b {
+            b'+' => b' ',
+            b'%' => {
+                let hi = input.next().and_then(hex);
+                let lo = input.next().and_then(hex);
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => hi << 4 | lo,
+                    _ => return Err(Error::Parse("form: bad escape")),
+                }
+            }
+            b => b,
+        };
+        bytes
+            .push(b)
+            .map_err(|_| Error::Parse("form: value too long"))?;
+    }
+    let text = core::str::from_utf8(&bytes).map_err(|_| Error::Parse("form: not utf-8"))?;
+    let mut s = String::new();
+    s.push_str(text).ok();
+    Ok(s)
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// 配网流程
+pub struct Provisioner<'a> {
+    ap_ssid: &'a str,
+    ap_password: &'a str,
+    channel: u8,
+    timeout: u32,
+}
+
+impl<'a> Provisioner<'a> {
+    //ap_password为空时热点不加密，否则至少8个字符
+    pub fn new(ap_ssid: &'a str, ap_password: &'a str) -> Self {
+        Self {
+            ap_ssid,
+            ap_password,
+            channel: 5,
+            timeout: 300_000,
+        }
+    }
+
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    //等待提交的最长毫秒数，超时返回Error::Timeout
+    pub fn timeout(mut self, milliseconds: u32) -> Self {
+        self.timeout = milliseconds;
+        self
+    }
+
+    //按住按键或没有保存过账号时配网，否则用保存的账号连接
+    pub fn connect<T, TIM, S>(
+        &self,
+        esp: &mut Esp8266<T, TIM>,
+        store: &mut S,
+        button_held: bool,
+    ) -> Result<Credentials>
+    where
+        T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+        TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+        S: CredentialStore,
+    {
+        if !button_held {
+            if let Some(credentials) = store.load() {
+                esp.dial(&credentials.ssid, &credentials.password, true)?;
+                return Ok(credentials);
+            }
+        }
+        self.run(esp, store)
+    }
+
+    //开热点等待提交，连上WIFI后保存账号；连接失败时回到表单
+    pub fn run<T, TIM, S>(&self, esp: &mut Esp8266<T, TIM>, store: &mut S) -> Result<Credentials>
+    where
+        T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+        TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+        S: CredentialStore,
+    {
+        let encryption = if self.ap_password.is_empty() {
+            Encryption::Open
+        } else {
+            Encryption::Wpa2Psk
+        };
+        esp.set_mode(WifiMode::Both)?;
+        esp.soft_ap(self.ap_ssid, self.ap_password, self.channel, encryption)?;
+        esp.listen(80)?;
+        let deadline = esp.uptime().wrapping_add(self.timeout);
+        let result = loop {
+            if (esp.uptime().wrapping_sub(deadline) as i32) >= 0 {
+                break Err(Error::Timeout);
+            }
+            let link = match esp.accept(1000) {
+                Ok(link) => link,
+                Err(Error::Timeout) => continue,
+                Err(err) => break Err(err),
+            };
+            let credentials = match serve(esp, &link) {
+                Ok(Some(credentials)) => credentials,
+                _ => {
+                    esp.close(link).ok();
+                    continue;
+                }
+            };
+            esp.close(link).ok();
+            match esp.dial(&credentials.ssid, &credentials.password, true) {
+                Ok(_) => match store.save(&credentials) {
+                    Ok(_) => break Ok(credentials),
+                    Err(err) => break Err(err),
+                },
+                //密码错误等，继续等待提交
+                Err(Error::Join(_)) => continue,
+                Err(err) => break Err(err),
+            }
+        };
+        esp.unlisten().ok();
+        if result.is_ok() {
+            esp.set_mode(WifiMode::Station)?;
+        }
+        result
+    }
+}
+
+//处理一个浏览器连接，收到有效的提交时返回账号
+fn serve<T, TIM>(esp: &mut Esp8266<T, TIM>, link: &Link) -> Result<Option<Credentials>>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    let mut buf = [0u8; MAX_REQUEST];
+    let mut filled = 0;
+    let request = loop {
+        if let Some(request) = parse_request(&buf[..filled])? {
+            break request;
+        }
+        if filled == buf.len() {
+            return Err(Error::BufferFull);
+        }
+        filled += esp.recv(link, &mut buf[filled..], 5000)?;
+    };
+    match request {
+        FormRequest::Form | FormRequest::Invalid(_) => {
+            respond(esp, link, "200 OK", FORM)?;
+            Ok(None)
+        }
+        FormRequest::Submit(credentials) => {
+            respond(esp, link, "200 OK", SAVED)?;
+            Ok(Some(credentials))
+        }
+        FormRequest::NotFound => {
+            //手机检测强制门户时也会访问其它路径，重定向到表单
+            let head = "HTTP/1.1 302 Found\r\nLocation: http://192.168.4.1/\r\n\
+                        Content-Length: 0\r\nConnection: close\r\n\r\n";
+            esp.send(link, head.as_bytes(), 5000)?;
+            Ok(None)
+        }
+    }
+}
+
+fn respond<T, TIM>(esp: &mut Esp8266<T, TIM>, link: &Link, status: &str, html: &str) -> Result<()>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    let head = alloc::format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        html.len()
+    );
+    esp.send(link, head.as_bytes(), 5000)?;
+    esp.send(link, html.as_bytes(), 5000)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Port, Timer};
+    use crate::storage::SimFlash;
+
+    fn submitted(request: Option<FormRequest>) -> Credentials {
+        match request {
+            Some(FormRequest::Submit(credentials)) => credentials,
+            other => panic!("expected a submission, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn get_form() {
+        let get = b"GET / HTTP/1.1\r\nHost: 192.168.4.1\r\n\r\n";
+        assert_eq!(parse_request(get).unwrap(), Some(FormRequest::Form));
+        assert_eq!(parse_request(&get[..get.len() - 2]).unwrap(), None);
+        assert_eq!(
+            parse_request(b"GET /?x=1 HTTP/1.1\r\n\r\n").unwrap(),
+            Some(FormRequest::Form)
+        );
+        //手机检测强制门户
+        assert_eq!(
+            parse_request(b"GET /generate_204 HTTP/1.1\r\n\r\n").unwrap(),
+            Some(FormRequest::NotFound)
+        );
+        assert_eq!(
+            parse_request(b"PUT / HTTP/1.1\r\n\r\n").unwrap(),
+            Some(FormRequest::NotFound)
+        );
+        assert!(parse_request(b"GET\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn post_split_across_reads() {
+        let post =
+            b"POST / HTTP/1.1\r\nContent-Length: 35\r\n\r\nssid=My+Net%21&password=pa%26ss1234";
+        //每次多收到一个字节，直到最后一个字节才完整
+        for len in 0..post.len() {
+            assert_eq!(parse_request(&post[..len]).unwrap(), None, "{}", len);
+        }
+        let credentials = submitted(parse_request(post).unwrap());
+        assert_eq!(credentials.ssid.as_str(), "My Net!");
+        assert_eq!(credentials.password.as_str(), "pa&ss1234");
+    }
+
+    #[test]
+    fn form_decoding() {
+        let credentials = submitted(Some(
+            parse_form(b"password=%E4%BD%A0%e5%a5%BD12345678&ssid=a%2Bb+c").unwrap(),
+        ));
+        assert_eq!(credentials.ssid.as_str(), "a+b c");
+        assert_eq!(credentials.password.as_str(), "你好12345678");
+        //开放网络不需要密码
+        let open = submitted(Some(parse_form(b"ssid=open").unwrap()));
+        assert!(open.password.is_empty());
+
+        assert_eq!(
+            parse_form(b"ssid=&password=12345678").unwrap(),
+            FormRequest::Invalid("SSID is required")
+        );
+        assert_eq!(
+            parse_form(b"ssid=a&password=123").unwrap(),
+            FormRequest::Invalid("password too short")
+        );
+        for bad in [&b"ssid=%zz"[..], b"ssid=%4", b"ssid=%ff%fe", b"ssid=\xff"] {
+            assert!(matches!(parse_form(bad), Err(Error::Parse(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn oversize_fields() {
+        let mut body = alloc::string::String::from("ssid=");
+        body.push_str(&"a".repeat(32));
+        assert_eq!(
+            submitted(Some(parse_form(body.as_bytes()).unwrap()))
+                .ssid
+                .len(),
+            32
+        );
+        body.push('a');
+        assert!(matches!(
+            parse_form(body.as_bytes()),
+            Err(Error::Parse("form: value too long"))
+        ));
+        //64个字符只能是十六进制的PSK
+        let body = alloc::format!("ssid=a&password={}", "x".repeat(63));
+        assert_eq!(
+            submitted(Some(parse_form(body.as_bytes()).unwrap()))
+                .password
+                .len(),
+            63
+        );
+        let body = alloc::format!("ssid=a&password={}", "0aF9".repeat(16));
+        assert_eq!(
+            submitted(Some(parse_form(body.as_bytes()).unwrap()))
+                .password
+                .len(),
+            64
+        );
+        let body = alloc::format!("ssid=a&password={}g", "0".repeat(63));
+        assert_eq!(
+            parse_form(body.as_bytes()).unwrap(),
+            FormRequest::Invalid("password too long")
+        );
+        //%XX解码后才算长度
+        let body = alloc::format!("ssid=a&password={}", "%41".repeat(65));
+        assert!(matches!(
+            parse_form(body.as_bytes()),
+            Err(Error::Parse("form: value too long"))
+        ));
+    }
+
+    #[test]
+    fn content_length() {
+        //没有Content-Length时正文为空，重新显示表单
+        assert_eq!(
+            parse_request(b"POST / HTTP/1.1\r\n\r\nssid=a").unwrap(),
+            Some(FormRequest::Invalid("SSID is required"))
+        );
+        assert!(parse_request(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n").is_err());
+        //超过接收缓冲区，永远收不完
+        let huge = b"POST / HTTP/1.1\r\nContent-Length: 1000\r\n\r\nssid=a";
+        assert!(matches!(
+            parse_request(huge),
+            Err(Error::Parse("http: request too large"))
+        ));
+        let overflow = alloc::format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert!(matches!(
+            parse_request(overflow.as_bytes()),
+            Err(Error::Parse("http: request too large"))
+        ));
+    }
+
+    #[test]
+    fn saved_in_flash() {
+        let credentials = Credentials {
+            ssid: String::from("a\"b,c\\d"),
+            password: String::from("12345678"),
+        };
+        let mut store = KvStore::mount(SimFlash::new(1024, 4)).unwrap();
+        assert_eq!(store.load(), None);
+        store.save(&credentials).unwrap();
+        //重新挂载后还在
+        let mut store = KvStore::mount(store.release()).unwrap();
+        assert_eq!(store.load(), Some(credentials));
+
+        //没按住按键时直接用保存的账号连接
+        let mut esp = Esp8266::new(Port::scripted(&["OK\r\n", "OK\r\n"]), Timer);
+        let provisioner = Provisioner::new("bluepill", "");
+        assert_eq!(
+            provisioner.connect(&mut esp, &mut store, false).unwrap(),
+            store.load().unwrap()
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! SNTP(NTPv4客户端模式)
+//!
+//! 报文编解码和日期换算只依赖core，可以在主机上测试；
+//! 得到的Unix时间戳可以直接写入RTC计数器(`hal::rtc::Rtc::set_time`)，
+//! 之后RTC在低功耗模式下继续走时
+
+use super::UdpSocket;
+use crate::io::{Error, Result};
+
+pub const PORT: u16 = 123;
+pub const PACKET_LEN: usize = 48;
+
+//1900-01-01到1970-01-01的秒数
+const UNIX_OFFSET: u32 = 2_208_988_800;
+
+//LI=0 VN=4 Mode=3(client)
+const CLIENT: u8 = 0b00_100_011;
+const MODE_SERVER: u8 = 4;
+const MODE_BROADCAST: u8 = 5;
+//LI=3表示服务器时钟未同步
+const LI_ALARM: u8 = 3;
+
+/// NTP时间戳，1900年起的秒数和1/2^32秒的小数部分
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl Timestamp {
+    pub fn from_unix(seconds: u32) -> Self {
+        Self {
+            seconds: seconds.wrapping_add(UNIX_OFFSET),
+            fraction: 0,
+        }
+    }
+
+    //四舍五入到秒，NTP纪元1(2036年以后)按回绕处理
+    pub fn to_unix(&self) -> u32 {
+        let seconds = self.seconds.wrapping_sub(UNIX_OFFSET);
+        if self.fraction >= 0x8000_0000 {
+            seconds.wrapping_add(1)
+        } else {
+            seconds
+        }
+    }
+
+    fn read(buf: &[u8]) -> Self {
+        Self {
+            seconds: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            fraction: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        }
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&self.seconds.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.fraction.to_be_bytes());
+    }
+}
+
+/// 服务器应答中客户端关心的字段
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Response {
+    pub stratum: u8,
+    pub receive: Timestamp,
+    pub transmit: Timestamp,
+}
+
+//客户端请求，transmit会被服务器原样放进应答的originate字段，用来匹配应答
+pub fn encode_request(transmit: Timestamp) -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    packet[0] = CLIENT;
+    transmit.write(&mut packet[40..48]);
+    packet
+}
+
+//originate不是transmit时返回None，是迟到的旧应答或伪造的报文，应丢掉继续等
+pub fn decode_response(packet: &[u8], transmit: Timestamp) -> Result<Option<Response>> {
+    if packet.len() < PACKET_LEN {
+        return Err(Error::Parse("ntp: short packet"));
+    }
+    let li = packet[0] >> 6;
+    let version = (packet[0] >> 3) & 0b111;
+    let mode = packet[0] & 0b111;
+    if !(3..=4).contains(&version) {
+        return Err(Error::Parse("ntp: bad version"));
+    }
+    if mode != MODE_SERVER && mode != MODE_BROADCAST {
+        return Err(Error::Parse("ntp: not a server reply"));
+    }
+    //stratum 0是Kiss-o'-Death，如限速RATE
+    if li == LI_ALARM || packet[1] == 0 {
+        return Err(Error::Parse("ntp: server not synchronized"));
+    }
+    if Timestamp::read(&packet[24..32]) != transmit {
+        return Ok(None);
+    }
+    let response = Response {
+        stratum: packet[1],
+        receive: Timestamp::read(&packet[32..40]),
+        transmit: Timestamp::read(&packet[40..48]),
+    };
+    if response.transmit.seconds == 0 && response.transmit.fraction == 0 {
+        return Err(Error::Parse("ntp: empty transmit timestamp"));
+    }
+    Ok(Some(response))
+}
+
+//通过已建立到NTP服务器的UDP连接查询一次，返回Unix时间戳；
+//transmit可以填本地当前时间，没有时钟时填任意不重复的值
+pub fn query<S: UdpSocket>(socket: &mut S, transmit: Timestamp) -> Result<u32> {
+    socket.send(&encode_request(transmit))?;
+    let mut packet = [0u8; PACKET_LEN];
+    loop {
+        let (len, _) = socket.recv_from(&mut packet)?;
+        if let Some(response) = decode_response(&packet[..len], transmit)? {
+            return Ok(response.transmit.to_unix());
+        }
+    }
+}
+
+/// 公历日期时间(UTC)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, //1~12
+    pub day: u8,   //1~31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    pub fn from_unix(timestamp: u32) -> Self {
+        let days = timestamp / 86400;
+        let rest = timestamp % 86400;
+        //Howard Hinnant的civil_from_days，纪元移到0000-03-01
+        let z = days as i64 + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+        Self {
+            year,
+            month,
+            day,
+            hour: (rest / 3600) as u8,
+            minute: (rest % 3600 / 60) as u8,
+            second: (rest % 60) as u8,
+        }
+    }
+
+    //1970年以前或字段越界时返回None
+    pub fn to_unix(&self) -> Option<u32> {
+        if self.year < 1970
+            || !(1..=12).contains(&self.month)
+            || !(1..=31).contains(&self.day)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+        {
+            return None;
+        }
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let yoe = year - era * 400;
+        let month = self.month as i64;
+        let doy =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        let seconds =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        if seconds > u32::MAX as i64 {
+            return None;
+        }
+        Some(seconds as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::Ipv4Addr;
+    use alloc::vec::Vec;
+
+    const TRANSMIT: Timestamp = Timestamp {
+        seconds: 0x1234_5678,
+        fraction: 7,
+    };
+
+    //服务器应答：stratum 2，originate是请求的transmit
+    fn reply(originate: Timestamp, transmit: Timestamp) -> [u8; PACKET_LEN] {
+        let mut packet = [0u8; PACKET_LEN];
+        packet[0] = 0b00_100_100;
+        packet[1] = 2;
+        originate.write(&mut packet[24..32]);
+        Timestamp::from_unix(1_699_999_999).write(&mut packet[32..40]);
+        transmit.write(&mut packet[40..48]);
+        packet
+    }
+
+    fn malformed(result: Result<Option<Response>>) -> &'static str {
+        match result {
+            Err(Error::Parse(detail)) => detail,
+            other => panic!("expected Error::Parse, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn request_packet() {
+        let packet = encode_request(TRANSMIT);
+        //LI=0 VN=4 Mode=3
+        assert_eq!(packet[0], 0x23);
+        assert!(packet[1..40].iter().all(|b| *b == 0));
+        assert_eq!(&packet[40..48], &[0x12, 0x34, 0x56, 0x78, 0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn server_reply() {
+        let transmit = Timestamp {
+            fraction: 0x9000_0000,
+            ..Timestamp::from_unix(1_700_000_000)
+        };
+        let response = decode_response(&reply(TRANSMIT, transmit), TRANSMIT)
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.stratum, 2);
+        assert_eq!(response.receive.to_unix(), 1_699_999_999);
+        //小数部分超过半秒进一
+        assert_eq!(response.transmit.to_unix(), 1_700_000_001);
+    }
+
+    #[test]
+    fn rejected_replies() {
+        let transmit = Timestamp::from_unix(1_700_000_000);
+        let good = reply(TRANSMIT, transmit);
+        assert_eq!(
+            malformed(decode_response(&good[..47], TRANSMIT)),
+            "ntp: short packet"
+        );
+        //originate不是这次请求的transmit，是迟到或伪造的应答
+        let other = Timestamp::default();
+        assert_eq!(decode_response(&good, other).unwrap(), None);
+
+        //Kiss-o'-Death：stratum 0，reference id是"RATE"
+        let mut kod = good;
+        kod[1] = 0;
+        kod[12..16].copy_from_slice(b"RATE");
+        assert_eq!(
+            malformed(decode_response(&kod, TRANSMIT)),
+            "ntp: server not synchronized"
+        );
+        let mut alarm = good;
+        alarm[0] |= 0b11 << 6;
+        assert_eq!(
+            malformed(decode_response(&alarm, TRANSMIT)),
+            "ntp: server not synchronized"
+        );
+        let mut client = good;
+        client[0] = 0x23;
+        assert_eq!(
+            malformed(decode_response(&client, TRANSMIT)),
+            "ntp: not a server reply"
+        );
+        let mut version = good;
+        version[0] = 0b00_010_100;
+        assert_eq!(
+            malformed(decode_response(&version, TRANSMIT)),
+            "ntp: bad version"
+        );
+        let empty = reply(TRANSMIT, Timestamp::default());
+        assert_eq!(
+            malformed(decode_response(&empty, TRANSMIT)),
+            "ntp: empty transmit timestamp"
+        );
+    }
+
+    //按顺序返回事先准备好的数据报
+    struct Socket {
+        sent: Vec<Vec<u8>>,
+        replies: Vec<[u8; PACKET_LEN]>,
+    }
+
+    impl UdpSocket for Socket {
+        fn send(&mut self, buf: &[u8]) -> Result<usize> {
+            self.sent.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn send_to(&mut self, buf: &[u8], _addr: (&str, u16)) -> Result<usize> {
+            self.send(buf)
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, (Ipv4Addr, u16))> {
+            if self.replies.is_empty() {
+                return Err(Error::Timeout);
+            }
+            let packet = self.replies.remove(0);
+            buf[..PACKET_LEN].copy_from_slice(&packet);
+            Ok((PACKET_LEN, (Ipv4Addr::new(1, 2, 3, 4), PORT)))
+        }
+
+        fn close(self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn query_skips_stale_replies() {
+        let stale = reply(Timestamp::default(), Timestamp::from_unix(1));
+        let fresh = reply(TRANSMIT, Timestamp::from_unix(1_700_000_000));
+        let mut socket = Socket {
+            sent: Vec::new(),
+            replies: alloc::vec![stale, fresh],
+        };
+        assert_eq!(query(&mut socket, TRANSMIT).unwrap(), 1_700_000_000);
+        assert_eq!(socket.sent, [encode_request(TRANSMIT).to_vec()]);
+        assert!(matches!(query(&mut socket, TRANSMIT), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn date_time() {
+        for &timestamp in &[0, 951_782_400, 1_700_000_000, 4_102_444_800, u32::MAX] {
+            assert_eq!(DateTime::from_unix(timestamp).to_unix(), Some(timestamp));
+        }
+        //闰年2月29日
+        let leap = DateTime::from_unix(951_782_400);
+        assert_eq!((leap.year, leap.month, leap.day), (2000, 2, 29));
+        assert_eq!(
+            DateTime::from_unix(1_470_322_085),
+            DateTime {
+                year: 2016,
+                month: 8,
+                day: 4,
+                hour: 14,
+                minute: 48,
+                second: 5,
+            }
+        );
+        let valid = DateTime::from_unix(0);
+        assert_eq!(
+            DateTime {
+                year: 1969,
+                ..valid
+            }
+            .to_unix(),
+            None
+        );
+        assert_eq!(DateTime { month: 13, ..valid }.to_unix(), None);
+        assert_eq!(DateTime { hour: 24, ..valid }.to_unix(), None);
+        //超过u32的2106年
+        assert_eq!(
+            DateTime {
+                year: 2107,
+                ..valid
+            }
+            .to_unix(),
+            None
+        );
+    }
+}
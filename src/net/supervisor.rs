@@ -0,0 +1,546 @@
+//! ESP8266链路守护
+//! 每隔interval毫秒发送AT探测模块，无应答时依次升级处理：
+//! AT+RST软复位 -> RST/CH_PD引脚硬件复位 -> 重新连接WIFI -> 重新建立登记的连接
+//! 应用在主循环里反复调用poll，过程中的变化通过event取出
+
+use super::at::JoinError;
+use super::esp826601s::{Esp8266, Event, Link};
+use super::Net;
+use crate::gpio::NoPin;
+use crate::hal::time::Hertz;
+use crate::io::Error;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embedded_hal::digital::v2::OutputPin;
+
+//未处理事件最多保留的个数
+const EVENTS: usize = 16;
+
+/// 登记的连接，模块复位后重新建立时保持不变
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SocketId(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    Online,    //正常，定期探测
+    SoftReset, //探测失败，准备AT+RST
+    HardReset, //软复位失败，准备拉RST引脚
+    Offline,   //复位都失败，等待下次重试
+    Join,      //等待重新连接WIFI
+    Reopen,    //重新建立登记的连接
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SupervisorEvent {
+    ProbeFailed,
+    SoftReset,
+    HardReset,
+    ResetFailed,
+    Joined,
+    JoinFailed(Option<JoinError>),
+    Reopened(SocketId),
+    ReopenFailed(SocketId),
+    Online,
+    Module(Event), //模块上报的其它事件
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Tcp,
+    Ssl,
+    Udp(u16), //本地端口
+}
+
+struct Socket {
+    kind: Kind,
+    host: String,
+    port: u16,
+    link: Option<Link>,
+}
+
+pub struct Supervisor<T, TIM, P> {
+    esp: Esp8266<T, TIM>,
+    pin: Option<P>,
+    ssid: String,
+    password: String,
+    interval: u32,
+    attempts: u8,
+    failures: u8,
+    state: State,
+    next: u32, //下次动作的时间(Esp8266::uptime)
+    sockets: Vec<Option<Socket>>,
+    events: VecDeque<SupervisorEvent>,
+}
+
+impl<T, TIM> Supervisor<T, TIM, NoPin>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    pub fn new(esp: Esp8266<T, TIM>, ssid: &str, password: &str) -> Self {
+        Self {
+            next: esp.uptime(),
+            esp,
+            pin: None,
+            ssid: String::from(ssid),
+            password: String::from(password),
+            interval: 10000,
+            attempts: 3,
+            failures: 0,
+            state: State::Join,
+            sockets: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    //接RST或CH_PD引脚后，软复位失败时可以硬件复位
+    pub fn reset_pin<P: OutputPin>(self, pin: P) -> Supervisor<T, TIM, P> {
+        Supervisor {
+            esp: self.esp,
+            pin: Some(pin),
+            ssid: self.ssid,
+            password: self.password,
+            interval: self.interval,
+            attempts: self.attempts,
+            failures: self.failures,
+            state: self.state,
+            next: self.next,
+            sockets: self.sockets,
+            events: self.events,
+        }
+    }
+}
+
+impl<T, TIM, P> Supervisor<T, TIM, P>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+    P: OutputPin,
+{
+    //探测及重试的间隔(毫秒)
+    pub fn interval(mut self, milliseconds: u32) -> Self {
+        self.interval = milliseconds;
+        self
+    }
+
+    //连续失败多少次后重新复位模块
+    pub fn attempts(mut self, attempts: u8) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn device(&mut self) -> &mut Esp8266<T, TIM> {
+        &mut self.esp
+    }
+
+    //取出一个事件
+    pub fn event(&mut self) -> Option<SupervisorEvent> {
+        self.events.pop_front()
+    }
+
+    //立即探测，如应用收发出错时
+    pub fn probe(&mut self) {
+        self.next = self.esp.uptime();
+    }
+
+    //登记连接，由poll建立并在模块复位后重新建立，需要多连接模式
+    pub fn register_tcp(&mut self, addr: (&str, u16)) -> SocketId {
+        self.register(Kind::Tcp, addr)
+    }
+
+    pub fn register_ssl(&mut self, addr: (&str, u16)) -> SocketId {
+        self.register(Kind::Ssl, addr)
+    }
+
+    pub fn register_udp(&mut self, remote: (&str, u16), local_port: u16) -> SocketId {
+        self.register(Kind::Udp(local_port), remote)
+    }
+
+    //取消登记并关闭连接
+    pub fn unregister(&mut self, id: SocketId) -> crate::io::Result<()> {
+        match self.sockets.get_mut(id.0).and_then(|s| s.take()) {
+            Some(Socket {
+                link: Some(link), ..
+            }) => self.esp.close(link),
+            _ => Ok(()),
+        }
+    }
+
+    //已建立的连接，用Esp8266::send/recv等收发
+    pub fn socket(&mut self, id: SocketId) -> Option<(&mut Esp8266<T, TIM>, &Link)> {
+        let link = self.sockets.get(id.0)?.as_ref()?.link.as_ref()?;
+        Some((&mut self.esp, link))
+    }
+
+    //等待模块数据最多timeout毫秒，到时间则执行探测或下一步恢复
+    pub fn poll(&mut self, timeout: u32) -> State {
+        if self.esp.poll(timeout).is_err() && self.state == State::Online {
+            self.probe();
+        }
+        loop {
+            while let Some(event) = self.esp.event() {
+                self.handle(event);
+            }
+            if !self.due() {
+                return self.state;
+            }
+            self.step();
+        }
+    }
+
+    fn register(&mut self, kind: Kind, addr: (&str, u16)) -> SocketId {
+        let socket = Socket {
+            kind,
+            host: String::from(addr.0),
+            port: addr.1,
+            link: None,
+        };
+        let index = match self.sockets.iter().position(|s| s.is_none()) {
+            Some(index) => {
+                self.sockets[index] = Some(socket);
+                index
+            }
+            None => {
+                self.sockets.push(Some(socket));
+                self.sockets.len() - 1
+            }
+        };
+        if self.state == State::Online {
+            self.state = State::Reopen;
+            self.probe();
+        }
+        SocketId(index)
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            //模块设置了自动连接时会自己重连，先等一个间隔
+            Event::WifiDisconnected if self.state == State::Online => {
+                self.state = State::Join;
+                self.failures = 0;
+                self.next = self.esp.uptime().wrapping_add(self.interval);
+            }
+            Event::WifiGotIp if self.state == State::Join => {
+                self.state = State::Reopen;
+                self.failures = 0;
+                self.probe();
+            }
+            Event::Closed(id) => {
+                for socket in self.sockets.iter_mut().flatten() {
+                    if socket.link.as_ref().map(|link| link.id()) == Some(id) {
+                        if let Some(link) = socket.link.take() {
+                            self.esp.close(link).ok();
+                        }
+                        if self.state == State::Online {
+                            self.state = State::Reopen;
+                            self.next = self.esp.uptime();
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.report(SupervisorEvent::Module(event));
+    }
+
+    fn step(&mut self) {
+        match self.state {
+            State::Online => {
+                if self.esp.hello().is_ok() {
+                    self.wait();
+                } else {
+                    self.report(SupervisorEvent::ProbeFailed);
+                    self.state = State::SoftReset;
+                }
+            }
+            State::SoftReset | State::Offline => {
+                self.report(SupervisorEvent::SoftReset);
+                self.drop_links();
+                self.state = match self.esp.reset().and_then(|_| self.esp.hello()) {
+                    Ok(_) => State::Join,
+                    Err(_) => State::HardReset,
+                };
+                self.failures = 0;
+            }
+            State::HardReset => {
+                //没接复位引脚时直接算作复位失败
+                let recovered = if self.pin.is_none() {
+                    false
+                } else {
+                    self.report(SupervisorEvent::HardReset);
+                    let pin = self.pin.as_mut().unwrap();
+                    self.esp
+                        .hard_reset(pin)
+                        .and_then(|_| self.esp.hello())
+                        .is_ok()
+                };
+                if recovered {
+                    self.state = State::Join;
+                } else {
+                    self.report(SupervisorEvent::ResetFailed);
+                    self.state = State::Offline;
+                    self.wait();
+                }
+            }
+            State::Join => self.join(),
+            State::Reopen => self.reopen(),
+        }
+    }
+
+    fn join(&mut self) {
+        //模块保存了AP时复位后会自己连接，不必再写一次flash
+        if let Ok(Some(_)) = self.esp.station() {
+            self.state = State::Reopen;
+            return;
+        }
+        match self.esp.dial(&self.ssid, &self.password, true) {
+            Ok(_) => {
+                self.report(SupervisorEvent::Joined);
+                self.failures = 0;
+                self.state = State::Reopen;
+            }
+            Err(err) => {
+                let reason = match err {
+                    Error::Join(reason) => Some(reason),
+                    _ => None,
+                };
+                self.report(SupervisorEvent::JoinFailed(reason));
+                self.fail();
+            }
+        }
+    }
+
+    fn reopen(&mut self) {
+        if self.sockets.iter().flatten().any(|s| s.link.is_none())
+            && !self.esp.is_mux()
+            && self.esp.set_mux(true).is_err()
+        {
+            self.fail();
+            return;
+        }
+        let mut ok = true;
+        for index in 0..self.sockets.len() {
+            let socket = match &self.sockets[index] {
+                Some(socket) if socket.link.is_none() => socket,
+                _ => continue,
+            };
+            let addr = (socket.host.as_str(), socket.port);
+            let result = match socket.kind {
+                Kind::Tcp => self.esp.open_link(addr),
+                Kind::Ssl => self.esp.open_ssl_link(addr),
+                Kind::Udp(local_port) => self.esp.open_udp_link(addr, local_port),
+            };
+            match result {
+                Ok(link) => {
+                    if let Some(socket) = self.sockets[index].as_mut() {
+                        socket.link = Some(link);
+                    }
+                    self.report(SupervisorEvent::Reopened(SocketId(index)));
+                }
+                Err(_) => {
+                    self.report(SupervisorEvent::ReopenFailed(SocketId(index)));
+                    ok = false;
+                }
+            }
+        }
+        if ok {
+            self.report(SupervisorEvent::Online);
+            self.failures = 0;
+            self.state = State::Online;
+            self.wait();
+        } else {
+            self.fail();
+        }
+    }
+
+    //连续失败attempts次后从软复位重来，否则等一个间隔再试
+    fn fail(&mut self) {
+        self.failures += 1;
+        if self.failures >= self.attempts {
+            self.failures = 0;
+            self.state = State::SoftReset;
+        } else {
+            self.wait();
+        }
+    }
+
+    //模块复位后旧的句柄都已失效
+    fn drop_links(&mut self) {
+        for socket in self.sockets.iter_mut().flatten() {
+            socket.link = None;
+        }
+    }
+
+    fn wait(&mut self) {
+        self.next = self.esp.uptime().wrapping_add(self.interval);
+    }
+
+    fn due(&self) -> bool {
+        self.esp.uptime().wrapping_sub(self.next) as i32 >= 0
+    }
+
+    fn report(&mut self, event: SupervisorEvent) {
+        if self.events.len() == EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{Port, Timer};
+
+    const ONLINE: &str = "+CWJAP_CUR:\"ssid\",\"aa:bb:cc:dd:ee:ff\",6,-50\r\n\r\nOK\r\n";
+
+    fn supervisor(script: &[&'static str]) -> Supervisor<Port, Timer, NoPin> {
+        let esp = Esp8266::new(Port::scripted(script), Timer);
+        Supervisor::new(esp, "ssid", "password").interval(100)
+    }
+
+    //不含模块上报的事件
+    fn events(sup: &mut Supervisor<Port, Timer, NoPin>) -> Vec<SupervisorEvent> {
+        core::iter::from_fn(|| sup.event())
+            .filter(|event| !matches!(event, SupervisorEvent::Module(_)))
+            .collect()
+    }
+
+    #[test]
+    fn soft_reset_recovers() {
+        let mut sup = supervisor(&[
+            ONLINE,                    //AT+CWJAP_CUR?
+            "",                        //AT 无应答
+            "\r\nOK\r\n\r\nready\r\n", //AT+RST
+            "\r\nOK\r\n",              //AT
+            ONLINE,                    //AT+CWJAP_CUR?
+        ]);
+        assert_eq!(sup.poll(0), State::Online);
+        assert_eq!(events(&mut sup), [SupervisorEvent::Online]);
+        assert_eq!(sup.poll(100), State::Online);
+        assert_eq!(
+            events(&mut sup),
+            [
+                SupervisorEvent::ProbeFailed,
+                SupervisorEvent::SoftReset,
+                SupervisorEvent::Online,
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_fails_without_pin() {
+        let mut sup = supervisor(&[
+            ONLINE,                    //AT+CWJAP_CUR?
+            "\r\nERROR\r\n",           //AT
+            "",                        //AT+RST 无应答
+            "\r\nOK\r\n\r\nready\r\n", //间隔后再次AT+RST
+            "\r\nOK\r\n",              //AT
+            ONLINE,                    //AT+CWJAP_CUR?
+        ]);
+        sup.poll(0);
+        events(&mut sup);
+        assert_eq!(sup.poll(100), State::Offline);
+        assert_eq!(
+            events(&mut sup),
+            [
+                SupervisorEvent::ProbeFailed,
+                SupervisorEvent::SoftReset,
+                SupervisorEvent::ResetFailed,
+            ]
+        );
+        //没有复位引脚，下个间隔从软复位重来
+        assert_eq!(sup.poll(100), State::Online);
+        assert_eq!(
+            events(&mut sup),
+            [SupervisorEvent::SoftReset, SupervisorEvent::Online]
+        );
+    }
+
+    #[test]
+    fn join_failures_escalate() {
+        let mut sup = supervisor(&[
+            "No AP\r\n\r\nOK\r\n",                         //AT+CWJAP_CUR?
+            "+CWJAP:2\r\n\r\nFAIL\r\n",                    //AT+CWJAP_DEF=...
+            "No AP\r\n\r\nOK\r\n",                         //AT+CWJAP_CUR?
+            "+CWJAP:3\r\n\r\nFAIL\r\n",                    //AT+CWJAP_DEF=...
+            "\r\nOK\r\n\r\nready\r\n",                     //AT+RST
+            "\r\nOK\r\n",                                  //AT
+            "No AP\r\n\r\nOK\r\n",                         //AT+CWJAP_CUR?
+            "WIFI CONNECTED\r\nWIFI GOT IP\r\n\r\nOK\r\n", //AT+CWJAP_DEF=...
+            "\r\nOK\r\n",                                  //AT+CWAUTOCONN=1
+        ])
+        .attempts(2);
+        assert_eq!(sup.poll(0), State::Join);
+        assert_eq!(
+            events(&mut sup),
+            [SupervisorEvent::JoinFailed(Some(JoinError::WrongPassword))]
+        );
+        //第二次失败后软复位，再连接成功
+        assert_eq!(sup.poll(100), State::Online);
+        assert_eq!(
+            events(&mut sup),
+            [
+                SupervisorEvent::JoinFailed(Some(JoinError::NotFound)),
+                SupervisorEvent::SoftReset,
+                SupervisorEvent::Joined,
+                SupervisorEvent::Online,
+            ]
+        );
+    }
+
+    #[test]
+    fn reopen_after_got_ip() {
+        let mut sup = supervisor(&[
+            ONLINE,                                                                  //AT+CWJAP_CUR?
+            "\r\nOK\r\n",                                                            //AT+CIPMUX=1
+            "0,CONNECT\r\n\r\nOK\r\nWIFI DISCONNECT\r\n0,CLOSED\r\nWIFI GOT IP\r\n", //AT+CIPSTART
+            "0,CONNECT\r\n\r\nOK\r\n",                                               //AT+CIPSTART
+        ]);
+        let id = sup.register_tcp(("example.com", 80));
+        assert_eq!(sup.poll(0), State::Online);
+        assert_eq!(
+            events(&mut sup),
+            [SupervisorEvent::Reopened(id), SupervisorEvent::Online]
+        );
+        assert!(sup.socket(id).is_some());
+        //掉线时连接被关闭，拿到IP后马上重建，不必等一个间隔
+        assert_eq!(sup.poll(0), State::Online);
+        let all: Vec<_> = core::iter::from_fn(|| sup.event()).collect();
+        assert_eq!(
+            all,
+            [
+                SupervisorEvent::Module(Event::WifiDisconnected),
+                SupervisorEvent::Module(Event::Closed(0)),
+                SupervisorEvent::Module(Event::WifiGotIp),
+                SupervisorEvent::Reopened(id),
+                SupervisorEvent::Online,
+                SupervisorEvent::Module(Event::Connected(0)),
+            ]
+        );
+        assert!(sup.socket(id).is_some());
+    }
+
+    #[test]
+    fn closed_link_reopens() {
+        let mut sup = supervisor(&[
+            ONLINE,                                //AT+CWJAP_CUR?
+            "\r\nOK\r\n",                          //AT+CIPMUX=1
+            "0,CONNECT\r\n\r\nOK\r\n0,CLOSED\r\n", //AT+CIPSTART
+            "0,CONNECT\r\n\r\nOK\r\n",             //AT+CIPSTART
+        ]);
+        let id = sup.register_tcp(("example.com", 80));
+        sup.poll(0);
+        events(&mut sup);
+        assert_eq!(sup.poll(0), State::Online);
+        assert_eq!(
+            events(&mut sup),
+            [SupervisorEvent::Reopened(id), SupervisorEvent::Online]
+        );
+    }
+}
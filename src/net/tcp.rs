@@ -1 +1,131 @@
-//TODO
+//! 基于ESP8266的TCP连接
+
+use super::esp826601s::{Esp8266, Link};
+use super::TcpStream;
+use crate::hal::time::Hertz;
+use crate::io::{Error, Result};
+
+//AT+CIPSEND单次最多发送2048字节
+const MAX_SEND: usize = 2048;
+
+pub struct EspTcpStream<'a, T, TIM> {
+    esp: &'a mut Esp8266<T, TIM>,
+    link: Option<Link>, //None表示单连接模式
+    read_buffer: usize,
+    write_buffer: usize,
+    timeout: u32,
+}
+
+impl<'a, T, TIM> EspTcpStream<'a, T, TIM>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    pub fn new(esp: &'a mut Esp8266<T, TIM>) -> Self {
+        Self {
+            esp,
+            link: None,
+            read_buffer: MAX_SEND,
+            write_buffer: MAX_SEND,
+            timeout: 5000,
+        }
+    }
+
+    //多连接模式下把连接句柄包装成TcpStream
+    pub fn with_link(esp: &'a mut Esp8266<T, TIM>, link: Link) -> Self {
+        let mut stream = Self::new(esp);
+        stream.link = Some(link);
+        stream
+    }
+
+    //交还连接句柄而不关闭连接，释放对设备的借用
+    pub fn into_link(self) -> Option<Link> {
+        self.link
+    }
+}
+
+impl<'a, T, TIM> TcpStream for EspTcpStream<'a, T, TIM>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(self.read_buffer);
+        match &self.link {
+            Some(link) => self.esp.recv(link, &mut buf[..len], self.timeout),
+            None => self.esp.read_data(&mut buf[..len], self.timeout),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut sent = 0;
+        for chunk in buf.chunks(self.write_buffer) {
+            sent += match &self.link {
+                Some(link) => self.esp.send(link, chunk, self.timeout)?,
+                None => self.esp.send_data(chunk, self.timeout)?,
+            };
+        }
+        Ok(sent)
+    }
+
+    fn set_write_buffer(&mut self, size: usize) -> Result<()> {
+        if size == 0 || size > MAX_SEND {
+            return Err(Error::BufferFull);
+        }
+        self.write_buffer = size;
+        Ok(())
+    }
+
+    fn set_read_buffer(&mut self, size: usize) -> Result<()> {
+        if size == 0 {
+            return Err(Error::BufferFull);
+        }
+        self.read_buffer = size;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, milliseconds: u32) {
+        self.timeout = milliseconds;
+    }
+
+    fn close(self) -> Result<()> {
+        match self.link {
+            Some(link) => self.esp.close(link),
+            None => self.esp.disconnect().map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{Port, Timer};
+    use crate::net::esp826601s::{Esp8266, Event};
+    use crate::net::{Net, TcpStream};
+
+    #[test]
+    fn single_connection() {
+        let port = Port::new(b"CONNECT\r\n\r\nOK\r\nOK\r\n> SEND OK\r\n+IPD,5:hello\r\nCLOSED\r\n");
+        let mut esp = Esp8266::new(port, Timer);
+        {
+            let mut stream = esp.open_tcp(("1.2.3.4", 80)).unwrap();
+            assert_eq!(stream.write(b"abc").unwrap(), 3);
+            let mut buf = [0u8; 10];
+            assert_eq!(stream.read(&mut buf).unwrap(), 5);
+            assert_eq!(&buf[..5], b"hello");
+            //远端已关闭
+            assert!(stream.read(&mut buf).is_err());
+        }
+        assert_eq!(esp.event(), Some(Event::Connected(0)));
+    }
+
+    #[test]
+    fn write_buffer_splits_sends() {
+        let port = Port::new(b"CONNECT\r\n\r\nOK\r\nOK\r\n> SEND OK\r\nOK\r\n> SEND OK\r\n");
+        let mut esp = Esp8266::new(port, Timer);
+        let mut stream = esp.open_tcp(("1.2.3.4", 80)).unwrap();
+        assert!(stream.set_write_buffer(0).is_err());
+        assert!(stream.set_write_buffer(4096).is_err());
+        stream.set_write_buffer(2).unwrap();
+        assert_eq!(stream.write(b"abc").unwrap(), 3);
+    }
+}
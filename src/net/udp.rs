@@ -0,0 +1,73 @@
+//! 基于ESP8266的UDP连接
+
+use super::esp826601s::{Esp8266, Link};
+use super::{Ipv4Addr, UdpSocket};
+use crate::hal::time::Hertz;
+use crate::io::Result;
+
+pub struct EspUdpSocket<'a, T, TIM> {
+    esp: &'a mut Esp8266<T, TIM>,
+    link: Option<Link>, //None表示单连接模式
+    timeout: u32,
+}
+
+impl<'a, T, TIM> EspUdpSocket<'a, T, TIM>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    pub fn new(esp: &'a mut Esp8266<T, TIM>) -> Self {
+        Self {
+            esp,
+            link: None,
+            timeout: 5000,
+        }
+    }
+
+    //多连接模式下把连接句柄包装成UdpSocket
+    pub fn with_link(esp: &'a mut Esp8266<T, TIM>, link: Link) -> Self {
+        let mut socket = Self::new(esp);
+        socket.link = Some(link);
+        socket
+    }
+
+    //交还连接句柄而不关闭连接，释放对设备的借用
+    pub fn into_link(self) -> Option<Link> {
+        self.link
+    }
+
+    //读写超时(毫秒)
+    pub fn set_timeout(&mut self, milliseconds: u32) {
+        self.timeout = milliseconds;
+    }
+}
+
+impl<'a, T, TIM> UdpSocket for EspUdpSocket<'a, T, TIM>
+where
+    T: embedded_hal::serial::Read<u8> + embedded_hal::serial::Write<u8>,
+    TIM: embedded_hal::timer::CountDown<Time = Hertz>,
+{
+    //发送到建立连接时指定的远端地址
+    fn send(&mut self, buf: &[u8]) -> Result<usize> {
+        match &self.link {
+            Some(link) => self.esp.send(link, buf, self.timeout),
+            None => self.esp.send_data(buf, self.timeout),
+        }
+    }
+
+    fn send_to(&mut self, buf: &[u8], addr: (&str, u16)) -> Result<usize> {
+        self.esp
+            .send_to(self.link.as_ref(), buf, addr, self.timeout)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, (Ipv4Addr, u16))> {
+        self.esp.recv_from(self.link.as_ref(), buf, self.timeout)
+    }
+
+    fn close(self) -> Result<()> {
+        match self.link {
+            Some(link) => self.esp.close(link),
+            None => self.esp.disconnect().map(|_| ()),
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! 固件升级(OTA)
+//!
+//! 64K flash的分区，和memory-app.x、memory-bootloader.x一致：
+//!
+//! | 地址       | 大小 | 用途                                   |
+//! |------------|------|----------------------------------------|
+//! | 0x08000000 | 8K   | bootloader(examples/bootloader.rs)     |
+//! | 0x08002000 | 25K  | 应用                                   |
+//! | 0x08008400 | 25K  | 下载区，交换后存放旧固件用于回滚       |
+//! | 0x0800E800 | 1K   | 升级状态                               |
+//! | 0x0800EC00 | 1K   | 交换用的临时页                         |
+//! | 0x0800F000 | 4K   | storage::KvStore                       |
+//!
+//! 应用用`Updater`把新固件写进下载区，校验长度和CRC32后标记为待升级，然后复位。
+//! bootloader上电时调用`boot`，逐页交换应用区和下载区，进入试运行状态后跳转到新固件。
+//! 新固件自检通过后调用`Updater::confirm`；试运行中复位(崩溃、看门狗)时bootloader再交换一次，
+//! 回滚到旧固件。
+//!
+//! 交换每一页分三步：应用页→临时页、下载页→应用页、临时页→下载页，每步完成后在状态页写一个标记，
+//! 掉电后从没有标记的那一步重做。这里`Flash`的偏移都相对于0x08000000，
+//! 用`Stm32Flash::range(&mut flash, 0, 64)`或`SimFlash::new(1024, 64)`
+
+use crate::io::{Error, Result};
+use crate::net::http::{self, Request};
+use crate::net::TcpStream;
+use crate::storage::{crc16, crc32, Flash};
+use alloc::format;
+use alloc::string::String;
+
+pub const PAGE_SIZE: usize = 1024;
+pub const BOOTLOADER: usize = 0;
+pub const APP: usize = 0x2000;
+pub const STAGING: usize = 0x8400;
+//应用区和下载区一样大
+pub const SLOT_SIZE: usize = 25 * 1024;
+pub const STATE: usize = 0xe800;
+pub const SCRATCH: usize = 0xec00;
+
+const SLOT_PAGES: usize = SLOT_SIZE / PAGE_SIZE;
+const MAGIC: u16 = 0x4f54; //"OT"
+
+//状态页头: magic(2) 保留(2) 长度(4) CRC32(4) 头的CRC16(2)
+const HEADER: usize = 14;
+//标记写成0表示已发生
+const SWAPPED: usize = 16;
+const CONFIRMED: usize = 18;
+const ROLLBACK: usize = 20;
+const ROLLED_BACK: usize = 22;
+//交换进度，每页3个标记
+const FORWARD: usize = 32;
+const BACKWARD: usize = FORWARD + SLOT_PAGES * 3 * 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Idle,        //没有升级
+    Pending,     //已下载并校验，等待bootloader交换
+    Swapping,    //交换到一半
+    Testing,     //新固件试运行，等待确认
+    Confirmed,   //新固件已确认
+    RollingBack, //回滚到一半
+    RolledBack,  //新固件没有确认，已回滚到旧固件
+    Rejected,    //bootloader校验下载区失败，没有交换
+}
+
+struct Image {
+    len: usize,
+    crc: u32,
+}
+
+pub fn status<F: Flash>(flash: &mut F) -> Result<Status> {
+    if read_image(flash)?.is_none() {
+        return Ok(Status::Idle);
+    }
+    let swapped = is_set(flash, SWAPPED)?;
+    let status = if is_set(flash, ROLLED_BACK)? {
+        if swapped {
+            Status::RolledBack
+        } else {
+            Status::Rejected
+        }
+    } else if is_set(flash, ROLLBACK)? {
+        Status::RollingBack
+    } else if is_set(flash, CONFIRMED)? {
+        Status::Confirmed
+    } else if swapped {
+        Status::Testing
+    } else if is_set(flash, FORWARD)? {
+        Status::Swapping
+    } else {
+        Status::Pending
+    };
+    Ok(status)
+}
+
+//bootloader上电时调用，完成升级交换或回滚，返回后跳转到应用区
+pub fn boot<F: Flash>(flash: &mut F) -> Result<Status> {
+    let image = match read_image(flash)? {
+        Some(image) => image,
+        None => return Ok(Status::Idle),
+    };
+    let pages = pages(image.len);
+    match status(flash)? {
+        status @ Status::Pending | status @ Status::Swapping => {
+            //交换前再校验一次下载区
+            if status == Status::Pending && checksum(flash, STAGING, image.len)? != image.crc {
+                set(flash, ROLLED_BACK)?;
+                return Ok(Status::Rejected);
+            }
+            swap(flash, pages, FORWARD)?;
+            set(flash, SWAPPED)?;
+            Ok(Status::Testing)
+        }
+        //试运行时复位，说明新固件没能确认自己
+        status @ Status::Testing | status @ Status::RollingBack => {
+            if status == Status::Testing {
+                set(flash, ROLLBACK)?;
+            }
+            swap(flash, pages, BACKWARD)?;
+            set(flash, ROLLED_BACK)?;
+            Ok(Status::RolledBack)
+        }
+        status => Ok(status),
+    }
+}
+
+/// 应用一侧：把新固件写进下载区，确认试运行的固件
+pub struct Updater<F> {
+    flash: F,
+    len: usize,      //begin时声明的长度
+    written: usize,  //已写入下载区的字节数
+    odd: Option<u8>, //flash按半字编程，奇数个字节时最后一个先留着
+}
+
+impl<F: Flash> Updater<F> {
+    //flash要覆盖整片64K
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            len: 0,
+            written: 0,
+            odd: None,
+        }
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    pub fn status(&mut self) -> Result<Status> {
+        status(&mut self.flash)
+    }
+
+    //新固件自检通过后调用，否则下次复位会回滚；不在试运行时什么也不做
+    pub fn confirm(&mut self) -> Result<()> {
+        if self.status()? == Status::Testing {
+            set(&mut self.flash, CONFIRMED)?;
+        }
+        Ok(())
+    }
+
+    //开始写入len字节的固件，清除上一次升级的状态并擦除下载区
+    pub fn begin(&mut self, len: usize) -> Result<()> {
+        if len == 0 || len > SLOT_SIZE {
+            return Err(Error::BufferFull);
+        }
+        //试运行时下载区里是回滚用的旧固件
+        if self.status()? == Status::Testing {
+            return Err(Error::Other(String::from(
+                "ota: confirm the running image first",
+            )));
+        }
+        self.flash.erase(STATE / PAGE_SIZE)?;
+        for page in 0..pages(len) {
+            self.flash.erase(STAGING / PAGE_SIZE + page)?;
+        }
+        self.len = len;
+        self.written = 0;
+        self.odd = None;
+        Ok(())
+    }
+
+    //按顺序写入固件，分段大小任意
+    pub fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        let held = self.odd.is_some() as usize;
+        if self.written + held + data.len() > self.len {
+            return Err(Error::BufferFull);
+        }
+        if let Some(first) = self.odd.take() {
+            match data.split_first() {
+                Some((second, rest)) => {
+                    self.program(&[first, *second])?;
+                    data = rest;
+                }
+                None => {
+                    self.odd = Some(first);
+                    return Ok(());
+                }
+            }
+        }
+        let even = data.len() & !1;
+        self.program(&data[..even])?;
+        if even < data.len() {
+            self.odd = Some(data[even]);
+        }
+        Ok(())
+    }
+
+    //校验长度和CRC32，通过后标记为待升级，复位后由bootloader交换
+    pub fn finish(&mut self, crc: u32) -> Result<()> {
+        let len = self.written + self.odd.is_some() as usize;
+        if let Some(last) = self.odd.take() {
+            self.program(&[last, 0xff])?;
+        }
+        if len != self.len {
+            return Err(Error::Other(format!(
+                "ota: got {} of {} bytes",
+                len, self.len
+            )));
+        }
+        if checksum(&mut self.flash, STAGING, len)? != crc {
+            return Err(Error::Other(String::from("ota: crc mismatch")));
+        }
+        let mut header = [0u8; HEADER];
+        header[..2].copy_from_slice(&MAGIC.to_le_bytes());
+        header[2..4].copy_from_slice(&[0xff, 0xff]);
+        header[4..8].copy_from_slice(&(len as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&crc.to_le_bytes());
+        let check = crc16(0xffff, &header[..12]);
+        header[12..].copy_from_slice(&check.to_le_bytes());
+        self.flash.write(STATE, &header)
+    }
+
+    //下载len字节的固件并标记为待升级，len和crc随升级通知一起下发
+    //let request = Request::get("example.com", "/firmware.bin").timeout(10_000);
+    //updater.download(&mut stream, &request, len, crc)?;
+    pub fn download<S: TcpStream>(
+        &mut self,
+        stream: &mut S,
+        request: &Request,
+        len: usize,
+        crc: u32,
+    ) -> Result<()> {
+        self.begin(len)?;
+        let mut buf = [0u8; 512];
+        let (status, _) = http::fetch(stream, request, &mut buf, |chunk| self.write(chunk))?;
+        if !(200..300).contains(&status) {
+            return Err(Error::Other(format!("ota: http status {}", status)));
+        }
+        self.finish(crc)
+    }
+
+    fn program(&mut self, data: &[u8]) -> Result<()> {
+        self.flash.write(STAGING + self.written, data)?;
+        self.written += data.len();
+        Ok(())
+    }
+}
+
+fn read_image<F: Flash>(flash: &mut F) -> Result<Option<Image>> {
+    let mut header = [0u8; HEADER];
+    flash.read(STATE, &mut header)?;
+    if u16::from_le_bytes([header[0], header[1]]) != MAGIC
+        || crc16(0xffff, &header[..12]) != u16::from_le_bytes([header[12], header[13]])
+    {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if len == 0 || len > SLOT_SIZE {
+        return Ok(None);
+    }
+    Ok(Some(Image { len, crc }))
+}
+
+//len字节占几页
+fn pages(len: usize) -> usize {
+    match len % PAGE_SIZE {
+        0 => len / PAGE_SIZE,
+        _ => len / PAGE_SIZE + 1,
+    }
+}
+
+fn is_set<F: Flash>(flash: &mut F, marker: usize) -> Result<bool> {
+    let mut word = [0u8; 2];
+    flash.read(STATE + marker, &mut word)?;
+    Ok(word == [0, 0])
+}
+
+fn set<F: Flash>(flash: &mut F, marker: usize) -> Result<()> {
+    flash.write(STATE + marker, &[0, 0])
+}
+
+//逐页交换应用区和下载区的前pages页，progress是这次交换的进度标记
+fn swap<F: Flash>(flash: &mut F, pages: usize, progress: usize) -> Result<()> {
+    for page in 0..pages {
+        let app = APP + page * PAGE_SIZE;
+        let staging = STAGING + page * PAGE_SIZE;
+        let marker = progress + page * 6;
+        for (step, (from, to)) in [(app, SCRATCH), (staging, app), (SCRATCH, staging)]
+            .iter()
+            .enumerate()
+        {
+            if !is_set(flash, marker + step * 2)? {
+                copy(flash, *from, *to)?;
+                set(flash, marker + step * 2)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+//擦除to页，把from页复制过去
+fn copy<F: Flash>(flash: &mut F, from: usize, to: usize) -> Result<()> {
+    flash.erase(to / PAGE_SIZE)?;
+    let mut buf = [0u8; 64];
+    for offset in (0..PAGE_SIZE).step_by(buf.len()) {
+        flash.read(from + offset, &mut buf)?;
+        flash.write(to + offset, &buf)?;
+    }
+    Ok(())
+}
+
+fn checksum<F: Flash>(flash: &mut F, start: usize, len: usize) -> Result<u32> {
+    let mut crc = 0;
+    let mut buf = [0u8; 64];
+    let mut offset = 0;
+    while offset < len {
+        let n = buf.len().min(len - offset);
+        flash.read(start + offset, &mut buf[..n])?;
+        crc = crc32(crc, &buf[..n]);
+        offset += n;
+    }
+    Ok(crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SimFlash;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn image(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(seed).wrapping_add(seed))
+            .collect()
+    }
+
+    fn app(flash: &mut SimFlash, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        flash.read(APP, &mut buf).unwrap();
+        buf
+    }
+
+    //应用区放旧固件，下载区写入新固件并标记为待升级
+    fn stage(old: &[u8], new: &[u8]) -> SimFlash {
+        let mut flash = SimFlash::new(PAGE_SIZE, 64);
+        for page in 0..pages(old.len()) {
+            flash.erase(APP / PAGE_SIZE + page).unwrap();
+        }
+        let mut old = old.to_vec();
+        if old.len() % 2 == 1 {
+            old.push(0xff);
+        }
+        flash.write(APP, &old).unwrap();
+        let mut updater = Updater::new(flash);
+        updater.begin(new.len()).unwrap();
+        for chunk in new.chunks(7) {
+            updater.write(chunk).unwrap();
+        }
+        updater.finish(crc32(0, new)).unwrap();
+        assert_eq!(updater.status().unwrap(), Status::Pending);
+        updater.release()
+    }
+
+    #[test]
+    fn staging() {
+        let mut updater = Updater::new(SimFlash::new(PAGE_SIZE, 64));
+        assert_eq!(updater.status().unwrap(), Status::Idle);
+        assert!(updater.begin(SLOT_SIZE + 1).is_err());
+        //长度不够
+        updater.begin(10).unwrap();
+        updater.write(&[1; 9]).unwrap();
+        assert!(updater.finish(0).is_err());
+        //CRC不对
+        updater.begin(4).unwrap();
+        updater.write(&[1, 2, 3, 4]).unwrap();
+        assert!(updater.write(&[5]).is_err());
+        assert!(updater.finish(0).is_err());
+        assert_eq!(updater.status().unwrap(), Status::Idle);
+
+        let new = image(5, 3001);
+        let mut flash = stage(&image(3, 3000), &new);
+        let mut staged = vec![0; new.len()];
+        flash.read(STAGING, &mut staged).unwrap();
+        assert_eq!(staged, new);
+
+        //标记待升级后下载区被改坏，bootloader不交换
+        flash.write(STAGING + 100, &[0, 0]).unwrap();
+        assert_eq!(boot(&mut flash).unwrap(), Status::Rejected);
+        assert_eq!(app(&mut flash, 3000), image(3, 3000));
+        assert_eq!(boot(&mut flash).unwrap(), Status::Rejected);
+    }
+
+    #[test]
+    fn confirm_and_rollback() {
+        let old = image(3, 9000);
+        let new = image(5, 5001);
+        let mut flash = stage(&old, &new);
+        assert_eq!(boot(&mut flash).unwrap(), Status::Testing);
+        assert_eq!(app(&mut flash, new.len()), new);
+        let mut updater = Updater::new(flash);
+        //试运行中不能开始下一次升级
+        assert!(updater.begin(10).is_err());
+        updater.confirm().unwrap();
+        let mut flash = updater.release();
+        assert_eq!(boot(&mut flash).unwrap(), Status::Confirmed);
+        assert_eq!(app(&mut flash, new.len()), new);
+
+        //没有确认就复位，回滚到旧固件
+        let mut flash = stage(&old, &new);
+        boot(&mut flash).unwrap();
+        assert_eq!(boot(&mut flash).unwrap(), Status::RolledBack);
+        assert_eq!(app(&mut flash, old.len()), old);
+        assert_eq!(status(&mut flash).unwrap(), Status::RolledBack);
+        //回滚后确认什么也不做
+        let mut updater = Updater::new(flash);
+        updater.confirm().unwrap();
+        assert_eq!(updater.status().unwrap(), Status::RolledBack);
+    }
+
+    #[test]
+    fn power_loss_during_swap() {
+        let old = image(3, 4100);
+        let new = image(7, 3001);
+        for cut in (0..12000).step_by(37) {
+            let mut flash = stage(&old, &new);
+            flash.cut_power_after(cut);
+            let result = boot(&mut flash);
+            flash.power_on();
+            let status = boot(&mut flash).unwrap();
+            if result.is_ok() {
+                //交换完成后才掉电，再次上电就是试运行中复位
+                assert_eq!(status, Status::RolledBack, "cut {}", cut);
+                assert_eq!(app(&mut flash, old.len()), old, "cut {}", cut);
+                continue;
+            }
+            assert_eq!(status, Status::Testing, "cut {}", cut);
+            assert_eq!(app(&mut flash, new.len()), new, "cut {}", cut);
+
+            //新固件没确认就复位，回滚时又掉电
+            flash.cut_power_after(cut);
+            let _ = boot(&mut flash);
+            flash.power_on();
+            assert_eq!(boot(&mut flash).unwrap(), Status::RolledBack, "cut {}", cut);
+            assert_eq!(app(&mut flash, old.len()), old, "cut {}", cut);
+        }
+    }
+}
@@ -3,22 +3,33 @@ use crate::hal::gpio::gpioa;
 use crate::hal::gpio::gpiob;
 use crate::hal::gpio::gpioc;
 use crate::hal::gpio::gpiod;
-use crate::hal::gpio::{Alternate, Floating, Input, PushPull};
-use crate::hal::pac::interrupt;
+use crate::hal::gpio::{Alternate, Floating, Input, OpenDrain, PushPull};
+use crate::hal::pac::usart1::RegisterBlock;
+use crate::hal::pac::{interrupt, Interrupt};
 use crate::hal::pac::{USART1, USART2, USART3};
 use crate::hal::rcc::{Clocks, APB1, APB2};
 use crate::hal::serial::Pins;
+use crate::hal::serial::Tx;
 use crate::hal::serial::{Config, StopBits};
-use crate::hal::serial::{Rx, Tx};
 use crate::hal::time::U32Ext;
 
-use alloc::collections::VecDeque;
-use core::cell::RefCell;
 use core::convert::Infallible;
-use cortex_m::interrupt::Mutex;
+use embedded_hal::digital::v2::OutputPin;
 use embedded_hal::serial::{Read, Write};
 
-pub struct Serial<'a, USART, PINx, PINy, BUS, CR> {
+#[cfg(feature = "serial-dma")]
+mod dma;
+#[cfg(feature = "serial-dma")]
+pub use dma::{DmaRW, DMA_RX_LEN, USART1_TX_CAPACITY, USART2_TX_CAPACITY, USART3_TX_CAPACITY};
+mod half_duplex;
+pub use half_duplex::HalfDuplex;
+mod pins;
+pub use pins::{FlowControl, NoRx, NoTx, RxOnly, TxOnly};
+mod ring;
+pub use ring::{RingBuffer, RxError, RxQueue, RxStats};
+
+//FC是`rts_cts`传入的(RTS, CTS)引脚，没有硬件流控时是()
+pub struct Serial<'a, USART, PINx, PINy, BUS, CR, FC = ()> {
     usart: USART,
     pins: Option<(PINx, PINy)>,
     clocks: Option<Clocks>,
@@ -26,6 +37,7 @@ pub struct Serial<'a, USART, PINx, PINy, BUS, CR> {
     apb: Option<&'a mut BUS>,
     cr: Option<&'a mut CR>,
     config: Config,
+    flow_control: FC,
 }
 
 impl<'a, USART, PINx, PINy, BUS, CR> Serial<'a, USART, PINx, PINy, BUS, CR> {
@@ -39,14 +51,18 @@ impl<'a, USART, PINx, PINy, BUS, CR> Serial<'a, USART, PINx, PINy, BUS, CR> {
             afio_mapr: None,
             cr: None,
             config: Config::default().baudrate(115200.bps()),
+            flow_control: (),
         }
     }
+}
+
+impl<'a, USART, PINx, PINy, BUS, CR, FC> Serial<'a, USART, PINx, PINy, BUS, CR, FC> {
     //开启AFIO时钟，复用重映射和调试I/O配置寄存器(AFIO_MAPR)
     pub fn afio_mapr(mut self, mapr: &'a mut MAPR) -> Self {
         self.afio_mapr = Some(mapr);
         self
     }
-    //映射到GPIO引脚
+    //映射到GPIO引脚，只发送时rx用NoRx，只接收时tx用NoTx，可用的引脚见pins.rs
     pub fn pins(mut self, tx: PINx, rx: PINy) -> Self {
         self.pins = Some((tx, rx));
         self
@@ -103,6 +119,8 @@ macro_rules! serial {
             $TX:ident,
             $RX:ident,
             $CR:ident,
+            $TXCH:ident,
+            $RXCH:ident,
         ),
     )+) => {
         $(
@@ -129,6 +147,91 @@ macro_rules! serial {
                     let my = self.build();
                     RW::<Tx<$USARTX>>::new(my)
                 }
+
+                //DMA收发，tx_channel和rx_channel来自`DMA1.split()`
+                #[cfg(feature = "serial-dma")]
+                pub fn build_dma(
+                    self,
+                    tx_channel: crate::hal::dma::dma1::$TXCH,
+                    rx_channel: crate::hal::dma::dma1::$RXCH,
+                ) -> DmaRW<$USARTX, crate::hal::dma::dma1::$TXCH, crate::hal::dma::dma1::$RXCH> {
+                    DmaRW::new(self.build(), tx_channel, rx_channel)
+                }
+            }
+
+            $(#[$meta])*
+            impl<'a> Serial<'a, $USARTX, $gpioX::$TX<Input<Floating>>, NoRx, $APBX, $gpioX::$CR> {
+                //只发送，关掉接收器
+                pub fn build(
+                    self,
+                ) -> crate::hal::serial::Serial<$USARTX, TxOnly<$gpioX::$TX<Alternate<PushPull>>>> {
+                    let (tx, _) = self.pins.unwrap();
+                    let tx = tx.into_alternate_push_pull(self.cr.unwrap());
+                    let serial = crate::hal::serial::Serial::$usartX(
+                        self.usart,
+                        TxOnly(tx),
+                        self.afio_mapr.unwrap(),
+                        self.config,
+                        self.clocks.unwrap(),
+                        self.apb.unwrap(),
+                    );
+                    unsafe { &*$USARTX::ptr() }.cr1.modify(|_, w| w.re().clear_bit());
+                    serial
+                }
+
+                //单线半双工，TX引脚开漏输出，同时用来接收
+                pub fn build_single_wire(
+                    self,
+                ) -> crate::hal::serial::Serial<$USARTX, TxOnly<$gpioX::$TX<Alternate<OpenDrain>>>> {
+                    let (tx, _) = self.pins.unwrap();
+                    let tx = tx.into_alternate_open_drain(self.cr.unwrap());
+                    let serial = crate::hal::serial::Serial::$usartX(
+                        self.usart,
+                        TxOnly(tx),
+                        self.afio_mapr.unwrap(),
+                        self.config,
+                        self.clocks.unwrap(),
+                        self.apb.unwrap(),
+                    );
+                    single_wire(unsafe { &*$USARTX::ptr() });
+                    serial
+                }
+
+                //单线半双工，中断接收，自己发出的字节也会收到，可以用`HalfDuplex`跳过
+                pub fn build_single_wire_rw(
+                    self,
+                ) -> RW<Tx<$USARTX>> {
+                    let my = self.build_single_wire();
+                    RW::<Tx<$USARTX>>::new(my)
+                }
+            }
+
+            $(#[$meta])*
+            impl<'a> Serial<'a, $USARTX, NoTx, $gpioX::$RX<Input<Floating>>, $APBX, $gpioX::$CR> {
+                //只接收，关掉发送器，RX引脚保持浮空输入，不需要cr
+                pub fn build(
+                    self,
+                ) -> crate::hal::serial::Serial<$USARTX, RxOnly<$gpioX::$RX<Input<Floating>>>> {
+                    let (_, rx) = self.pins.unwrap();
+                    let serial = crate::hal::serial::Serial::$usartX(
+                        self.usart,
+                        RxOnly(rx),
+                        self.afio_mapr.unwrap(),
+                        self.config,
+                        self.clocks.unwrap(),
+                        self.apb.unwrap(),
+                    );
+                    unsafe { &*$USARTX::ptr() }.cr1.modify(|_, w| w.te().clear_bit());
+                    serial
+                }
+
+                //中断接收，不能用来发送
+                pub fn build_rw(
+                    self,
+                ) -> RW<Tx<$USARTX>> {
+                    let my = self.build();
+                    RW::<Tx<$USARTX>>::new(my)
+                }
             }
         )+
     }
@@ -143,6 +246,8 @@ serial! {
         PA9,
         PA10,
         CRH,
+        C4,
+        C5,
     ),
     /// # USART1 functions
     USART1: (
@@ -152,6 +257,8 @@ serial! {
         PB6,
         PB7,
         CRL,
+        C4,
+        C5,
     ),
     /// # USART2 functions
     USART2: (
@@ -161,6 +268,8 @@ serial! {
         PA2,
         PA3,
         CRL,
+        C7,
+        C6,
     ),
     /// # USART2 functions
     USART2: (
@@ -170,6 +279,8 @@ serial! {
         PD5,
         PD6,
         CRL,
+        C7,
+        C6,
     ),
     /// # USART3 functions
     USART3: (
@@ -179,6 +290,8 @@ serial! {
         PB10,
         PB11,
         CRH,
+        C2,
+        C3,
     ),
     /// # USART3 functions
     USART3: (
@@ -188,6 +301,8 @@ serial! {
         PC10,
         PC11,
         CRH,
+        C2,
+        C3,
     ),
     /// # USART3 functions
     USART3: (
@@ -197,17 +312,127 @@ serial! {
         PD8,
         PD9,
         CRH,
+        C2,
+        C3,
     ),
 }
 
+macro_rules! rts_cts {
+    ($(
+        $USARTX:ident: (
+            $usartX:ident,
+            $APBX:ident,
+            $gpioX:ident,
+            $TX:ident,
+            $RX:ident,
+            $CR:ident,
+            $RTS:ty,
+            $CTS:ty,
+            $TXCH:ident,
+            $RXCH:ident,
+        ),
+    )+) => {
+        $(
+            impl<'a> Serial<'a, $USARTX, $gpioX::$TX<Input<Floating>>, $gpioX::$RX<Input<Floating>>, $APBX, $gpioX::$CR> {
+                //硬件流控，rts要先配置成复用推挽输出，cts保持浮空输入
+                pub fn rts_cts(
+                    self,
+                    rts: $RTS,
+                    cts: $CTS,
+                ) -> Serial<'a, $USARTX, $gpioX::$TX<Input<Floating>>, $gpioX::$RX<Input<Floating>>, $APBX, $gpioX::$CR, ($RTS, $CTS)> {
+                    Serial {
+                        usart: self.usart,
+                        pins: self.pins,
+                        clocks: self.clocks,
+                        afio_mapr: self.afio_mapr,
+                        apb: self.apb,
+                        cr: self.cr,
+                        config: self.config,
+                        flow_control: (rts, cts),
+                    }
+                }
+            }
+
+            impl<'a> Serial<'a, $USARTX, $gpioX::$TX<Input<Floating>>, $gpioX::$RX<Input<Floating>>, $APBX, $gpioX::$CR, ($RTS, $CTS)> {
+                //rts、cts和tx、rx一起放在hal的Serial里，release时取回
+                pub fn build(
+                    self,
+                ) -> crate::hal::serial::Serial<$USARTX, FlowControl<($gpioX::$TX<Alternate<PushPull>>, $gpioX::$RX<Input<Floating>>), $RTS, $CTS>> {
+                    let (tx, rx) = self.pins.unwrap();
+                    let tx = tx.into_alternate_push_pull(self.cr.unwrap());
+                    let (rts, cts) = self.flow_control;
+                    let serial = crate::hal::serial::Serial::$usartX(
+                        self.usart,
+                        FlowControl {
+                            pins: (tx, rx),
+                            rts,
+                            cts,
+                        },
+                        self.afio_mapr.unwrap(),
+                        self.config,
+                        self.clocks.unwrap(),
+                        self.apb.unwrap(),
+                    );
+                    flow_control(unsafe { &*$USARTX::ptr() });
+                    serial
+                }
+
+                pub fn build_rw(
+                    self,
+                ) -> RW<Tx<$USARTX>> {
+                    let my = self.build();
+                    RW::<Tx<$USARTX>>::new(my)
+                }
+
+                #[cfg(feature = "serial-dma")]
+                pub fn build_dma(
+                    self,
+                    tx_channel: crate::hal::dma::dma1::$TXCH,
+                    rx_channel: crate::hal::dma::dma1::$RXCH,
+                ) -> DmaRW<$USARTX, crate::hal::dma::dma1::$TXCH, crate::hal::dma::dma1::$RXCH> {
+                    DmaRW::new(self.build(), tx_channel, rx_channel)
+                }
+            }
+        )+
+    }
+}
+
+rts_cts! {
+    USART2: (usart2, APB1, gpioa, PA2, PA3, CRL, gpioa::PA1<Alternate<PushPull>>, gpioa::PA0<Input<Floating>>, C7, C6,),
+    USART2: (usart2, APB1, gpiod, PD5, PD6, CRL, gpiod::PD4<Alternate<PushPull>>, gpiod::PD3<Input<Floating>>, C7, C6,),
+    USART3: (usart3, APB1, gpiob, PB10, PB11, CRH, gpiob::PB14<Alternate<PushPull>>, gpiob::PB13<Input<Floating>>, C2, C3,),
+    USART3: (usart3, APB1, gpioc, PC10, PC11, CRH, gpiob::PB14<Alternate<PushPull>>, gpiob::PB13<Input<Floating>>, C2, C3,),
+    USART3: (usart3, APB1, gpiod, PD8, PD9, CRH, gpiod::PD12<Alternate<PushPull>>, gpiod::PD11<Input<Floating>>, C2, C3,),
+}
+
+//HDSEL只能在UE为0时修改
+fn single_wire(usart: &RegisterBlock) {
+    usart.cr1.modify(|_, w| w.ue().clear_bit());
+    usart.cr3.modify(|_, w| w.hdsel().set_bit());
+    usart.cr1.modify(|_, w| w.ue().set_bit());
+}
+
+//RTS在接收缓冲区有数据没读时变高让对方暂停，CTS为高时暂停发送
+fn flow_control(usart: &RegisterBlock) {
+    usart.cr3.modify(|_, w| w.rtse().set_bit().ctse().set_bit());
+}
+
+/// 中断接收的串口，收到的字节放进定长的接收队列，读的时候不关中断
 pub struct RW<W> {
     tx: W,
 }
 
+impl<W> RW<W> {
+    //RS-485，发送前拉高de，flush等到发送完成后拉低
+    pub fn half_duplex<DE: OutputPin>(self, de: DE) -> HalfDuplex<Self, DE> {
+        HalfDuplex::new(self, de)
+    }
+}
+
 macro_rules! rw {
     ($(
         $(#[$meta:meta])*
-        $USARTX:ident: ($RXX:ident, $BUFX:ident),
+        $USARTX:ident: $RXX:ident,
     )+) => {
         $(
             $(#[$meta])*
@@ -218,14 +443,21 @@ macro_rules! rw {
                 PINS: Pins<$USARTX>,
             {
                 let (tx, mut rx) = serial.split();
+                $RXX.clear();
                 rx.listen();
-                cortex_m::interrupt::free(|cs| unsafe{
-                    $RXX.replace(rx);
-                    $BUFX.borrow(cs).replace(Some(VecDeque::with_capacity(64)));
-                });
                 crate::enable_interrupt(crate::hal::pac::Interrupt::$USARTX);
                 Self { tx }
             }
+
+            //接收队列里还没读的字节数
+            pub fn available(&self) -> usize {
+                $RXX.len()
+            }
+
+            //接收统计，队列满丢掉的字节和硬件报告的错误
+            pub fn stats(&self) -> RxStats {
+                $RXX.stats()
+            }
         }
 
         impl Write<u8> for RW<Tx<$USARTX>> {
@@ -243,21 +475,7 @@ macro_rules! rw {
         impl Read<u8> for RW<Tx<$USARTX>> {
             type Error = crate::io::Error;
             fn read(&mut self) -> nb::Result<u8, Self::Error> {
-                cortex_m::interrupt::free(|cs| {
-                    match $BUFX.borrow(cs).borrow_mut().deref_mut() {
-                        Some(buf) => {
-                            match buf.pop_front() {
-                                Some(w) => {
-                                    return Ok(w);
-                                }
-                                None => {
-                                    return Err(nb::Error::WouldBlock);
-                                }
-                            }
-                        }
-                        None => return Err(nb::Error::Other(crate::io::Error::NoIoDevice)),
-                    }
-                })
+                $RXX.pop().ok_or(nb::Error::WouldBlock)
             }
         }
         )+
@@ -265,60 +483,78 @@ macro_rules! rw {
 }
 
 rw! {
-    USART1:(RX1, TX1_BUFFER),
-    USART2:(RX2, TX2_BUFFER),
-    USART3:(RX3, TX3_BUFFER),
+    USART1: RX1,
+    USART2: RX2,
+    USART3: RX3,
 }
 
-static mut RX1: Option<Rx<USART1>> = None;
-static TX1_BUFFER: Mutex<RefCell<Option<VecDeque<u8>>>> = Mutex::new(RefCell::new(None));
+//接收队列的字节数，编译时可以用环境变量修改，比如
+//BLUEPILL_USART2_RX=1024 cargo build --release --example esp8266
+pub const USART1_RX_CAPACITY: usize = ring::capacity(option_env!("BLUEPILL_USART1_RX"), 128);
+pub const USART2_RX_CAPACITY: usize = ring::capacity(option_env!("BLUEPILL_USART2_RX"), 512);
+pub const USART3_RX_CAPACITY: usize = ring::capacity(option_env!("BLUEPILL_USART3_RX"), 128);
 
-static mut RX2: Option<Rx<USART2>> = None;
-static TX2_BUFFER: Mutex<RefCell<Option<VecDeque<u8>>>> = Mutex::new(RefCell::new(None));
+static RX1: RxQueue<USART1_RX_CAPACITY> = RxQueue::new();
+static RX2: RxQueue<USART2_RX_CAPACITY> = RxQueue::new();
+static RX3: RxQueue<USART3_RX_CAPACITY> = RxQueue::new();
 
-static mut RX3: Option<Rx<USART3>> = None;
-static TX3_BUFFER: Mutex<RefCell<Option<VecDeque<u8>>>> = Mutex::new(RefCell::new(None));
+//记录SR里的错误标志，读DR后清除
+fn count_errors<const N: usize>(sr: &crate::hal::pac::usart1::sr::R, queue: &RxQueue<N>) {
+    if sr.ore().bit_is_set() {
+        queue.error(RxError::Overrun);
+    }
+    if sr.fe().bit_is_set() {
+        queue.error(RxError::Framing);
+    }
+    if sr.ne().bit_is_set() {
+        queue.error(RxError::Noise);
+    }
+    if sr.pe().bit_is_set() {
+        queue.error(RxError::Parity);
+    }
+}
 
-#[interrupt]
-unsafe fn USART1() {
-    if let Some(rx) = RX1.as_mut() {
-        if let Ok(w) = nb::block!(rx.read()) {
-            cortex_m::interrupt::free(|cs| {
-                if let Some(buf) = TX1_BUFFER.borrow(cs).borrow_mut().deref_mut() {
-                    buf.push_back(w);
-                }
-            });
+//先读SR再读DR，收到的字节放进队列，同时清掉错误标志
+fn receive<const N: usize>(usart: &RegisterBlock, queue: &RxQueue<N>) {
+    let sr = usart.sr.read();
+    count_errors(&sr, queue);
+    if sr.rxne().bit_is_set() || sr.ore().bit_is_set() {
+        let byte = usart.dr.read().dr().bits() as u8;
+        //帧错误和校验错误的字节不可信
+        if sr.fe().bit_is_clear() && sr.pe().bit_is_clear() {
+            queue.push(byte);
         }
     }
 }
 
-use core::ops::DerefMut;
-#[interrupt]
-unsafe fn USART2() {
-    // static mut RX: Option<Rx<USART2>> = None;
-    // let rx = RX.get_or_insert_with(|| {
-    //     cortex_m::interrupt::free(|cs| RX2.borrow(cs).replace(None).unwrap())
-    // });
-    if let Some(rx) = RX2.as_mut() {
-        if let Ok(w) = nb::block!(rx.read()) {
-            cortex_m::interrupt::free(|cs| {
-                if let Some(buf) = TX2_BUFFER.borrow(cs).borrow_mut().deref_mut() {
-                    buf.push_back(w);
-                }
-            });
-        }
+//DMA接收时串口中断只有IDLE：同样先读SR再读DR清掉标志，再挂起DMA接收通道的中断，
+//由它把缓冲区里不满半个的字节搬走
+fn idle<const N: usize>(usart: &RegisterBlock, queue: &RxQueue<N>, dma_rx: Interrupt) {
+    let sr = usart.sr.read();
+    count_errors(&sr, queue);
+    let _ = usart.dr.read();
+    cortex_m::peripheral::NVIC::pend(dma_rx);
+}
+
+fn on_interrupt<const N: usize>(usart: &RegisterBlock, queue: &RxQueue<N>, dma_rx: Interrupt) {
+    if cfg!(feature = "serial-dma") && usart.cr3.read().dmar().bit_is_set() {
+        idle(usart, queue, dma_rx);
+    } else {
+        receive(usart, queue);
     }
 }
 
 #[interrupt]
-unsafe fn USART3() {
-    if let Some(rx) = RX3.as_mut() {
-        if let Ok(w) = nb::block!(rx.read()) {
-            cortex_m::interrupt::free(|cs| {
-                if let Some(buf) = TX3_BUFFER.borrow(cs).borrow_mut().deref_mut() {
-                    buf.push_back(w);
-                }
-            });
-        }
-    }
+fn USART1() {
+    on_interrupt(unsafe { &*USART1::ptr() }, &RX1, Interrupt::DMA1_CHANNEL5);
+}
+
+#[interrupt]
+fn USART2() {
+    on_interrupt(unsafe { &*USART2::ptr() }, &RX2, Interrupt::DMA1_CHANNEL6);
+}
+
+#[interrupt]
+fn USART3() {
+    on_interrupt(unsafe { &*USART3::ptr() }, &RX3, Interrupt::DMA1_CHANNEL3);
 }
@@ -0,0 +1,292 @@
+//! DMA收发的串口
+//!
+//! 接收：DMA循环写入一块缓冲区，半满、全满和串口空闲(IDLE)时在中断里把新到的字节搬进`RxQueue`，
+//! CPU忙着刷墨水屏时最多可以晚半个缓冲区再处理，不会像每字节一次中断那样丢字节。
+//! 发送：`write`只把字节放进发送队列，DMA每发完一段在中断里接着发下一段，不阻塞
+//!
+//! USART1用DMA1的通道4(TX)/5(RX)，USART2用7/6，USART3用2/3。
+//! 这些通道的中断由这里处理，所以要开启`serial-dma`特性
+
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use embedded_hal::serial::{Read, Write};
+
+use super::ring::{self, RingBuffer};
+use super::{RxStats, RX1, RX2, RX3};
+use crate::hal::dma::dma1::{C2, C3, C4, C5, C6, C7};
+use crate::hal::pac::dma1::CH;
+use crate::hal::pac::usart1::RegisterBlock;
+use crate::hal::pac::{interrupt, Interrupt, DMA1, USART1, USART2, USART3};
+use crate::hal::serial::{Pins, Rx, Serial, Tx};
+
+//接收缓冲区，115200波特率时半个缓冲区约2.8ms
+pub const DMA_RX_LEN: usize = 64;
+//每次DMA发送的最大字节数
+const DMA_TX_LEN: usize = 64;
+
+//发送队列的字节数，和接收队列一样可以用环境变量修改
+pub const USART1_TX_CAPACITY: usize = ring::capacity(option_env!("BLUEPILL_USART1_TX"), 256);
+pub const USART2_TX_CAPACITY: usize = ring::capacity(option_env!("BLUEPILL_USART2_TX"), 256);
+pub const USART3_TX_CAPACITY: usize = ring::capacity(option_env!("BLUEPILL_USART3_TX"), 256);
+
+static PORT1: DmaPort<USART1_TX_CAPACITY> = DmaPort::new();
+static PORT2: DmaPort<USART2_TX_CAPACITY> = DmaPort::new();
+static PORT3: DmaPort<USART3_TX_CAPACITY> = DmaPort::new();
+
+//DMA直接读写的内存
+struct DmaBuffer<const N: usize>(UnsafeCell<[u8; N]>);
+
+unsafe impl<const N: usize> Sync for DmaBuffer<N> {}
+
+impl<const N: usize> DmaBuffer<N> {
+    const fn new() -> Self {
+        Self(UnsafeCell::new([0; N]))
+    }
+
+    fn address(&self) -> u32 {
+        self.0.get() as u32
+    }
+
+    //DMA可能同时在写，要用volatile
+    fn get(&self, index: usize) -> u8 {
+        unsafe { core::ptr::read_volatile((self.0.get() as *const u8).add(index)) }
+    }
+
+    fn set(&self, index: usize, byte: u8) {
+        unsafe { core::ptr::write_volatile((self.0.get() as *mut u8).add(index), byte) }
+    }
+}
+
+//一个串口的DMA缓冲区和发送队列，中断和`DmaRW`共用。
+//接收的两个中断(DMA和串口IDLE)优先级相同，不会互相打断
+struct DmaPort<const TX: usize> {
+    rx: DmaBuffer<DMA_RX_LEN>,
+    rx_read: AtomicUsize, //rx里已经搬走的位置，只在中断里修改
+    tx_queue: RingBuffer<TX>,
+    tx: DmaBuffer<DMA_TX_LEN>,
+}
+
+impl<const TX: usize> DmaPort<TX> {
+    const fn new() -> Self {
+        Self {
+            rx: DmaBuffer::new(),
+            rx_read: AtomicUsize::new(0),
+            tx_queue: RingBuffer::new(),
+            tx: DmaBuffer::new(),
+        }
+    }
+
+    //中断里调用，把DMA新写入的字节搬进接收队列
+    fn drain<const N: usize>(&self, ch: &CH, queue: &super::RxQueue<N>) {
+        //NDTR是这一圈还剩的字节数，刚重装时等于DMA_RX_LEN
+        let write = (DMA_RX_LEN - ch.ndtr.read().bits() as usize) % DMA_RX_LEN;
+        let mut read = self.rx_read.load(Ordering::Relaxed);
+        while read != write {
+            queue.push(self.rx.get(read));
+            read = (read + 1) % DMA_RX_LEN;
+        }
+        self.rx_read.store(read, Ordering::Relaxed);
+    }
+
+    //中断里调用，上一段发完后把队列里的字节交给DMA
+    fn transmit(&self, usart: &RegisterBlock, ch: &CH) {
+        if busy(ch) {
+            return;
+        }
+        let mut len = 0;
+        while len < DMA_TX_LEN {
+            match self.tx_queue.pop() {
+                Some(byte) => self.tx.set(len, byte),
+                None => break,
+            }
+            len += 1;
+        }
+        if len == 0 {
+            return;
+        }
+        //改NDTR之前必须先关掉通道
+        ch.cr.modify(|_, w| w.en().clear_bit());
+        ch.mar.write(|w| unsafe { w.bits(self.tx.address()) });
+        ch.ndtr.write(|w| unsafe { w.bits(len as u32) });
+        //上一段留下的TC还是1，不清掉的话flush会在这一段发完前返回
+        usart.sr.modify(|_, w| w.tc().clear_bit());
+        ch.cr.modify(|_, w| w.en().set_bit());
+    }
+}
+
+//通道开着并且还有没传完的字节
+fn busy(ch: &CH) -> bool {
+    ch.cr.read().en().bit_is_set() && ch.ndtr.read().bits() != 0
+}
+
+//清掉通道的全部中断标志，channel从1开始
+fn clear_flags(channel: u8) {
+    let dma = unsafe { &*DMA1::ptr() };
+    dma.ifcr
+        .write(|w| unsafe { w.bits(0xf << (4 * (channel as u32 - 1))) });
+}
+
+/// DMA收发的串口，`build_dma`创建
+pub struct DmaRW<USART, TXCH, RXCH> {
+    //只是占住串口和DMA通道，寄存器在中断里直接访问
+    _tx: Tx<USART>,
+    _rx: Rx<USART>,
+    _tx_channel: TXCH,
+    _rx_channel: RXCH,
+}
+
+macro_rules! dma {
+    ($(
+        $USARTX:ident: (
+            $PORT:ident,
+            $RXX:ident,
+            $TXCH:ident,
+            $txch:ident,
+            $tx_channel:expr,
+            $TXINT:ident,
+            $RXCH:ident,
+            $rxch:ident,
+            $rx_channel:expr,
+            $RXINT:ident,
+        ),
+    )+) => {
+        $(
+        impl DmaRW<$USARTX, $TXCH, $RXCH> {
+            pub fn new<PINS>(serial: Serial<$USARTX, PINS>, tx_channel: $TXCH, rx_channel: $RXCH) -> Self
+            where
+                PINS: Pins<$USARTX>,
+            {
+                let (tx, rx) = serial.split();
+                let usart = unsafe { &*$USARTX::ptr() };
+                let dma = unsafe { &*DMA1::ptr() };
+                let dr = &usart.dr as *const _ as u32;
+
+                dma.$rxch.cr.modify(|_, w| w.en().clear_bit());
+                dma.$txch.cr.modify(|_, w| w.en().clear_bit());
+                clear_flags($rx_channel);
+                clear_flags($tx_channel);
+                $RXX.clear();
+                $PORT.tx_queue.clear();
+                $PORT.rx_read.store(0, Ordering::Relaxed);
+
+                //接收：循环模式，半满和全满时中断
+                dma.$rxch.par.write(|w| unsafe { w.bits(dr) });
+                dma.$rxch.mar.write(|w| unsafe { w.bits($PORT.rx.address()) });
+                dma.$rxch.ndtr.write(|w| unsafe { w.bits(DMA_RX_LEN as u32) });
+                dma.$rxch.cr.write(|w| {
+                    w.mem2mem().clear_bit()
+                        .pl().high()
+                        .msize().bits8()
+                        .psize().bits8()
+                        .minc().set_bit()
+                        .pinc().clear_bit()
+                        .circ().set_bit()
+                        .dir().clear_bit()
+                        .htie().set_bit()
+                        .tcie().set_bit()
+                        .en().set_bit()
+                });
+                //发送：每段发完中断一次，通道在transmit里打开
+                dma.$txch.par.write(|w| unsafe { w.bits(dr) });
+                dma.$txch.cr.write(|w| {
+                    w.mem2mem().clear_bit()
+                        .pl().medium()
+                        .msize().bits8()
+                        .psize().bits8()
+                        .minc().set_bit()
+                        .pinc().clear_bit()
+                        .circ().clear_bit()
+                        .dir().set_bit()
+                        .tcie().set_bit()
+                });
+
+                usart.cr3.modify(|_, w| w.dmar().set_bit().dmat().set_bit());
+                usart.cr1.modify(|_, w| w.idleie().set_bit());
+                crate::enable_interrupt(Interrupt::$RXINT);
+                crate::enable_interrupt(Interrupt::$TXINT);
+                crate::enable_interrupt(Interrupt::$USARTX);
+                Self {
+                    _tx: tx,
+                    _rx: rx,
+                    _tx_channel: tx_channel,
+                    _rx_channel: rx_channel,
+                }
+            }
+
+            //接收队列里还没读的字节数，DMA缓冲区里还没搬过来的不算
+            pub fn available(&self) -> usize {
+                $RXX.len()
+            }
+
+            //接收统计，队列满丢掉的字节和硬件报告的错误
+            pub fn stats(&self) -> RxStats {
+                $RXX.stats()
+            }
+
+            //发送队列里还没交给DMA的字节数
+            pub fn pending(&self) -> usize {
+                $PORT.tx_queue.len()
+            }
+        }
+
+        impl Write<u8> for DmaRW<$USARTX, $TXCH, $RXCH> {
+            type Error = Infallible;
+
+            //队列满时返回WouldBlock
+            fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+                let queued = $PORT.tx_queue.push(word);
+                //DMA空闲时由中断启动发送，队列只在中断里取
+                NVIC::pend(Interrupt::$TXINT);
+                if queued {
+                    Ok(())
+                } else {
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+
+            //等到最后一个字节从移位寄存器发出去
+            fn flush(&mut self) -> nb::Result<(), Self::Error> {
+                let usart = unsafe { &*$USARTX::ptr() };
+                let dma = unsafe { &*DMA1::ptr() };
+                if !$PORT.tx_queue.is_empty()
+                    || busy(&dma.$txch)
+                    || usart.sr.read().tc().bit_is_clear()
+                {
+                    return Err(nb::Error::WouldBlock);
+                }
+                Ok(())
+            }
+        }
+
+        impl Read<u8> for DmaRW<$USARTX, $TXCH, $RXCH> {
+            type Error = crate::io::Error;
+            fn read(&mut self) -> nb::Result<u8, Self::Error> {
+                $RXX.pop().ok_or(nb::Error::WouldBlock)
+            }
+        }
+
+        //半满、全满，或者串口IDLE中断挂起的
+        #[interrupt]
+        fn $RXINT() {
+            clear_flags($rx_channel);
+            $PORT.drain(unsafe { &(*DMA1::ptr()).$rxch }, &$RXX);
+        }
+
+        //一段发完，或者write挂起的
+        #[interrupt]
+        fn $TXINT() {
+            clear_flags($tx_channel);
+            $PORT.transmit(unsafe { &*$USARTX::ptr() }, unsafe { &(*DMA1::ptr()).$txch });
+        }
+        )+
+    }
+}
+
+dma! {
+    USART1: (PORT1, RX1, C4, ch4, 4, DMA1_CHANNEL4, C5, ch5, 5, DMA1_CHANNEL5,),
+    USART2: (PORT2, RX2, C7, ch7, 7, DMA1_CHANNEL7, C6, ch6, 6, DMA1_CHANNEL6,),
+    USART3: (PORT3, RX3, C2, ch2, 2, DMA1_CHANNEL2, C3, ch3, 3, DMA1_CHANNEL3,),
+}
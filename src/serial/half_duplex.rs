@@ -0,0 +1,93 @@
+//! 半双工串口
+//!
+//! RS-485通过MAX485收发，发送前拉高DE，发送完成(TC)后拉低；单线半双工(HDSEL)的TX引脚
+//! 同时用来收发，不需要DE，用`NoPin`占位。两种接法发出去的字节都可能被自己收到，
+//! 开启`suppress_echo`后读的时候跳过
+//!
+//! 每发完一帧要调用`flush`，否则DE一直拉高，对方的应答收不到
+
+use crate::gpio::NoPin;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::serial::{Read, Write};
+
+pub struct HalfDuplex<S, DE> {
+    serial: S,
+    de: DE,
+    sending: bool,       //DE已拉高
+    suppress_echo: bool, //跳过自己发出的字节
+    echo: usize,         //还没跳过的回显字节数
+}
+
+impl<S> HalfDuplex<S, NoPin> {
+    //单线半双工，不需要DE
+    pub fn single_wire(serial: S) -> Self {
+        Self::new(serial, NoPin)
+    }
+}
+
+impl<S, DE: OutputPin> HalfDuplex<S, DE> {
+    pub fn new(serial: S, mut de: DE) -> Self {
+        de.set_low().ok();
+        Self {
+            serial,
+            de,
+            sending: false,
+            suppress_echo: false,
+            echo: 0,
+        }
+    }
+
+    //读的时候跳过自己发出的字节，MAX485的RE接地时，以及单线半双工时需要
+    pub fn suppress_echo(mut self, suppress: bool) -> Self {
+        self.suppress_echo = suppress;
+        self
+    }
+
+    pub fn serial(&mut self) -> &mut S {
+        &mut self.serial
+    }
+
+    pub fn release(self) -> (S, DE) {
+        (self.serial, self.de)
+    }
+}
+
+impl<S: Write<u8>, DE: OutputPin> Write<u8> for HalfDuplex<S, DE> {
+    type Error = S::Error;
+
+    fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        if !self.sending {
+            self.de.set_high().ok();
+            self.sending = true;
+        }
+        self.serial.write(word)?;
+        if self.suppress_echo {
+            self.echo += 1;
+        }
+        Ok(())
+    }
+
+    //等到TC置位，最后一个字节的停止位发完后再释放总线
+    fn flush(&mut self) -> nb::Result<(), Self::Error> {
+        self.serial.flush()?;
+        if self.sending {
+            self.de.set_low().ok();
+            self.sending = false;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read<u8>, DE> Read<u8> for HalfDuplex<S, DE> {
+    type Error = S::Error;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        loop {
+            let byte = self.serial.read()?;
+            if self.echo == 0 {
+                return Ok(byte);
+            }
+            self.echo -= 1;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! 串口引脚组合
+//!
+//! AFIO重映射由引脚类型决定：hal的`Serial::usartX`按`Pins::REMAP`写MAPR，
+//! 这里补上只发送、只接收的组合。`pins(tx, NoRx)`只发送，`pins(NoTx, rx)`只接收，
+//! 没用到的引脚可以另作他用。单线半双工也是`pins(tx, NoRx)`，TX引脚配置成开漏，
+//! 要接上拉电阻。RTS/CTS见`Serial::rts_cts`
+//!
+//! | 串口   | REMAP | TX   | RX   | RTS  | CTS  |
+//! | ------ | ----- | ---- | ---- | ---- | ---- |
+//! | USART1 | 0     | PA9  | PA10 |      |      |
+//! | USART1 | 1     | PB6  | PB7  |      |      |
+//! | USART2 | 0     | PA2  | PA3  | PA1  | PA0  |
+//! | USART2 | 1     | PD5  | PD6  | PD4  | PD3  |
+//! | USART3 | 0     | PB10 | PB11 | PB14 | PB13 |
+//! | USART3 | 1     | PC10 | PC11 | PB14 | PB13 |
+//! | USART3 | 3     | PD8  | PD9  | PD12 | PD11 |
+//!
+//! C8T6没有PC10/PC11和PD口的这些引脚，只有更多引脚的型号能用
+
+use crate::hal::gpio::{gpioa, gpiob, gpioc, gpiod};
+use crate::hal::gpio::{Alternate, Floating, Input, OpenDrain, PushPull};
+use crate::hal::pac::{USART1, USART2, USART3};
+use crate::hal::serial::Pins;
+
+/// 只接收，不占用TX引脚
+pub struct NoTx;
+
+/// 只发送，不占用RX引脚
+pub struct NoRx;
+
+/// 只发送的串口引脚，`release`时取回
+pub struct TxOnly<TX>(pub TX);
+
+/// 只接收的串口引脚，`release`时取回
+pub struct RxOnly<RX>(pub RX);
+
+/// 带硬件流控的串口引脚，`Serial::rts_cts`之后`build`得到，`release`时连同RTS、CTS一起取回
+pub struct FlowControl<PINS, RTS, CTS> {
+    pub pins: PINS,
+    pub rts: RTS,
+    pub cts: CTS,
+}
+
+//RTS、CTS跟着TX、RX重映射，引脚类型由`rts_cts`保证
+impl<USART, PINS: Pins<USART>, RTS, CTS> Pins<USART> for FlowControl<PINS, RTS, CTS> {
+    const REMAP: u8 = PINS::REMAP;
+}
+
+macro_rules! remap {
+    ($(
+        $USARTX:ident: ($gpioX:ident, $TX:ident, $RX:ident, $REMAP:expr),
+    )+) => {
+        $(
+            impl Pins<$USARTX> for TxOnly<$gpioX::$TX<Alternate<PushPull>>> {
+                const REMAP: u8 = $REMAP;
+            }
+
+            //单线半双工
+            impl Pins<$USARTX> for TxOnly<$gpioX::$TX<Alternate<OpenDrain>>> {
+                const REMAP: u8 = $REMAP;
+            }
+
+            impl Pins<$USARTX> for RxOnly<$gpioX::$RX<Input<Floating>>> {
+                const REMAP: u8 = $REMAP;
+            }
+        )+
+    }
+}
+
+remap! {
+    USART1: (gpioa, PA9, PA10, 0),
+    USART1: (gpiob, PB6, PB7, 1),
+    USART2: (gpioa, PA2, PA3, 0),
+    USART2: (gpiod, PD5, PD6, 1),
+    USART3: (gpiob, PB10, PB11, 0),
+    USART3: (gpioc, PC10, PC11, 1),
+    USART3: (gpiod, PD8, PD9, 0b11),
+}
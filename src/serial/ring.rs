@@ -0,0 +1,332 @@
+//! 串口接收队列
+//!
+//! 单生产者单消费者的定长字节队列：串口中断里`push`，`RW::read`里`pop`，
+//! 只用原子变量同步，不关中断也不分配内存。队列满时新收到的字节被丢掉并计数
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    //读写位置在0..2N里循环，区分空和满；N不必是2的幂
+    head: AtomicUsize, //读位置，只有消费者修改
+    tail: AtomicUsize, //写位置，只有生产者修改
+}
+
+//push只在一个地方调用、pop只在另一个地方调用时是安全的
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    //生产者调用，队列满时返回false
+    pub fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if distance(head, tail, N) >= N {
+            return false;
+        }
+        unsafe { (*self.buf.get())[tail % N] = byte };
+        self.tail.store(next(tail, N), Ordering::Release);
+        true
+    }
+
+    //消费者调用
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let byte = unsafe { (*self.buf.get())[head % N] };
+        self.head.store(next(head, N), Ordering::Release);
+        Some(byte)
+    }
+
+    //消费者调用，丢掉队列里所有的字节
+    pub fn clear(&self) {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.store(tail, Ordering::Release);
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        distance(head, self.tail.load(Ordering::Acquire), N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+//从head到tail有几个字节
+fn distance(head: usize, tail: usize, n: usize) -> usize {
+    if tail >= head {
+        tail - head
+    } else {
+        tail + 2 * n - head
+    }
+}
+
+fn next(index: usize, n: usize) -> usize {
+    if index + 1 == 2 * n {
+        0
+    } else {
+        index + 1
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 接收错误，和`hal::serial::Error`一一对应
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RxError {
+    Overrun, //中断来不及读DR，硬件丢了字节
+    Framing, //通常是波特率不对
+    Noise,
+    Parity,
+}
+
+/// 接收统计，从上电开始累计
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct RxStats {
+    pub received: u32, //放进队列的字节数
+    pub dropped: u32,  //队列满丢掉的字节数
+    pub overrun: u32,
+    pub framing: u32,
+    pub noise: u32,
+    pub parity: u32,
+}
+
+/// 一个串口的接收队列和错误计数，放在static里由中断和`RW`共用
+pub struct RxQueue<const N: usize> {
+    buffer: RingBuffer<N>,
+    received: AtomicU32,
+    dropped: AtomicU32,
+    overrun: AtomicU32,
+    framing: AtomicU32,
+    noise: AtomicU32,
+    parity: AtomicU32,
+}
+
+impl<const N: usize> RxQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: RingBuffer::new(),
+            received: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+            overrun: AtomicU32::new(0),
+            framing: AtomicU32::new(0),
+            noise: AtomicU32::new(0),
+            parity: AtomicU32::new(0),
+        }
+    }
+
+    //中断里调用
+    pub fn push(&self, byte: u8) {
+        let counter = if self.buffer.push(byte) {
+            &self.received
+        } else {
+            &self.dropped
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //中断里调用
+    pub fn error(&self, error: RxError) {
+        let counter = match error {
+            RxError::Overrun => &self.overrun,
+            RxError::Framing => &self.framing,
+            RxError::Noise => &self.noise,
+            RxError::Parity => &self.parity,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        self.buffer.pop()
+    }
+
+    pub fn clear(&self) {
+        self.buffer.clear()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn stats(&self) -> RxStats {
+        RxStats {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            overrun: self.overrun.load(Ordering::Relaxed),
+            framing: self.framing.load(Ordering::Relaxed),
+            noise: self.noise.load(Ordering::Relaxed),
+            parity: self.parity.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<const N: usize> Default for RxQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//解析编译时的环境变量，没设置时用default
+pub(crate) const fn capacity(value: Option<&str>, default: usize) -> usize {
+    let digits = match value {
+        Some(value) => value.as_bytes(),
+        None => return default,
+    };
+    let mut capacity = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        let digit = digits[i];
+        if digit < b'0' || digit > b'9' {
+            panic!("serial rx capacity must be a decimal number");
+        }
+        capacity = capacity * 10 + (digit - b'0') as usize;
+        i += 1;
+    }
+    if capacity == 0 {
+        panic!("serial rx capacity must not be 0");
+    }
+    capacity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_pop() {
+        let ring: RingBuffer<4> = RingBuffer::new();
+        assert!(ring.is_empty());
+        assert_eq!(ring.pop(), None);
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+        ring.push(3);
+        ring.clear();
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn full() {
+        let ring: RingBuffer<3> = RingBuffer::new();
+        for byte in 0..3 {
+            assert!(ring.push(byte));
+        }
+        assert!(!ring.push(9));
+        assert_eq!(ring.len(), ring.capacity());
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(3));
+        assert!(!ring.push(9));
+        for byte in 1..4 {
+            assert_eq!(ring.pop(), Some(byte));
+        }
+        assert!(ring.is_empty());
+    }
+
+    //N不是2的幂时，读写位置绕回多次后数据和长度都不能错
+    #[test]
+    fn wrap() {
+        let ring: RingBuffer<5> = RingBuffer::new();
+        let mut pushed = 0u32;
+        let mut popped = 0u32;
+        for round in 0..200 {
+            for _ in 0..round % 6 {
+                if ring.push(pushed as u8) {
+                    pushed += 1;
+                }
+            }
+            assert_eq!(ring.len(), (pushed - popped) as usize);
+            for _ in 0..round % 4 {
+                if let Some(byte) = ring.pop() {
+                    assert_eq!(byte, popped as u8);
+                    popped += 1;
+                }
+            }
+        }
+        while let Some(byte) = ring.pop() {
+            assert_eq!(byte, popped as u8);
+            popped += 1;
+        }
+        assert_eq!(pushed, popped);
+        assert!(pushed > 100);
+    }
+
+    #[test]
+    fn wrap_at_last_index() {
+        let ring: RingBuffer<3> = RingBuffer::new();
+        ring.head.store(5, Ordering::Relaxed);
+        ring.tail.store(5, Ordering::Relaxed);
+        for byte in 0..3 {
+            assert!(ring.push(byte));
+        }
+        assert!(!ring.push(9));
+        assert_eq!(ring.tail.load(Ordering::Relaxed), 2);
+        assert_eq!(ring.len(), 3);
+        for byte in 0..3 {
+            assert_eq!(ring.pop(), Some(byte));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn rx_queue_stats() {
+        let queue: RxQueue<8> = RxQueue::new();
+        for byte in 0..10 {
+            queue.push(byte);
+        }
+        assert_eq!(queue.len(), 8);
+        assert_eq!(
+            queue.stats(),
+            RxStats {
+                received: 8,
+                dropped: 2,
+                ..Default::default()
+            }
+        );
+        queue.error(RxError::Framing);
+        queue.error(RxError::Overrun);
+        queue.error(RxError::Overrun);
+        let stats = queue.stats();
+        assert_eq!((stats.framing, stats.overrun, stats.noise), (1, 2, 0));
+        assert_eq!(queue.pop(), Some(0));
+        queue.clear();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn capacity_from_env() {
+        assert_eq!(capacity(None, 64), 64);
+        assert_eq!(capacity(Some("100"), 64), 100);
+    }
+}
@@ -0,0 +1,57 @@
+//! 片内flash存储
+//!
+//! `Flash`是对NOR flash的最小抽象：按页擦除(擦除后全是0xFF)，按半字(2字节)编程，
+//! 编程只能把1变成0。`sim::SimFlash`在内存里模拟它并可以在任意一次写入处掉电，
+//! `kv::KvStore`在它上面实现掉电安全的键值存储
+
+pub mod kv;
+pub mod sim;
+pub mod stm32;
+
+pub use kv::KvStore;
+pub use sim::SimFlash;
+
+use crate::io::Result;
+
+pub trait Flash {
+    //每页字节数，STM32F103C8是1K
+    fn page_size(&self) -> usize;
+    fn pages(&self) -> usize;
+    //offset是相对于存储区起始的字节偏移
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()>;
+    //offset和data长度都必须是2的倍数，写入的位置必须已擦除
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()>;
+    fn erase(&mut self, page: usize) -> Result<()>;
+}
+
+//CRC-16/CCITT-FALSE
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+//CRC-32/ISO-HDLC，和zlib、binascii.crc32一样，crc初值传0，可以分段计算
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,535 @@
+//! 日志结构的键值存储
+//!
+//! 每次写入都在当前页末尾追加一条记录，同一个键以最新的记录为准，删除写一条墓碑记录。
+//! 始终保留一个空页，写满时把最旧的页中仍然有效的记录搬到空页，再擦除旧页(页交换)，
+//! 各页擦除次数因此大致均衡。
+//!
+//! 页头(12字节): magic(2) 序号(4) 完成标记(2) 作废标记(2) 保留(2)
+//! 记录: 键长(1) 标志(1) 值长(2) 键 值 [补齐到2字节] CRC16(2)
+//!
+//! 掉电安全：记录写一半时CRC对不上，读的时候跳过；搬运到一半时新页没有完成标记，
+//! 旧页作废标记写上之后才擦除，mount时按标记把存储区恢复成一致的状态
+
+use super::{crc16, Flash};
+use crate::io::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub const MAX_KEY: usize = 32;
+pub const MAX_VALUE: usize = 256;
+
+const MAGIC: u16 = 0x4b56; //"KV"
+const HEADER: usize = 12;
+const RECORD_HEADER: usize = 4;
+const TOMBSTONE: u8 = 0x01;
+//最多支持的页数
+const MAX_PAGES: usize = 16;
+const MAX_RECORD: usize = RECORD_HEADER + MAX_KEY + MAX_VALUE + 1 + 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PageState {
+    Erased,
+    Valid(u32), //序号
+    Garbage,    //页头损坏、搬运未完成或已作废，需要擦除
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Record {
+    offset: usize, //页内偏移
+    len: usize,    //整条记录占用的字节数
+    key_len: usize,
+    value_len: usize,
+    tombstone: bool,
+    valid: bool, //CRC正确
+}
+
+pub struct KvStore<F> {
+    flash: F,
+    active: usize, //当前追加的页
+    offset: usize, //当前页下一条记录的位置
+    seq: u32,      //当前页的序号，最大
+    scratch: [u8; MAX_RECORD],
+}
+
+impl<F: Flash> KvStore<F> {
+    //挂载存储区，清理掉电留下的半成品，空的存储区会被格式化
+    pub fn mount(flash: F) -> Result<Self> {
+        if flash.pages() < 2 || flash.pages() > MAX_PAGES {
+            return Err(Error::Other(alloc::string::String::from(
+                "KvStore needs 2~16 pages",
+            )));
+        }
+        let mut store = Self {
+            flash,
+            active: 0,
+            offset: HEADER,
+            seq: 0,
+            scratch: [0; MAX_RECORD],
+        };
+        let mut erased = 0;
+        for page in 0..store.flash.pages() {
+            match store.page_state(page)? {
+                PageState::Garbage => {
+                    store.flash.erase(page)?;
+                    erased += 1;
+                }
+                PageState::Erased => erased += 1,
+                PageState::Valid(_) => {}
+            }
+        }
+        let pages = store.valid_pages()?;
+        if pages.is_empty() {
+            store.open_page(0, 1, true)?;
+            store.active = 0;
+            store.seq = 1;
+            return Ok(store);
+        }
+        //搬运完成、旧页还没作废时掉电：旧页的有效记录已全部在新页里
+        if erased == 0 {
+            let (_, oldest) = pages[0];
+            store.retire(oldest)?;
+        }
+        let pages = store.valid_pages()?;
+        let (seq, active) = pages[pages.len() - 1];
+        store.active = active;
+        store.seq = seq;
+        store.offset = store.log_end(active)?;
+        Ok(store)
+    }
+
+    //交还flash
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    //把键对应的值读进buf，返回值的长度，键不存在时返回None
+    pub fn get_bytes(&mut self, key: &str, buf: &mut [u8]) -> Result<Option<usize>> {
+        let (page, record) = match self.locate(key)? {
+            Some((page, record)) if !record.tombstone => (page, record),
+            _ => return Ok(None),
+        };
+        if buf.len() < record.value_len {
+            return Err(Error::BufferFull);
+        }
+        let at = self.address(page, record.offset) + RECORD_HEADER + record.key_len;
+        self.flash.read(at, &mut buf[..record.value_len])?;
+        Ok(Some(record.value_len))
+    }
+
+    pub fn set_bytes(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        check_key(key)?;
+        if value.len() > MAX_VALUE {
+            return Err(Error::BufferFull);
+        }
+        //值没变就不写，减少擦写
+        let mut current = [0u8; MAX_VALUE];
+        if let Some(len) = self.get_bytes(key, &mut current)? {
+            if &current[..len] == value {
+                return Ok(());
+            }
+        }
+        self.append(key, value, false)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        check_key(key)?;
+        match self.locate(key)? {
+            Some((_, record)) if !record.tombstone => self.append(key, &[], true),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn contains(&mut self, key: &str) -> Result<bool> {
+        Ok(matches!(self.locate(key)?, Some((_, record)) if !record.tombstone))
+    }
+
+    //值用serde序列化成JSON保存
+    pub fn get<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>> {
+        let mut buf = [0u8; MAX_VALUE];
+        match self.get_bytes(key, &mut buf)? {
+            Some(len) => serde_json_core::from_slice(&buf[..len])
+                .map(|(value, _)| Some(value))
+                .map_err(|_| Error::Parse("kv: bad value")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set<T: Serialize>(&mut self, key: &str, value: &T) -> Result<()> {
+        let mut buf = [0u8; MAX_VALUE];
+        let len = serde_json_core::to_slice(value, &mut buf).map_err(|_| Error::BufferFull)?;
+        self.set_bytes(key, &buf[..len])
+    }
+
+    //写入一条记录，当前页放不下时换页或回收
+    fn append(&mut self, key: &str, value: &[u8], tombstone: bool) -> Result<()> {
+        let len = encode(&mut self.scratch, key, value, tombstone);
+        if HEADER + len > self.flash.page_size() {
+            return Err(Error::BufferFull);
+        }
+        for _ in 0..=self.flash.pages() {
+            if self.offset + len <= self.flash.page_size() {
+                let at = self.address(self.active, self.offset);
+                //写失败时这条记录可能写了一半，跳过它
+                self.offset += len;
+                return self.flash.write(at, &self.scratch[..len]);
+            }
+            let erased = self.erased_pages()?;
+            if erased.len() >= 2 {
+                self.open_page(erased[0], self.seq + 1, true)?;
+                self.active = erased[0];
+                self.offset = HEADER;
+                self.seq += 1;
+            } else {
+                self.collect()?;
+            }
+        }
+        //回收也腾不出空间
+        Err(Error::BufferFull)
+    }
+
+    //把最旧的页中有效的记录搬到空页，然后擦除旧页
+    fn collect(&mut self) -> Result<()> {
+        let spare = *self.erased_pages()?.first().ok_or(Error::BufferFull)?;
+        let (_, victim) = self.valid_pages()?[0];
+        let seq = self.seq + 1;
+        self.open_page(spare, seq, false)?;
+        let mut offset = HEADER;
+        let mut read = HEADER;
+        while let Some(record) = self.record_at(victim, read)? {
+            read += record.len;
+            if !record.valid || record.tombstone {
+                continue;
+            }
+            //只搬每个键最新的一条，别的页里有更新的就不搬
+            let mut key = [0u8; MAX_KEY];
+            let at = self.address(victim, record.offset) + RECORD_HEADER;
+            self.flash.read(at, &mut key[..record.key_len])?;
+            let key = core::str::from_utf8(&key[..record.key_len])
+                .map_err(|_| Error::Parse("kv: bad key"))?;
+            match self.locate(key)? {
+                Some((page, latest)) if page == victim && latest.offset == record.offset => {}
+                _ => continue,
+            }
+            let mut copy = [0u8; MAX_RECORD];
+            self.flash
+                .read(self.address(victim, record.offset), &mut copy[..record.len])?;
+            self.flash
+                .write(self.address(spare, offset), &copy[..record.len])?;
+            offset += record.len;
+        }
+        //完成标记
+        self.flash.write(self.address(spare, 6), &[0, 0])?;
+        self.active = spare;
+        self.offset = offset;
+        self.seq = seq;
+        self.retire(victim)
+    }
+
+    //写作废标记后擦除
+    fn retire(&mut self, page: usize) -> Result<()> {
+        self.flash.write(self.address(page, 8), &[0, 0])?;
+        self.flash.erase(page)
+    }
+
+    fn open_page(&mut self, page: usize, seq: u32, complete: bool) -> Result<()> {
+        let mut header = [0xffu8; HEADER];
+        header[..2].copy_from_slice(&MAGIC.to_le_bytes());
+        header[2..6].copy_from_slice(&seq.to_le_bytes());
+        if complete {
+            header[6..8].copy_from_slice(&[0, 0]);
+        }
+        self.flash.write(self.address(page, 0), &header)
+    }
+
+    //最新的一条记录(包括墓碑)
+    fn locate(&mut self, key: &str) -> Result<Option<(usize, Record)>> {
+        let pages = self.valid_pages()?;
+        for (_, page) in pages.iter().rev() {
+            let mut found = None;
+            let mut offset = HEADER;
+            while let Some(record) = self.record_at(*page, offset)? {
+                offset += record.len;
+                if record.valid && record.key_len == key.len() {
+                    let mut stored = [0u8; MAX_KEY];
+                    let at = self.address(*page, record.offset) + RECORD_HEADER;
+                    self.flash.read(at, &mut stored[..record.key_len])?;
+                    if &stored[..record.key_len] == key.as_bytes() {
+                        found = Some(record);
+                    }
+                }
+            }
+            if let Some(record) = found {
+                return Ok(Some((*page, record)));
+            }
+        }
+        Ok(None)
+    }
+
+    //读offset处的记录，到日志末尾时返回None
+    fn record_at(&mut self, page: usize, offset: usize) -> Result<Option<Record>> {
+        let page_size = self.flash.page_size();
+        if offset + RECORD_HEADER > page_size {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER];
+        self.flash.read(self.address(page, offset), &mut header)?;
+        if header[..2] == [0xff, 0xff] {
+            return Ok(None);
+        }
+        let key_len = header[0] as usize;
+        let value_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let len = record_len(key_len, value_len);
+        //长度损坏，当作页已写满
+        if key_len == 0 || key_len > MAX_KEY || value_len > MAX_VALUE || offset + len > page_size {
+            return Ok(None);
+        }
+        let mut data = [0u8; MAX_RECORD];
+        self.flash
+            .read(self.address(page, offset), &mut data[..len])?;
+        let crc = u16::from_le_bytes([data[len - 2], data[len - 1]]);
+        Ok(Some(Record {
+            offset,
+            len,
+            key_len,
+            value_len,
+            tombstone: header[1] & TOMBSTONE != 0,
+            valid: crc16(0xffff, &data[..len - 2]) == crc,
+        }))
+    }
+
+    //日志末尾的位置
+    fn log_end(&mut self, page: usize) -> Result<usize> {
+        let mut offset = HEADER;
+        while let Some(record) = self.record_at(page, offset)? {
+            offset += record.len;
+        }
+        //末尾不是0xFF说明长度损坏，不再往这页追加
+        if offset + 2 <= self.flash.page_size() {
+            let mut word = [0u8; 2];
+            self.flash.read(self.address(page, offset), &mut word)?;
+            if word != [0xff, 0xff] {
+                return Ok(self.flash.page_size());
+            }
+        }
+        Ok(offset)
+    }
+
+    fn page_state(&mut self, page: usize) -> Result<PageState> {
+        let mut header = [0u8; HEADER];
+        self.flash.read(self.address(page, 0), &mut header)?;
+        if header[..2] == MAGIC.to_le_bytes() {
+            let seq = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+            let complete = header[6..8] == [0, 0];
+            let retired = header[8..10] == [0, 0];
+            return Ok(if complete && !retired {
+                PageState::Valid(seq)
+            } else {
+                PageState::Garbage
+            });
+        }
+        //擦除到一半掉电时页头可能已是0xFF，但后面还有数据
+        let mut chunk = [0u8; 64];
+        for offset in (0..self.flash.page_size()).step_by(chunk.len()) {
+            let len = chunk.len().min(self.flash.page_size() - offset);
+            self.flash
+                .read(self.address(page, offset), &mut chunk[..len])?;
+            if chunk[..len].iter().any(|b| *b != 0xff) {
+                return Ok(PageState::Garbage);
+            }
+        }
+        Ok(PageState::Erased)
+    }
+
+    //有效页按序号从旧到新
+    fn valid_pages(&mut self) -> Result<heapless::Vec<(u32, usize), MAX_PAGES>> {
+        let mut pages = heapless::Vec::<(u32, usize), MAX_PAGES>::new();
+        for page in 0..self.flash.pages() {
+            if let PageState::Valid(seq) = self.header_state(page)? {
+                pages.push((seq, page)).ok();
+            }
+        }
+        pages.sort_unstable();
+        Ok(pages)
+    }
+
+    fn erased_pages(&mut self) -> Result<heapless::Vec<usize, MAX_PAGES>> {
+        let mut pages = heapless::Vec::<usize, MAX_PAGES>::new();
+        for page in 0..self.flash.pages() {
+            if self.header_state(page)? == PageState::Erased {
+                pages.push(page).ok();
+            }
+        }
+        Ok(pages)
+    }
+
+    //mount之后只看页头：没有magic的页一定是擦除过的
+    fn header_state(&mut self, page: usize) -> Result<PageState> {
+        let mut header = [0u8; HEADER];
+        self.flash.read(self.address(page, 0), &mut header)?;
+        if header[..2] != MAGIC.to_le_bytes() {
+            return Ok(PageState::Erased);
+        }
+        let seq = u32::from_le_bytes([header[2], header[3], header[4], header[5]]);
+        if header[6..8] == [0, 0] && header[8..10] != [0, 0] {
+            Ok(PageState::Valid(seq))
+        } else {
+            Ok(PageState::Garbage)
+        }
+    }
+
+    fn address(&self, page: usize, offset: usize) -> usize {
+        page * self.flash.page_size() + offset
+    }
+}
+
+fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.len() > MAX_KEY {
+        return Err(Error::Parse("kv: key must be 1~32 bytes"));
+    }
+    Ok(())
+}
+
+fn record_len(key_len: usize, value_len: usize) -> usize {
+    let data = key_len + value_len;
+    RECORD_HEADER + data + data % 2 + 2
+}
+
+//编码一条记录，返回长度
+fn encode(buf: &mut [u8], key: &str, value: &[u8], tombstone: bool) -> usize {
+    let len = record_len(key.len(), value.len());
+    buf[0] = key.len() as u8;
+    buf[1] = if tombstone { TOMBSTONE } else { 0 };
+    buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+    let key_end = RECORD_HEADER + key.len();
+    buf[RECORD_HEADER..key_end].copy_from_slice(key.as_bytes());
+    buf[key_end..key_end + value.len()].copy_from_slice(value);
+    for b in &mut buf[key_end + value.len()..len - 2] {
+        *b = 0xff;
+    }
+    let crc = crc16(0xffff, &buf[..len - 2]);
+    buf[len - 2..len].copy_from_slice(&crc.to_le_bytes());
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SimFlash;
+    use alloc::vec::Vec;
+
+    const KEYS: [&str; 4] = ["a", "bb", "ccc", "wifi.password"];
+
+    //第i次操作：每5次删除一个键，其它时候写入长度不等的值
+    fn value(i: usize) -> Option<Vec<u8>> {
+        if i % 5 == 4 {
+            return None;
+        }
+        Some((0..i % 9 + 1).map(|j| (i * 7 + j) as u8).collect())
+    }
+
+    fn apply(store: &mut KvStore<SimFlash>, i: usize) -> Result<()> {
+        let key = KEYS[i % KEYS.len()];
+        match value(i) {
+            Some(value) => store.set_bytes(key, &value),
+            None => store.remove(key),
+        }
+    }
+
+    //执行前n次操作后的存储区和每个键的值
+    fn build(n: usize) -> (SimFlash, [Option<Vec<u8>>; 4]) {
+        let mut store = KvStore::mount(SimFlash::new(128, 3)).unwrap();
+        let mut model: [Option<Vec<u8>>; 4] = Default::default();
+        for i in 0..n {
+            apply(&mut store, i).unwrap();
+            model[i % KEYS.len()] = value(i);
+        }
+        (store.release(), model)
+    }
+
+    fn read(store: &mut KvStore<SimFlash>, key: &str) -> Option<Vec<u8>> {
+        let mut buf = [0u8; MAX_VALUE];
+        let len = store.get_bytes(key, &mut buf).unwrap()?;
+        Some(buf[..len].to_vec())
+    }
+
+    fn erases(flash: &SimFlash) -> u32 {
+        (0..flash.pages()).map(|page| flash.erase_count(page)).sum()
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut store = KvStore::mount(SimFlash::new(256, 3)).unwrap();
+        store.set_bytes("a", b"1").unwrap();
+        store.set_bytes("b", b"22").unwrap();
+        for i in 0..200u32 {
+            store.set_bytes("c", &i.to_le_bytes()).unwrap();
+        }
+        store.remove("a").unwrap();
+        assert!(store.set_bytes("", b"x").is_err());
+        assert!(store.set_bytes("k", &[0; MAX_VALUE + 1]).is_err());
+
+        let mut store = KvStore::mount(store.release()).unwrap();
+        assert_eq!(read(&mut store, "a"), None);
+        assert_eq!(read(&mut store, "b").unwrap(), b"22");
+        assert_eq!(read(&mut store, "c").unwrap(), 199u32.to_le_bytes());
+        assert!(!store.contains("a").unwrap());
+
+        //擦除次数均衡
+        let flash = store.release();
+        let counts: Vec<u32> = (0..3).map(|page| flash.erase_count(page)).collect();
+        assert!(counts.iter().max().unwrap() - counts.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn unchanged_value_not_written() {
+        let (flash, _) = build(3);
+        let mut store = KvStore::mount(flash).unwrap();
+        let writes = store.flash.writes();
+        store.set_bytes("a", &value(0).unwrap()).unwrap();
+        store.remove("wifi.password").unwrap();
+        assert_eq!(store.flash.writes(), writes);
+    }
+
+    //在第n次操作的每个半字处掉电，重新上电后每个键是旧值或新值，
+    //被写的键以外不受影响，存储区还能继续用
+    #[test]
+    fn power_loss_at_every_halfword() {
+        let mut compactions = 0;
+        for n in 0..60 {
+            let key = n % KEYS.len();
+            let (_, old) = build(n);
+            let (_, new) = build(n + 1);
+            for cut in 0.. {
+                let (mut flash, _) = build(n);
+                let before = erases(&flash);
+                flash.cut_power_after(cut);
+                let mut store = KvStore::mount(flash).unwrap();
+                let result = apply(&mut store, n);
+                let mut flash = store.release();
+                let done = result.is_ok();
+                if done && erases(&flash) > before {
+                    compactions += 1;
+                }
+
+                flash.power_on();
+                let mut store = KvStore::mount(flash).unwrap();
+                for (i, name) in KEYS.iter().enumerate() {
+                    let got = read(&mut store, name);
+                    if i != key || done {
+                        let expected = if done { &new[i] } else { &old[i] };
+                        assert_eq!(&got, expected, "op {} cut {} key {}", n, cut, name);
+                    } else {
+                        assert!(got == old[i] || got == new[i], "op {} cut {}", n, cut);
+                    }
+                }
+                store.set_bytes("a", b"after").unwrap();
+                assert_eq!(read(&mut store, "a").unwrap(), b"after");
+                if done {
+                    break;
+                }
+            }
+        }
+        //有操作需要回收旧页，回收过程中的每个半字都掉过电
+        assert!(compactions > 0);
+    }
+}
@@ -0,0 +1,121 @@
+//! 内存模拟的flash，用于在主机上测试和模拟掉电
+
+use super::Flash;
+use crate::io::{Error, Result};
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub struct SimFlash {
+    data: Vec<u8>,
+    page_size: usize,
+    budget: Option<usize>, //还能成功编程/擦除的半字数，用完即掉电
+    writes: usize,
+    erases: Vec<u32>, //每页擦除次数
+}
+
+impl SimFlash {
+    pub fn new(page_size: usize, pages: usize) -> Self {
+        Self {
+            data: vec![0xff; page_size * pages],
+            page_size,
+            budget: None,
+            writes: 0,
+            erases: vec![0; pages],
+        }
+    }
+
+    //再编程/擦除halfwords个半字后掉电，之后所有写和擦除都返回Error::WriteError；
+    //擦除到一半掉电时该页只擦了一部分
+    pub fn cut_power_after(&mut self, halfwords: usize) {
+        self.budget = Some(halfwords);
+    }
+
+    //重新上电，flash内容保持掉电时的样子
+    pub fn power_on(&mut self) {
+        self.budget = None;
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.budget != Some(0)
+    }
+
+    //累计编程的半字数
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+
+    pub fn erase_count(&self, page: usize) -> u32 {
+        self.erases[page]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    //消耗一个半字的预算，没电时返回false
+    fn spend(&mut self) -> bool {
+        match &mut self.budget {
+            Some(0) => false,
+            Some(budget) => {
+                *budget -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+impl Flash for SimFlash {
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn pages(&self) -> usize {
+        self.erases.len()
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let data = self
+            .data
+            .get(offset..offset + buf.len())
+            .ok_or(Error::ReadError)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        if offset & 1 != 0 || data.len() & 1 != 0 || offset + data.len() > self.data.len() {
+            return Err(Error::WriteError);
+        }
+        for (i, word) in data.chunks(2).enumerate() {
+            let at = offset + i * 2;
+            let old = [self.data[at], self.data[at + 1]];
+            //和STM32一样，只能编程已擦除的半字，或者写0
+            if old != [0xff, 0xff] && word != [0, 0] {
+                return Err(Error::WriteError);
+            }
+            if !self.spend() {
+                return Err(Error::WriteError);
+            }
+            self.data[at..at + 2].copy_from_slice(word);
+            self.writes += 1;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, page: usize) -> Result<()> {
+        if page >= self.pages() {
+            return Err(Error::WriteError);
+        }
+        let start = page * self.page_size;
+        for at in (start..start + self.page_size).step_by(2) {
+            if !self.spend() {
+                return Err(Error::WriteError);
+            }
+            self.data[at] = 0xff;
+            self.data[at + 1] = 0xff;
+        }
+        self.erases[page] += 1;
+        Ok(())
+    }
+}
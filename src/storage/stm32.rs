@@ -0,0 +1,70 @@
+//! STM32F103片内flash
+//!
+//! 使用flash最后几页，memory.x里FLASH的LENGTH要相应减去这些页，
+//! 避免程序被链接到存储区
+
+use super::Flash;
+use crate::hal::flash::{FlashSize, FlashWriter, SectorSize};
+use crate::io::{Error, Result};
+
+//STM32F103C8每页1K
+pub const PAGE_SIZE: usize = 1024;
+
+pub struct Stm32Flash<'a> {
+    writer: FlashWriter<'a>,
+    start: u32, //存储区相对0x08000000的偏移
+    pages: usize,
+}
+
+impl<'a> Stm32Flash<'a> {
+    //使用64K flash的最后pages页
+    //let mut flash = p.device.FLASH.constrain();
+    //let storage = Stm32Flash::last_pages(&mut flash, 4);
+    pub fn last_pages(flash: &'a mut crate::hal::flash::Parts, pages: usize) -> Self {
+        Self::range(flash, 64 - pages, pages)
+    }
+
+    //从第first页开始的pages页，ota用range(flash, 0, 64)访问整片flash
+    pub fn range(flash: &'a mut crate::hal::flash::Parts, first: usize, pages: usize) -> Self {
+        let writer = flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+        Self {
+            writer,
+            start: (first * PAGE_SIZE) as u32,
+            pages,
+        }
+    }
+}
+
+impl<'a> Flash for Stm32Flash<'a> {
+    fn page_size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let data = self
+            .writer
+            .read(self.start + offset as u32, buf.len())
+            .map_err(|_| Error::ReadError)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.writer
+            .write(self.start + offset as u32, data)
+            .map_err(|_| Error::WriteError)
+    }
+
+    fn erase(&mut self, page: usize) -> Result<()> {
+        if page >= self.pages {
+            return Err(Error::WriteError);
+        }
+        self.writer
+            .page_erase(self.start + (page * PAGE_SIZE) as u32)
+            .map_err(|_| Error::WriteError)
+    }
+}
@@ -0,0 +1,114 @@
+//! 串口刷机工具，通过FT232连接板子的USART1(PA9/PA10)，和bootloader的串口刷机模式通信。
+//! 协议和设备端共用src/flasher/protocol.rs
+//!
+//! ```text
+//! cargo objcopy --release --example esp8266 --features ota -- -O binary app.bin
+//! cd tools/flasher
+//! cargo run --target $(rustc -vV | sed -n 's/host: //p') -- /dev/ttyUSB0 ../../app.bin
+//! ```
+//!
+//! 上层目录的.cargo/config把目标设成了thumbv7m-none-eabi，所以要用--target指定主机
+
+#[allow(dead_code)] //设备端的Server用不到
+#[path = "../../../src/flasher/protocol.rs"]
+mod protocol;
+
+use protocol::{Client, Error, Port, Status};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+//bootloader上电后只等1秒，等待期间不停地发Hello
+const HELLO_TIMEOUT: Duration = Duration::from_millis(50);
+//擦除整个应用区要1秒左右
+const TIMEOUT: Duration = Duration::from_millis(3000);
+const WAIT: Duration = Duration::from_secs(30);
+
+struct Serial(Box<dyn serialport::SerialPort>);
+
+impl Port for Serial {
+    fn send(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.0.write_all(frame).map_err(|_| Error::Io)
+    }
+
+    fn recv(&mut self) -> Result<Option<u8>, Error> {
+        let mut byte = [0u8; 1];
+        match self.0.read(&mut byte) {
+            Ok(1) => Ok(Some(byte[0])),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(_) => Err(Error::Io),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: flasher <port> <firmware.bin> [baud]");
+        std::process::exit(2);
+    }
+    let baud = match args.get(3).map(|baud| baud.parse()) {
+        Some(Ok(baud)) => baud,
+        Some(Err(_)) => {
+            eprintln!("bad baud rate: {}", args[3]);
+            std::process::exit(2);
+        }
+        None => 115_200,
+    };
+    if let Err(err) = run(&args[1], &args[2], baud) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(port: &str, path: &str, baud: u32) -> Result<(), String> {
+    let image = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let serial = serialport::new(port, baud)
+        .timeout(HELLO_TIMEOUT)
+        .open()
+        .map_err(|err| format!("{}: {}", port, err))?;
+    let mut client = Client::new(Serial(serial)).retries(0);
+
+    println!("waiting for the bootloader, reset the board");
+    let started = Instant::now();
+    let info = loop {
+        match client.hello() {
+            Ok(info) => break info,
+            //复位时串口上的杂波会让第一帧损坏
+            Err(Error::Timeout) | Err(Error::Device(Status::BadFrame))
+                if started.elapsed() < WAIT =>
+            {
+                continue
+            }
+            Err(err) => return Err(format!("hello: {:?}", err)),
+        }
+    };
+    println!(
+        "bootloader v{}, {} bytes at {:#010x}, page size {}",
+        info.version, info.len, info.start, info.page_size
+    );
+    if image.len() > info.len as usize {
+        return Err(format!(
+            "{} is {} bytes, larger than the {} byte application area",
+            path,
+            image.len(),
+            info.len
+        ));
+    }
+
+    client
+        .port()
+        .0
+        .set_timeout(TIMEOUT)
+        .map_err(|err| err.to_string())?;
+    let mut client = client.retries(3);
+    let total = image.len();
+    client
+        .flash(info.start, &image, |written| {
+            print!("\rwriting {}/{} bytes", written, total);
+            std::io::stdout().flush().ok();
+        })
+        .map_err(|err| format!("\nflash: {:?}", err))?;
+    println!("\nverified, booting");
+    client.boot().map_err(|err| format!("boot: {:?}", err))
+}